@@ -1,13 +1,31 @@
-use hollywood::{self, ActorMailbox, Result, RunOpts};
+use hollywood::{self, ActorMailbox, DedupOpts, Result, RunOpts, SupervisionStrategy};
 use system::ActorX;
 use system::ActorY;
 use system::ActorYMsg;
+use tokio::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     pretty_env_logger::init();
+    // follow requests across actors with an OpenTelemetry collector
+    let _otel = system::init_tracing("actor-x")?;
     let actor_y = ActorY::mailbox_from_env::<ActorYMsg>().await?;
-    let actor = ActorX::new(actor_y);
-    let opts = RunOpts::from_env(actor)?.with_actor_mailbox_max_size(Some(100u32));
+    let actor = ActorX::new(actor_y.clone());
+    // run 4 ActorX instances so slow requests (i.e. ActorXMsg::Sleep)
+    // don't block the whole mailbox
+    let opts = RunOpts::from_env(actor)?
+        .with_actor_mailbox_max_size(Some(100u32))
+        .with_concurrency(4)
+        .with_actor_factory(move || ActorX::new(actor_y.clone()))
+        // rebuild ActorX instances if a handler panics
+        .with_supervision(SupervisionStrategy::RestartWithBackoff {
+            min: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+        })
+        // answer retried requests (same msg id) from a
+        // cache instead of running the handler again
+        .with_dedup(DedupOpts::new(Duration::from_secs(300)))
+        // curl http://127.0.0.1:9464/metrics
+        .with_metrics_endpoint(([127, 0, 0, 1], 9464).into());
     hollywood::run(opts).await
 }
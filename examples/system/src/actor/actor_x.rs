@@ -1,9 +1,10 @@
-use crate::types::msg::{ActorXMsg, ActorYMsg};
+use crate::types::msg::{ActorXMsg, ActorXResponse, ActorYMsg};
 use crate::types::version::V1_0;
 use chrono::{DateTime, Utc};
 use hollywood::prelude::actor::*;
 use hollywood_macro::Hollywood;
 use log::{debug, error, info};
+use std::io::{Error, ErrorKind};
 use std::time::SystemTime;
 use tokio::time::{sleep, Duration};
 
@@ -15,7 +16,7 @@ fn now() -> String {
 
 /// ActorX
 #[derive(Hollywood)]
-#[dispatch(ActorXMsg)]
+#[dispatch(ActorXMsg(send, request, stream))]
 pub struct ActorX {
     actor_y: hollywood::mailbox::Mailbox,
 }
@@ -31,13 +32,11 @@ impl Actor for ActorX {
 }
 
 #[async_trait]
-impl Handle<ActorXMsg> for ActorX {
-    type Msg = ActorXMsg;
-
-    async fn request(&mut self, msg: Self::Msg) -> Result<Option<Self::Msg>> {
+impl HandleRequest<ActorXMsg> for ActorX {
+    async fn request(&mut self, ctx: &Ctx, msg: ActorXMsg) -> Result<ActorXResponse> {
         match msg {
             ActorXMsg::HelloRequest => {
-                info!("hello request");
+                info!("hello request from {:?}", ctx.header("client"));
                 // try to ping ActorY here...
                 let msg = ActorYMsg::PingRequest { timestamp: now() };
                 match self.actor_y.request::<ActorYMsg>(msg).await {
@@ -48,17 +47,51 @@ impl Handle<ActorXMsg> for ActorX {
                         error!("ActorYMsg::PingRequest response err: {:?}", &err);
                     }
                 }
-                Ok(Some(ActorXMsg::HelloResponse))
+                Ok(ActorXResponse::HelloResponse)
             }
             ActorXMsg::Sleep { secs } => {
-                sleep(Duration::from_secs(secs)).await;
-                Ok(Some(ActorXMsg::HelloResponse))
+                // skip work the caller won't wait for
+                let duration = Duration::from_secs(secs);
+                if let Some(remaining) = ctx.time_remaining() {
+                    if remaining < duration {
+                        return Err(Error::new(
+                            ErrorKind::TimedOut,
+                            "sleep exceeds the request deadline",
+                        )
+                        .into());
+                    }
+                }
+                sleep(duration).await;
+                Ok(ActorXResponse::SleepResponse { secs })
+            }
+            _ => Err(Error::new(ErrorKind::Unsupported, "not a request msg").into()),
+        }
+    }
+}
+
+#[async_trait]
+impl HandleStream<ActorXMsg> for ActorX {
+    async fn stream(
+        &mut self,
+        _: &Ctx,
+        msg: ActorXMsg,
+        responses: StreamSender<ActorXResponse>,
+    ) -> Result<()> {
+        match msg {
+            ActorXMsg::Count { to } => {
+                for n in 1..=to {
+                    responses.send(ActorXResponse::CountResponse { n }).await?;
+                }
+                Ok(())
             }
-            _ => Ok(None),
+            _ => Err(Error::new(ErrorKind::Unsupported, "not a stream msg").into()),
         }
     }
+}
 
-    async fn send(&mut self, msg: Self::Msg) -> Result<()> {
+#[async_trait]
+impl HandleSend<ActorXMsg> for ActorX {
+    async fn send(&mut self, _: &Ctx, msg: ActorXMsg) -> Result<()> {
         match msg {
             ActorXMsg::HelloRequest => {
                 info!("hello send");
@@ -67,10 +100,4 @@ impl Handle<ActorXMsg> for ActorX {
         }
         Ok(())
     }
-
-    // default implementation since this actor subscribes
-    // to a queue...
-    async fn subscribe(&mut self, _: Self::Msg) -> Result<()> {
-        Ok(())
-    }
 }
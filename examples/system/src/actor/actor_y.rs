@@ -1,26 +1,103 @@
-use crate::types::msg::ActorYMsg;
+use crate::types::msg::{ActorYMsg, PUBSUB_ACTOR_Y_EVENTS};
 use crate::types::version::V1_0;
 use hollywood::prelude::actor::*;
 use hollywood_macro::Hollywood;
-use log::{debug, info};
+use log::{debug, info, warn};
 use redis;
 use redis::AsyncCommands;
+use std::io::{Error, ErrorKind};
+use tokio::time::{sleep, Duration};
+
+// init_redis_client returns an async redis connection
+// TODO: should define a timeout or max retries
+async fn init_redis_client(redis_uri: &str) -> Result<redis::aio::Connection> {
+    let mut result = redis::Client::open(redis_uri);
+    loop {
+        match result {
+            Err(err) => {
+                warn!("error creating redis client: #{:?}", &err);
+                sleep(Duration::from_millis(1000)).await;
+                result = redis::Client::open(redis_uri);
+            }
+            Ok(_) => {
+                info!("redis client initialized...");
+                break;
+            }
+        }
+    }
+    let client = result.unwrap();
+    let mut result = client.get_async_connection().await;
+    loop {
+        match result {
+            Err(err) => {
+                warn!("error creating redis client: #{:?}", &err);
+                sleep(Duration::from_millis(1000)).await;
+                result = client.get_async_connection().await;
+            }
+            Ok(_) => {
+                info!("redis connection created...");
+                break;
+            }
+        };
+    }
+    let conn = result.unwrap();
+    Ok(conn)
+}
 
 /// ActorY
 #[derive(Hollywood)]
 #[dispatch(ActorYMsg)]
 pub struct ActorY {
-    redis: redis::aio::Connection,
+    redis_uri: String,
+    redis: Option<redis::aio::Connection>,
 }
 
 impl ActorY {
-    pub fn new(redis_conn: redis::aio::Connection) -> Self {
-        Self { redis: redis_conn }
+    pub fn new(redis_uri: String) -> Self {
+        Self {
+            redis_uri,
+            redis: None,
+        }
+    }
+
+    fn redis(&mut self) -> Result<&mut redis::aio::Connection> {
+        match self.redis.as_mut() {
+            Some(conn) => Ok(conn),
+            None => {
+                Err(Error::new(ErrorKind::NotConnected, "redis connection not initialized").into())
+            }
+        }
     }
 }
 
+#[async_trait]
 impl Actor for ActorY {
     const VERSION: &'static str = V1_0;
+
+    // answer requests on the queue mailbox
+    // and read events from a pubsub topic
+    fn subscriptions() -> Vec<SubscribeType> {
+        vec![
+            SubscribeType::Queue,
+            SubscribeType::Publish {
+                subject: PUBSUB_ACTOR_Y_EVENTS,
+            },
+        ]
+    }
+
+    // connect to redis before reading messages
+    async fn on_start(&mut self) -> Result<()> {
+        self.redis = Some(init_redis_client(&self.redis_uri).await?);
+        Ok(())
+    }
+
+    // report unhealthy if redis stops responding
+    async fn health(&mut self) -> Result<()> {
+        redis::cmd("PING")
+            .query_async::<_, String>(self.redis()?)
+            .await?;
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -41,7 +118,7 @@ impl Handle<ActorYMsg> for ActorY {
         match msg {
             ActorYMsg::SomeSend => {
                 info!("Some send... update redis");
-                match self.redis.set::<_, _, String>("some_send", 42).await {
+                match self.redis()?.set::<_, _, String>("some_send", 42).await {
                     Ok(rv) => {
                         info!("some_send, redis reply: {}", rv)
                     }
@@ -53,9 +130,17 @@ impl Handle<ActorYMsg> for ActorY {
         Ok(())
     }
 
-    // default implementation since this actor subscribes
-    // to a queue...
-    async fn subscribe(&mut self, _: Self::Msg) -> Result<()> {
+    // events published to PUBSUB_ACTOR_Y_EVENTS
+    async fn subscribe(&mut self, msg: Self::Msg) -> Result<()> {
+        match msg {
+            ActorYMsg::SomeSend => {
+                info!("SomeSend event... update redis");
+                self.redis()?
+                    .set::<_, _, String>("some_send_event", 42)
+                    .await?;
+            }
+            _ => {}
+        }
         Ok(())
     }
 }
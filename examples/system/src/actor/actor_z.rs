@@ -6,7 +6,7 @@ use log::{debug, info};
 
 /// ActorZ
 #[derive(Hollywood)]
-#[dispatch(SubjectOneMsg)]
+#[dispatch(SubjectOneMsg(subscribe))]
 pub struct ActorZ {}
 
 #[allow(dead_code)]
@@ -19,30 +19,24 @@ impl ActorZ {
 impl Actor for ActorZ {
     const VERSION: &'static str = version::V1_0;
     fn subscribe_type() -> SubscribeType {
-        SubscribeType::Publish {
-            subject: PUBSUB_SUBJECT_ONE,
+        // handle each event once across running ActorZ agents
+        SubscribeType::PublishGroup {
+            subjects: &[PUBSUB_SUBJECT_ONE],
         }
     }
 }
 
 #[async_trait]
-impl Handle<SubjectOneMsg> for ActorZ {
-    type Msg = SubjectOneMsg;
-
-    async fn request(&mut self, _: Self::Msg) -> Result<Option<Self::Msg>> {
-        Ok(None)
-    }
-
-    async fn send(&mut self, _: Self::Msg) -> Result<()> {
-        Ok(())
-    }
-
-    // default implementation since this actor subscribes
-    // to a queue...
-    async fn subscribe(&mut self, msg: Self::Msg) -> Result<()> {
+impl HandleSubscribe<SubjectOneMsg> for ActorZ {
+    async fn subscribe(&mut self, ctx: &Ctx, msg: SubjectOneMsg) -> Result<()> {
         match msg {
             SubjectOneMsg::Event => {
-                info!("subscribe event actor-z: {:?}", &msg);
+                info!(
+                    "subscribe event actor-z: {:?} id: {} subject: {}",
+                    &msg,
+                    ctx.id(),
+                    ctx.subject()
+                );
             }
         }
         Ok(())
@@ -1,4 +1,4 @@
-use crate::types::msg::{SubjectOneMsg, PUBSUB_SUBJECT_ONE};
+use crate::types::msg::{SubjectOneMsg, PUBSUB_EVENTS, PUBSUB_SUBJECT_ONE};
 use crate::types::version;
 use hollywood::prelude::actor::*;
 use hollywood_macro::Hollywood;
@@ -6,7 +6,7 @@ use log::{debug, info};
 
 /// ActorZ
 #[derive(Hollywood)]
-#[dispatch(SubjectOneMsg)]
+#[dispatch(SubjectOneMsg(subscribe))]
 pub struct ActorZZ {}
 
 #[allow(dead_code)]
@@ -20,30 +20,23 @@ impl Actor for ActorZZ {
     const VERSION: &'static str = version::V1_0;
 
     fn subscribe_type() -> SubscribeType {
-        SubscribeType::Publish {
-            subject: PUBSUB_SUBJECT_ONE,
+        // audit subject-one and every event subject
+        SubscribeType::PublishMany {
+            subjects: &[PUBSUB_SUBJECT_ONE, PUBSUB_EVENTS],
         }
     }
 }
 
 #[async_trait]
-impl Handle<SubjectOneMsg> for ActorZZ {
-    type Msg = SubjectOneMsg;
-
-    async fn request(&mut self, _: Self::Msg) -> Result<Option<Self::Msg>> {
-        Ok(None)
-    }
-
-    async fn send(&mut self, _: Self::Msg) -> Result<()> {
-        Ok(())
-    }
-
-    // default implementation since this actor subscribes
-    // to a queue...
-    async fn subscribe(&mut self, msg: Self::Msg) -> Result<()> {
+impl HandleSubscribe<SubjectOneMsg> for ActorZZ {
+    async fn subscribe(&mut self, ctx: &Ctx, msg: SubjectOneMsg) -> Result<()> {
         match msg {
             SubjectOneMsg::Event => {
-                info!("subscribe event actor-zz: {:?}", &msg);
+                info!(
+                    "subscribe event actor-zz: {:?} subject: {}",
+                    &msg,
+                    ctx.subject()
+                );
             }
         }
         Ok(())
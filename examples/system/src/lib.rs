@@ -2,3 +2,28 @@ pub mod actor;
 pub mod types;
 pub use actor::{actor_x::ActorX, actor_y::ActorY, actor_z::ActorZ, actor_zz::ActorZZ};
 pub use types::msg::{ActorXMsg, ActorYMsg};
+
+/// Flushes the exported spans once dropped.
+#[cfg(feature = "opentelemetry")]
+pub type Tracing = hollywood::OpenTelemetry;
+
+/// Spans are only exported when the examples are
+/// built with the `opentelemetry` feature.
+#[cfg(not(feature = "opentelemetry"))]
+pub enum Tracing {}
+
+/// Export spans to an OpenTelemetry collector if
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` is set
+/// (i.e. `http://localhost:4318`).
+#[cfg(feature = "opentelemetry")]
+pub fn init_tracing(service_name: &str) -> hollywood::Result<Option<Tracing>> {
+    match std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(_) => Ok(Some(hollywood::init_opentelemetry(service_name, None)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(not(feature = "opentelemetry"))]
+pub fn init_tracing(_service_name: &str) -> hollywood::Result<Option<Tracing>> {
+    Ok(None)
+}
@@ -1,19 +1,34 @@
 use crate::types::version;
-use hollywood::Msg;
+use hollywood::{Msg, RequestMsg};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum ActorXMsg {
     HelloRequest,
-    HelloResponse,
     SomeSend,
     Sleep { secs: u64 },
+    Count { to: u64 },
 }
 impl Msg for ActorXMsg {
     type Type = Self;
     const VERSION: &'static str = version::V1_0;
 }
+impl RequestMsg for ActorXMsg {
+    type Response = ActorXResponse;
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+pub enum ActorXResponse {
+    HelloResponse,
+    SleepResponse { secs: u64 },
+    CountResponse { n: u64 },
+}
+impl Msg for ActorXResponse {
+    type Type = Self;
+    const VERSION: &'static str = version::V1_0;
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
@@ -26,9 +41,17 @@ impl Msg for ActorYMsg {
     type Type = Self;
     const VERSION: &'static str = version::V1_0;
 }
+// ActorY responds with the same msg type
+impl RequestMsg for ActorYMsg {
+    type Response = Self;
+}
 
 // Pubsub Msg & Subjects
-pub static PUBSUB_SUBJECT_ONE: &'static str = "subject-one";
+// ActorY events published along with its queue mailbox
+pub const PUBSUB_ACTOR_Y_EVENTS: &str = "actor-y.events";
+pub const PUBSUB_SUBJECT_ONE: &str = "subject-one";
+// matches every event subject (i.e. `events.created`)
+pub const PUBSUB_EVENTS: &str = "events.*";
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum SubjectOneMsg {
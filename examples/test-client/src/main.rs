@@ -1,16 +1,20 @@
-use hollywood::{env, ActorMailbox, Result};
+use futures::StreamExt;
+use hollywood::{env, ActorMailbox, Client, Metadata, Priority, Result};
 use log::{error, info};
 use pretty_env_logger;
 
 use system::actor::actor_x::ActorX;
 use system::actor::actor_y::ActorY;
 use system::actor::actor_z::ActorZ;
-use system::types::msg::{ActorXMsg, ActorYMsg, SubjectOneMsg};
+use system::actor::actor_zz::ActorZZ;
+use system::types::msg::{ActorXMsg, ActorYMsg, SubjectOneMsg, PUBSUB_ACTOR_Y_EVENTS};
 use tokio::time::{sleep, Duration};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     pretty_env_logger::init();
+    // follow requests across actors with an OpenTelemetry collector
+    let _otel = system::init_tracing("test-client")?;
 
     // system name and nats uri defaults
     let system_name: String = "examples".into();
@@ -29,9 +33,36 @@ async fn main() -> Result<()> {
     // ActorY client
     let actor_y = ActorY::mailbox_from_env::<ActorYMsg>().await?;
 
+    // ActorY client for msgs that skip ahead of normal traffic
+    let actor_y_urgent = actor_y.clone().with_priority(Priority::High);
+
     // Make a client from ActorZ that writes
     // pubsub messages...
     let actor_z = ActorZ::mailbox::<SubjectOneMsg>(system_name.clone(), nats_uri.clone()).await?;
+
+    // ActorZZ also subscribes to the `events.*` subjects
+    let actor_zz = ActorZZ::mailbox::<SubjectOneMsg>(system_name.clone(), nats_uri.clone()).await?;
+
+    // log messages the actors failed to handle
+    let dead_letters = Client::new(nats_uri.clone())
+        .await?
+        .dead_letters(&system_name)
+        .await?;
+    tokio::spawn(async move {
+        while let Some(dead_letter) = dead_letters.next().await {
+            match dead_letter {
+                Ok(dead_letter) => {
+                    info!(
+                        "dead letter from {} on {}: {}",
+                        &dead_letter.actor, &dead_letter.subject, &dead_letter.reason
+                    );
+                }
+                Err(err) => {
+                    error!("dead letter err: {:?}", &err);
+                }
+            }
+        }
+    });
     loop {
         // ActorX send
         info!("request ActorXMsg::HelloRequest");
@@ -52,6 +83,42 @@ async fn main() -> Result<()> {
                 error!("ActorXMsg::HelloRequest response err: {:?}", &err);
             }
         }
+        // ActorX request with headers
+        let meta = Metadata::new().with_header("client", "test-client");
+        match actor_x
+            .request_with_meta::<ActorXMsg>(ActorXMsg::HelloRequest, meta)
+            .await
+        {
+            Ok((msg, meta)) => {
+                info!(
+                    "ActorXMsg::HelloRequest response msg: {:?} from {:?} (correlation id {:?})",
+                    &msg, &meta.sender, &meta.correlation_id
+                );
+            }
+            Err(err) => {
+                error!("ActorXMsg::HelloRequest response err: {:?}", &err);
+            }
+        }
+        // ActorX request retried with the same msg id is
+        // answered from the agent dedup window
+        let msg_id = format!("hello-{}", std::process::id());
+        for attempt in 1..=2 {
+            let meta = Metadata::new().with_msg_id(msg_id.clone());
+            match actor_x
+                .request_with_meta::<ActorXMsg>(ActorXMsg::HelloRequest, meta)
+                .await
+            {
+                Ok((msg, meta)) => {
+                    info!(
+                        "ActorXMsg::HelloRequest attempt {} response msg: {:?} (sent at {:?})",
+                        attempt, &msg, &meta.sent_at
+                    );
+                }
+                Err(err) => {
+                    error!("ActorXMsg::HelloRequest response err: {:?}", &err);
+                }
+            }
+        }
         // ActorX request with timeout success
         match actor_x
             .request_timeout::<ActorXMsg>(ActorXMsg::Sleep { secs: 1 }, 2)
@@ -76,6 +143,27 @@ async fn main() -> Result<()> {
                 error!("with timeout ActorXMsg::Sleep response err: {:?}", &err);
             }
         }
+        // ActorX streamed responses
+        match actor_x
+            .request_stream::<ActorXMsg>(ActorXMsg::Count { to: 3 })
+            .await
+        {
+            Ok(mut responses) => {
+                while let Some(msg) = responses.next().await {
+                    match msg {
+                        Ok(msg) => {
+                            info!("ActorXMsg::Count stream msg: {:?}", &msg);
+                        }
+                        Err(err) => {
+                            error!("ActorXMsg::Count stream err: {:?}", &err);
+                        }
+                    }
+                }
+            }
+            Err(err) => {
+                error!("ActorXMsg::Count request_stream err: {:?}", &err);
+            }
+        }
         // ActorY send
         match actor_y.send::<ActorYMsg>(ActorYMsg::SomeSend).await {
             Ok(msg) => {
@@ -85,6 +173,44 @@ async fn main() -> Result<()> {
                 error!("ActorYMsg::SomeSend err: {:?}", &err);
             }
         }
+        // ActorY event (ActorY also reads a pubsub topic)
+        match actor_y
+            .publish_to::<ActorYMsg>(PUBSUB_ACTOR_Y_EVENTS, ActorYMsg::SomeSend)
+            .await
+        {
+            Ok(msg) => {
+                info!("ActorYMsg::SomeSend event resp: {:?}", &msg);
+            }
+            Err(err) => {
+                error!("ActorYMsg::SomeSend event err: {:?}", &err);
+            }
+        }
+        // ActorY high priority request
+        let msg = ActorYMsg::PingRequest {
+            timestamp: "urgent".into(),
+        };
+        match actor_y_urgent.request::<ActorYMsg>(msg).await {
+            Ok(msg) => {
+                info!("ActorYMsg::PingRequest urgent response msg: {:?}", &msg);
+            }
+            Err(err) => {
+                error!("ActorYMsg::PingRequest urgent response err: {:?}", &err);
+            }
+        }
+        // ActorY health check
+        match actor_y.health(1).await {
+            Ok(status) => {
+                info!("ActorY health status: {:?}", &status);
+                // ask the same agent instance again
+                match actor_y.instance_health(&status.instance_id, 1).await {
+                    Ok(status) => info!("ActorY instance health status: {:?}", &status),
+                    Err(err) => error!("ActorY instance health err: {:?}", &err),
+                }
+            }
+            Err(err) => {
+                error!("ActorY health err: {:?}", &err);
+            }
+        }
         // Publish subject message
         match actor_z.publish::<SubjectOneMsg>(SubjectOneMsg::Event).await {
             Ok(msg) => {
@@ -94,6 +220,17 @@ async fn main() -> Result<()> {
                 error!("SubjectOneMsg::Event err: {:?}", &err);
             }
         }
+        match actor_zz
+            .publish_to::<SubjectOneMsg>("events.created", SubjectOneMsg::Event)
+            .await
+        {
+            Ok(msg) => {
+                info!("SubjectOneMsg::Event events.created resp: {:?}", &msg);
+            }
+            Err(err) => {
+                error!("SubjectOneMsg::Event events.created err: {:?}", &err);
+            }
+        }
 
         sleep(Duration::from_millis(3000)).await;
     }
@@ -3,35 +3,177 @@ use quote::{format_ident, quote};
 use syn;
 use syn::spanned::Spanned;
 
-fn get_version_variant(ty: String) -> syn::Result<proc_macro2::TokenStream> {
-	let version_ty = format_ident!("{}", ty);
+/// A msg type listed in the dispatch attribute
+struct DispatchMsg {
+	ty: String,
+	// dispatch to HandleWithCtx instead of Handle
+	ctx: bool,
+	// dispatch types handled by HandleSend, HandleRequest,
+	// HandleSubscribe and HandleStream (none of them if
+	// dispatched to Handle)
+	send: bool,
+	request: bool,
+	subscribe: bool,
+	stream: bool,
+}
+
+impl DispatchMsg {
+	fn new(ty: String) -> Self {
+		DispatchMsg {
+			ty,
+			ctx: false,
+			send: false,
+			request: false,
+			subscribe: false,
+			stream: false,
+		}
+	}
+
+	// parse `MsgType(ctx)` or `MsgType(send, request, subscribe, stream)`
+	fn from_list(list: &syn::MetaList) -> syn::Result<Self> {
+		let ty = list
+			.path
+			.get_ident()
+			.ok_or_else(|| syn::Error::new_spanned(&list.path, "expected a msg type"))?;
+		let mut msg = DispatchMsg::new(ty.to_string());
+		for item in list.nested.iter() {
+			let option = match item {
+				syn::NestedMeta::Meta(syn::Meta::Path(path)) => path.get_ident(),
+				_ => None,
+			};
+			match option.map(|ident| ident.to_string()).as_deref() {
+				Some("ctx") => msg.ctx = true,
+				Some("send") => msg.send = true,
+				Some("request") => msg.request = true,
+				Some("subscribe") => msg.subscribe = true,
+				Some("stream") => msg.stream = true,
+				_ => return Err(syn::Error::new_spanned(item, "unknown dispatch option")),
+			}
+		}
+		if msg.ctx && msg.is_split() {
+			return Err(syn::Error::new_spanned(
+				list,
+				"ctx can't be combined with send, request, subscribe or stream",
+			));
+		}
+		Ok(msg)
+	}
+
+	// true if each dispatch type has its own handler trait
+	fn is_split(&self) -> bool {
+		self.send || self.request || self.subscribe || self.stream
+	}
+
+	// returns the handler call for a dispatch type
+	// or None if the actor doesn't handle it
+	fn handler(&self, dispatch_type: &str) -> Option<proc_macro2::TokenStream> {
+		let version_ty = format_ident!("{}", self.ty);
+		let method = format_ident!("{}", dispatch_type);
+		if self.is_split() {
+			let (handled, handler_trait) = match dispatch_type {
+				"send" => (self.send, format_ident!("HandleSend")),
+				"request" => (self.request, format_ident!("HandleRequest")),
+				"stream" => (self.stream, format_ident!("HandleStream")),
+				_ => (self.subscribe, format_ident!("HandleSubscribe")),
+			};
+			if !handled {
+				return None;
+			}
+			if dispatch_type == "stream" {
+				return Some(quote! {
+					<Self as hollywood::HandleStream<#version_ty>>::stream(self, ctx, msg, ctx.stream_sender()?)
+				});
+			}
+			Some(quote! { <Self as hollywood::#handler_trait<#version_ty>>::#method(self, ctx, msg) })
+		} else if dispatch_type == "stream" {
+			// only HandleStream streams responses
+			None
+		} else if self.ctx {
+			Some(quote! { <Self as hollywood::HandleWithCtx<#version_ty>>::#method(self, ctx, msg) })
+		} else {
+			Some(quote! { <Self as Handle<#version_ty>>::#method(self, msg) })
+		}
+	}
+}
+
+fn get_version_variant(msg: &DispatchMsg) -> syn::Result<proc_macro2::TokenStream> {
+	let version_ty = format_ident!("{}", msg.ty);
+	let unsupported = quote! {
+		return Err(hollywood::UnsupportedDispatchType::new(
+			self.type_name_version(),
+			version,
+			dispatch_type.clone(),
+		).into());
+	};
+	let send = match msg.handler("send") {
+		Some(send) => quote! {
+			let result = #send.await;
+			return match result {
+				Ok(_) => Ok((None, None)),
+				Err(err) => Err(err.into()),
+			};
+		},
+		None => unsupported.clone(),
+	};
+	let request = match msg.handler("request") {
+		// HandleRequest responds with the response type paired with the msg
+		Some(request) if msg.is_split() => quote! {
+			let result = #request.await;
+			return match result {
+				Ok(resp) => Ok((
+					Some(<<#version_ty as hollywood::RequestMsg>::Response as Msg>::version()),
+					Some(resp.into_bytes()?),
+				)),
+				Err(err) => Err(err.into()),
+			};
+		},
+		Some(request) => quote! {
+			let result = #request.await;
+			return match result {
+				Ok(Some(msg)) => {
+					Ok((Some(#version_ty::version()), Some(msg.into_bytes()?)))
+				},
+				Ok(None) => Ok((Some(#version_ty::version()), None)),
+				Err(err) => Err(err.into()),
+			};
+		},
+		None => unsupported.clone(),
+	};
+	let subscribe = match msg.handler("subscribe") {
+		Some(subscribe) => quote! {
+			let result = #subscribe.await;
+			return match result {
+				Ok(_) => Ok((None, None)),
+				Err(err) => Err(err.into()),
+			};
+		},
+		None => unsupported.clone(),
+	};
+	let stream = match msg.handler("stream") {
+		Some(stream) => quote! {
+			let result = #stream.await;
+			return match result {
+				Ok(_) => Ok((None, None)),
+				Err(err) => Err(err.into()),
+			};
+		},
+		None => unsupported,
+	};
 	let code = quote! {
 		#version_ty::VERSION => {
 			let msg = #version_ty::from_bytes(bytes)?;
 			match dispatch_type {
 				&DispatchType::Send => {
-					let result = <Self as Handle<#version_ty>>::send(self, msg).await;
-					return match result {
-						Ok(_) => Ok((None, None)),
-						Err(err) => Err(err.into()),
-					};
+					#send
 				}
 				&DispatchType::Request => {
-					let result = <Self as Handle<#version_ty>>::request(self, msg).await;
-					return match result {
-						Ok(Some(msg)) => {
-							Ok((Some(#version_ty::version()), Some(msg.into_bytes()?)))
-						},
-						Ok(None) => Ok((Some(#version_ty::version()), None)),
-						Err(err) => Err(err.into()),
-					};
+					#request
 				}
 				&DispatchType::Subscribe => {
-					let result = <Self as Handle<#version_ty>>::subscribe(self, msg).await;
-					return match result {
-						Ok(_) => Ok((None, None)),
-						Err(err) => Err(err.into()),
-					};
+					#subscribe
+				}
+				&DispatchType::Stream => {
+					#stream
 				}
 			}
 		},
@@ -40,8 +182,8 @@ fn get_version_variant(ty: String) -> syn::Result<proc_macro2::TokenStream> {
 }
 
 // construct dispatch type for each item
-fn get_version_vec(ty: String) -> syn::Result<proc_macro2::TokenStream> {
-	let version_ty = format_ident!("{}", ty);
+fn get_version_vec(msg: &DispatchMsg) -> syn::Result<proc_macro2::TokenStream> {
+	let version_ty = format_ident!("{}", msg.ty);
 	let code = quote! {
 		#version_ty::dispatch_type()
 	};
@@ -84,11 +226,15 @@ fn impl_hollywood_dispatch(
 		match item {
 			// parse type token
 			syn::NestedMeta::Meta(syn::Meta::Path(path)) => {
-				msg_types.push(path.get_ident().unwrap().to_string());
+				msg_types.push(DispatchMsg::new(path.get_ident().unwrap().to_string()));
+			}
+			// parse type(ctx) token
+			syn::NestedMeta::Meta(syn::Meta::List(list)) => {
+				msg_types.push(DispatchMsg::from_list(list)?);
 			}
 			// parse "type" token
 			syn::NestedMeta::Lit(syn::Lit::Str(lit_str)) => {
-				msg_types.push(lit_str.value());
+				msg_types.push(DispatchMsg::new(lit_str.value()));
 			}
 			_ => {}
 		}
@@ -105,13 +251,16 @@ fn impl_hollywood_dispatch(
 	// dispatch fn
 	let version_arms = &msg_types
 		.iter()
-		.map(|attr| get_version_variant(attr.to_string()))
+		.map(get_version_variant)
 		.collect::<syn::Result<Vec<_>>>()
 		.unwrap();
 
+	// ctx is unused unless a msg type is dispatched to HandleWithCtx
 	let dispatch_fn = quote! {
+		#[allow(unused_variables)]
 		async fn dispatch(
 			&mut self,
+			ctx: &hollywood::Ctx,
 			version: String,
 			dispatch_type: &DispatchType,
 			bytes: &Vec<u8>,
@@ -119,7 +268,14 @@ fn impl_hollywood_dispatch(
 			debug!("[DISPATCH] type: {:?}, version: {:?}, len: {}", &dispatch_type, &version, &bytes.len());
 			match &version[..] {
 				#(#version_arms)*
-				&_ => panic!("dispatch found an unknown version variant"),
+				&_ => match self.fallback(&version, dispatch_type, bytes).await {
+					Some(result) => result,
+					None => Err(hollywood::UnsupportedVersion::new(
+						self.type_name_version(),
+						version,
+						Self::dispatch_types(),
+					).into()),
+				},
 			}
 		}
 	};
@@ -127,7 +283,7 @@ fn impl_hollywood_dispatch(
 	// dispatch_types fn
 	let version_items = &msg_types
 		.iter()
-		.map(|attr| get_version_vec(attr.to_string()))
+		.map(get_version_vec)
 		.collect::<syn::Result<Vec<_>>>()
 		.unwrap();
 
@@ -0,0 +1,215 @@
+//! Broker latency and throughput for queue and pubsub subscriptions.
+//!
+//! Requires a nats server (`docker-compose up` from the repo root):
+//!
+//! ```sh
+//! cargo bench -p hollywood --bench broker
+//! ```
+//!
+//! Set `HOLLYWOOD_BENCH_NATS_URI` to use a different server
+//! than `nats://127.0.0.1:14222`.
+//!
+//! The bench only uses APIs the polling broker (the parent of the
+//! commit that replaced it) already had. To compare, copy it into
+//! a checkout of that revision along with the `[dev-dependencies]`
+//! and `[[bench]]` sections of `hollywood/Cargo.toml`.
+
+use hollywood::prelude::actor::*;
+use hollywood::{ActorMailbox, Client, RunOpts};
+use hollywood_macro::Hollywood;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+const SYSTEM_NAME: &str = "hollywood-bench";
+const PUBSUB_SUBJECT: &str = "hollywood-bench.pubsub";
+
+// latency is measured one msg at a time with a pause
+// between msgs so the broker goes idle
+const LATENCY_MSGS: usize = 200;
+const LATENCY_PAUSE: Duration = Duration::from_millis(20);
+const THROUGHPUT_MSGS: u64 = 20_000;
+
+static HANDLED: AtomicU64 = AtomicU64::new(0);
+static NOTIFY: Notify = Notify::const_new();
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+pub enum BenchMsg {
+    Ping,
+}
+
+impl Msg for BenchMsg {
+    type Type = Self;
+    const VERSION: &'static str = "v1.0";
+}
+
+fn handled() {
+    HANDLED.fetch_add(1, Ordering::SeqCst);
+    NOTIFY.notify_one();
+}
+
+#[derive(Hollywood)]
+#[dispatch(BenchMsg)]
+pub struct BenchQueue {}
+
+impl Actor for BenchQueue {
+    const VERSION: &'static str = "v1.0";
+}
+
+#[async_trait]
+impl Handle<BenchMsg> for BenchQueue {
+    type Msg = BenchMsg;
+
+    async fn request(&mut self, msg: Self::Msg) -> Result<Option<Self::Msg>> {
+        Ok(Some(msg))
+    }
+
+    async fn send(&mut self, _: Self::Msg) -> Result<()> {
+        handled();
+        Ok(())
+    }
+
+    async fn subscribe(&mut self, _: Self::Msg) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Hollywood)]
+#[dispatch(BenchMsg)]
+pub struct BenchPubsub {}
+
+impl Actor for BenchPubsub {
+    const VERSION: &'static str = "v1.0";
+    fn subscribe_type() -> SubscribeType {
+        SubscribeType::Publish {
+            subject: PUBSUB_SUBJECT,
+        }
+    }
+}
+
+#[async_trait]
+impl Handle<BenchMsg> for BenchPubsub {
+    type Msg = BenchMsg;
+
+    async fn request(&mut self, _: Self::Msg) -> Result<Option<Self::Msg>> {
+        Ok(None)
+    }
+
+    async fn send(&mut self, _: Self::Msg) -> Result<()> {
+        Ok(())
+    }
+
+    async fn subscribe(&mut self, _: Self::Msg) -> Result<()> {
+        handled();
+        Ok(())
+    }
+}
+
+/// Wait until the actors handled `count` msgs in total.
+async fn wait_for_handled(count: u64) -> Result<()> {
+    while HANDLED.load(Ordering::SeqCst) < count {
+        timeout(Duration::from_secs(10), NOTIFY.notified())
+            .await
+            .map_err(|_| anyhow::anyhow!("timed out waiting for the actor"))?;
+    }
+    Ok(())
+}
+
+fn report_latency(name: &str, mut samples: Vec<Duration>) {
+    samples.sort();
+    let mean = samples.iter().sum::<Duration>() / samples.len() as u32;
+    let p50 = samples[samples.len() / 2];
+    let p99 = samples[samples.len() * 99 / 100];
+    let max = samples[samples.len() - 1];
+    println!(
+        "{:<24} mean {:>10.3?}  p50 {:>10.3?}  p99 {:>10.3?}  max {:>10.3?}",
+        name, mean, p50, p99, max
+    );
+}
+
+fn report_throughput(name: &str, msgs: u64, elapsed: Duration) {
+    println!(
+        "{:<24} {:>10.0} msgs/sec ({} msgs in {:.3?})",
+        name,
+        msgs as f64 / elapsed.as_secs_f64(),
+        msgs,
+        elapsed
+    );
+}
+
+/// Latency from sending a msg until the actor handles it.
+async fn handled_latency<F, Fut>(name: &str, send: F) -> Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<()>>,
+{
+    let mut samples = Vec::with_capacity(LATENCY_MSGS);
+    for _ in 0..LATENCY_MSGS {
+        sleep(LATENCY_PAUSE).await;
+        let expected = HANDLED.load(Ordering::SeqCst) + 1;
+        let start = Instant::now();
+        send().await?;
+        wait_for_handled(expected).await?;
+        samples.push(start.elapsed());
+    }
+    report_latency(name, samples);
+    Ok(())
+}
+
+/// Throughput of sending msgs until the actor handles all of them.
+async fn handled_throughput<F, Fut>(name: &str, send: F) -> Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<()>>,
+{
+    let expected = HANDLED.load(Ordering::SeqCst) + THROUGHPUT_MSGS;
+    let start = Instant::now();
+    for _ in 0..THROUGHPUT_MSGS {
+        send().await?;
+    }
+    wait_for_handled(expected).await?;
+    report_throughput(name, THROUGHPUT_MSGS, start.elapsed());
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let nats_uri = std::env::var("HOLLYWOOD_BENCH_NATS_URI")
+        .unwrap_or_else(|_| "nats://127.0.0.1:14222".to_string());
+
+    tokio::spawn(hollywood::run(RunOpts::new(
+        SYSTEM_NAME.into(),
+        BenchQueue {},
+        nats_uri.clone(),
+    )));
+    tokio::spawn(hollywood::run(RunOpts::new(
+        SYSTEM_NAME.into(),
+        BenchPubsub {},
+        nats_uri.clone(),
+    )));
+
+    let queue = BenchQueue::mailbox::<BenchMsg>(SYSTEM_NAME.into(), nats_uri.clone()).await?;
+    let client = Client::new(nats_uri).await?;
+
+    // wait for the agents to subscribe
+    while queue.health(1).await.is_err() {
+        sleep(Duration::from_millis(100)).await;
+    }
+    sleep(Duration::from_millis(500)).await;
+
+    handled_latency("queue send", || queue.send(BenchMsg::Ping)).await?;
+    handled_latency("pubsub publish", || {
+        client.publish(PUBSUB_SUBJECT, BenchMsg::Ping)
+    })
+    .await?;
+
+    handled_throughput("queue send", || queue.send(BenchMsg::Ping)).await?;
+    handled_throughput("pubsub publish", || {
+        client.publish(PUBSUB_SUBJECT, BenchMsg::Ping)
+    })
+    .await?;
+    Ok(())
+}
@@ -1,19 +1,33 @@
-use crate::broker::Broker;
+use crate::agent::Agent;
 use crate::client;
 use crate::common;
+use crate::ctx::Ctx;
+use crate::dedup::DedupOpts;
 use crate::env::{hollywood_system, hollywood_system_nats_uri};
+use crate::jetstream::DurableOpts;
+use crate::metadata::Metadata;
+use crate::overflow::{MailboxOverflow, OverflowPolicy};
+use crate::priority::{MailboxReceiver, MailboxSender, Priority};
+use crate::retry::RetryPolicy;
+use crate::scheduler::Scheduler;
+use crate::stream::{HollywoodStreamFrame, StreamOpts, StreamSender};
+use crate::supervisor::SupervisionStrategy;
 use anyhow::Result;
-use async_channel;
 use async_trait::async_trait;
-use log::{error, info, warn};
-use nats::asynk::Connection;
+use log::{info, warn};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::time::{sleep, Duration};
 
 #[allow(non_upper_case_globals)]
 pub const VERSION_v1_0: &'static str = "v1.0";
 
+/// Default amount of time an agent spends draining
+/// its mailbox after receiving a shutdown signal.
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
 /// Returns the mailbox name for a given system and actor.
 /// This value is used as the Subject for reading/writing nats
 /// messages.
@@ -21,6 +35,44 @@ pub(crate) fn mailbox_name(system_name: &String, actor_name: &String) -> String
     format!("hollywood://{}@{}", system_name, actor_name)
 }
 
+/// Returns the queue mailbox name for a given system,
+/// actor type name/version and msg dispatch type.
+pub(crate) fn actor_mailbox_name(
+    system_name: &String,
+    actor_type_name_version: &String,
+    dispatch_type: &String,
+) -> String {
+    mailbox_name(
+        system_name,
+        &format!("{}::{}", actor_type_name_version, dispatch_type),
+    )
+}
+
+/// Returns the health check subject for a given system
+/// and actor type name/version (i.e. `ActorX/v1.0`).
+pub(crate) fn health_subject(system_name: &String, actor_type_name_version: &String) -> String {
+    mailbox_name(system_name, &format!("{}::health", actor_type_name_version))
+}
+
+/// Returns the health check subject of a single agent instance.
+pub(crate) fn instance_health_subject(
+    system_name: &String,
+    actor_type_name_version: &String,
+    instance_id: &str,
+) -> String {
+    format!(
+        "{}.{}",
+        health_subject(system_name, actor_type_name_version),
+        instance_id
+    )
+}
+
+/// Queue group shared by every agent of an
+/// actor type/version on `PublishGroup` subjects.
+pub(crate) fn topic_group_name(system_name: &String, actor_type_name_version: &String) -> String {
+    mailbox_name(system_name, actor_type_name_version)
+}
+
 /// Message type for sending nats requests
 /// that expect a reply.
 #[derive(Serialize, Deserialize, Debug)]
@@ -28,6 +80,19 @@ pub(crate) struct HollywoodRequest {
     pub id: String,
     pub msg: Vec<u8>,
     pub msg_version: String,
+    // epoch millis after which the caller stops waiting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<i64>,
+    #[serde(default, skip_serializing_if = "Priority::is_normal")]
+    pub priority: Priority,
+    // W3C trace context of the sender's span
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    pub meta: Metadata,
+    // set if the responses are streamed to the reply subject
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<StreamOpts>,
 }
 
 /// Message type for returning an Actor response.
@@ -37,6 +102,8 @@ pub(crate) struct HollywoodResponse {
     pub id: String,
     pub msg: Option<Vec<u8>>,
     pub msg_version: String,
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    pub meta: Metadata,
 }
 
 /// Message type that sends a nats message
@@ -46,6 +113,13 @@ pub(crate) struct HollywoodSend {
     pub id: String,
     pub msg: Vec<u8>,
     pub msg_version: String,
+    #[serde(default, skip_serializing_if = "Priority::is_normal")]
+    pub priority: Priority,
+    // W3C trace context of the sender's span
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    pub meta: Metadata,
 }
 
 /// Message type that delivers a pubsub message
@@ -54,6 +128,13 @@ pub(crate) struct HollywoodPublish {
     pub id: String,
     pub msg: Vec<u8>,
     pub msg_version: String,
+    #[serde(default, skip_serializing_if = "Priority::is_normal")]
+    pub priority: Priority,
+    // W3C trace context of the sender's span
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    pub meta: Metadata,
 }
 
 /// Main message wrapper type for all messages
@@ -65,6 +146,7 @@ pub(crate) enum HollywoodMsg {
     Response(HollywoodResponse),
     Send(HollywoodSend),
     Publish(HollywoodPublish),
+    Stream(HollywoodStreamFrame),
 }
 
 impl Msg for HollywoodMsg {
@@ -79,35 +161,105 @@ impl Msg for HollywoodMsg {
 // etc...
 pub(crate) struct ActorRequest {
     pub id: String,
+    // subject the msg was received on
+    pub subject: String,
     pub msg_version: String,
     pub msg: Vec<u8>,
     pub reply_id: String,
+    // epoch millis after which the caller stops waiting
+    pub deadline: Option<i64>,
+    pub priority: Priority,
+    // W3C trace context received with the msg
+    pub traceparent: Option<String>,
+    pub meta: Metadata,
+    pub stream: Option<StreamOpts>,
 }
 
 pub(crate) struct ActorSend {
     pub id: String,
+    // subject the msg was received on
+    pub subject: String,
     pub msg_version: String,
     pub msg: Vec<u8>,
+    // starts at 1 and increments on every retry
+    pub attempt: u32,
+    pub priority: Priority,
+    // W3C trace context received with the msg
+    pub traceparent: Option<String>,
+    pub meta: Metadata,
+    // JetStream ack subject of msgs from a durable mailbox
+    pub ack: Option<String>,
 }
 
 pub(crate) struct ActorSubscribe {
     pub id: String,
+    // subject the msg was received on
+    pub subject: String,
     pub msg_version: String,
     pub msg: Vec<u8>,
+    // starts at 1 and increments on every retry
+    pub attempt: u32,
+    pub priority: Priority,
+    // W3C trace context received with the msg
+    pub traceparent: Option<String>,
+    pub meta: Metadata,
 }
 
 pub(crate) enum ActorMsg {
     Request(ActorRequest),
     Send(ActorSend),
     Subscribe(ActorSubscribe),
-    #[allow(dead_code)]
-    Health,
-    #[allow(dead_code)]
-    Shutdown,
 }
 
-pub(crate) type ActorSender = async_channel::Sender<ActorMsg>;
-pub(crate) type ActorReceiver = async_channel::Receiver<ActorMsg>;
+impl ActorMsg {
+    /// Returns the dispatch type and msg version.
+    pub(crate) fn dispatch_type(&self) -> (DispatchType, &str) {
+        match self {
+            ActorMsg::Request(req) if req.stream.is_some() => {
+                (DispatchType::Stream, &req.msg_version)
+            }
+            ActorMsg::Request(req) => (DispatchType::Request, &req.msg_version),
+            ActorMsg::Send(send) => (DispatchType::Send, &send.msg_version),
+            ActorMsg::Subscribe(sub) => (DispatchType::Subscribe, &sub.msg_version),
+        }
+    }
+}
+
+/// Health check status returned by an agent
+/// on its health subject.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HealthStatus {
+    /// Actor type name and version (i.e. `ActorX/v1.0`)
+    pub actor: String,
+    /// Id of the agent instance that answered
+    #[serde(default)]
+    pub instance_id: String,
+    /// True if the broker is running and `Actor::health` returned Ok
+    pub healthy: bool,
+    /// Number of seconds since the agent started
+    pub uptime_secs: u64,
+    /// Number of unprocessed messages in the actor mailbox
+    pub mailbox_len: usize,
+    /// Epoch seconds of the last handled send/request/subscribe message
+    pub last_handled_at: Option<i64>,
+    /// True if all broker subscriptions are still running
+    pub broker_alive: bool,
+    /// Number of actor restarts after a handler panic
+    pub restarts: u64,
+    /// The error returned from `Actor::health`, if any
+    pub actor_error: Option<String>,
+    /// Number of times each mailbox overflow policy was applied
+    #[serde(default)]
+    pub mailbox_overflow: MailboxOverflow,
+}
+
+impl Msg for HealthStatus {
+    type Type = Self;
+    const VERSION: &'static str = VERSION_v1_0;
+}
+
+pub(crate) type ActorSender = MailboxSender;
+pub(crate) type ActorReceiver = MailboxReceiver;
 
 #[derive(Clone)]
 pub enum SubscribeType {
@@ -122,6 +274,40 @@ pub enum SubscribeType {
     // actors should handle the same subject and message
     // type. Actors should implement the subscribe handler.
     Publish { subject: &'static str },
+
+    // Same as Publish for a list of subjects. Subjects
+    // may use nats wildcards (i.e. `orders.*` or `orders.>`).
+    // Handlers read the subject a msg was published to
+    // with `Ctx::subject`.
+    PublishMany { subjects: &'static [&'static str] },
+
+    // Same as PublishMany but agents of the same actor
+    // type/version join a queue group, so each msg is
+    // handled once per actor type instead of once per
+    // running agent. Other actor types subscribed to
+    // the same subjects still receive every msg.
+    PublishGroup { subjects: &'static [&'static str] },
+
+    // Same as Queue but send msgs are stored in a
+    // JetStream stream until an agent handles them, so
+    // msgs sent while no agent is running aren't lost.
+    // Requires JetStream on the nats server. Requests
+    // are still answered on the queue mailboxes.
+    Durable,
+}
+
+impl SubscribeType {
+    /// Returns the pubsub subjects to subscribe to
+    /// (empty for `Queue` and `Durable`).
+    pub fn subjects(&self) -> Vec<&'static str> {
+        match self {
+            SubscribeType::Queue | SubscribeType::Durable => vec![],
+            SubscribeType::Publish { subject } => vec![*subject],
+            SubscribeType::PublishMany { subjects } | SubscribeType::PublishGroup { subjects } => {
+                subjects.to_vec()
+            }
+        }
+    }
 }
 
 pub trait Msg
@@ -152,13 +338,59 @@ where
     fn from_bytes(msg: &Vec<u8>) -> Result<Self> {
         Ok(common::deserialize::<Self>(msg)?)
     }
+
+    // Returns the msg a client receives when a request handler
+    // responds without a msg. The default None makes clients
+    // return an error for empty responses.
+    fn empty() -> Option<Self> {
+        None
+    }
+}
+
+/// Pairs a request msg with the msg type actors respond with.
+/// `Client::request` and `Mailbox::request` return the response
+/// type. Msgs handled by `Handle<M>` respond with `Self`.
+pub trait RequestMsg
+where
+    Self: Msg,
+{
+    type Response: Msg + Send + 'static;
 }
 
-#[derive(Debug)]
+/// Response for requests that only need an acknowledgement.
+/// Empty responses (i.e. `Handle::request` returning `Ok(None)`)
+/// are also received as an `Ack`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ack;
+
+impl Msg for Ack {
+    type Type = Self;
+    const VERSION: &'static str = VERSION_v1_0;
+
+    fn empty() -> Option<Self> {
+        Some(Ack)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum DispatchType {
     Send,
     Request,
     Subscribe,
+    // request with streamed responses
+    Stream,
+}
+
+impl DispatchType {
+    /// Returns the dispatch type name used in metrics and spans.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            DispatchType::Send => "send",
+            DispatchType::Request => "request",
+            DispatchType::Subscribe => "subscribe",
+            DispatchType::Stream => "stream",
+        }
+    }
 }
 
 // DispatchResponse: (message version, message)
@@ -174,6 +406,7 @@ pub trait Dispatch {
     // `dispatch` a message to an actor
     async fn dispatch(
         &mut self,
+        ctx: &Ctx,
         version: String,
         dispatch_type: &DispatchType,
         bytes: &Vec<u8>,
@@ -195,7 +428,10 @@ where
 /// Actor trait for defining an expected message
 /// type and how to handle request/send type requests.
 #[async_trait]
-pub trait Actor {
+pub trait Actor
+where
+    Self: Send,
+{
     const VERSION: &'static str;
 
     fn version() -> &'static str {
@@ -221,10 +457,67 @@ pub trait Actor {
     // How we expect to run this actor..
     // If unimplemented, the default is `SubscribeType::Queue`
     // Override this with SubscribeType::Publish(subject)
-    // if you want read from a pubsub topic instead
+    // or SubscribeType::PublishMany(subjects) if you
+    // want read from pubsub topics instead
     fn subscribe_type() -> SubscribeType {
         SubscribeType::Queue
     }
+
+    fn instance_subscriptions(&self) -> Vec<SubscribeType> {
+        Self::subscriptions()
+    }
+
+    // Every subscription the agent runs for this actor.
+    // Defaults to `subscribe_type`. Override this to
+    // answer requests on the queue mailboxes and read
+    // pubsub topics at the same time, i.e.
+    // `vec![SubscribeType::Queue, SubscribeType::Publish { subject }]`
+    fn subscriptions() -> Vec<SubscribeType> {
+        vec![Self::subscribe_type()]
+    }
+
+    // Called with the agent scheduler before `on_start`.
+    // Keep a copy if the actor needs to send itself
+    // delayed or periodic messages.
+    fn set_scheduler(&mut self, _scheduler: Scheduler) {}
+
+    // Called before the agent subscribes to its mailboxes.
+    // Use this to open connections the actor handlers need.
+    // Returning an error stops the agent from running.
+    async fn on_start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // Called once the agent has stopped reading from nats
+    // and drained its mailbox. Use this to flush buffers or
+    // close connections before the process exits.
+    async fn on_stop(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // Called with the raw message bytes when the message version
+    // doesn't match any of the actor dispatch types. Return `None`
+    // to reply with an `UnsupportedVersion` error.
+    async fn fallback(
+        &mut self,
+        _version: &str,
+        _dispatch_type: &DispatchType,
+        _bytes: &[u8],
+    ) -> Option<Result<DispatchResponse>> {
+        None
+    }
+
+    // Called when a send or subscribe handler returns an error.
+    // Request handler errors are returned to the caller instead.
+    async fn on_error(&mut self, _id: &str, _dispatch_type: &DispatchType, _err: &anyhow::Error) {}
+
+    // Called by each worker every 10 seconds. Override
+    // this to report the health of actor dependencies
+    // (i.e. database connections). An error marks the
+    // agent as unhealthy until the next call succeeds.
+    async fn health(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -239,198 +532,115 @@ where
     async fn subscribe(&mut self, msg: Self::Msg) -> Result<()>;
 }
 
-/// Agent is responsible for dispatching
-/// messages to an actor instance that
-/// are read from a Broker
-struct Agent<A: Actor + Dispatch> {
-    system_name: String,
-    actor: A,
-    max_size: Option<u32>,
-    sender: ActorSender,
-    receiver: ActorReceiver,
-    nats: Connection,
+/// Handle msgs along with the context they were received
+/// with. Use `#[dispatch(MyMsg(ctx))]` to dispatch `MyMsg`
+/// to this trait instead of `Handle`.
+#[async_trait]
+pub trait HandleWithCtx<M>
+where
+    Self: Actor,
+    M: Msg,
+{
+    async fn send(&mut self, ctx: &Ctx, msg: M) -> Result<()>;
+    async fn request(&mut self, ctx: &Ctx, msg: M) -> Result<Option<M>>;
+    async fn subscribe(&mut self, ctx: &Ctx, msg: M) -> Result<()>;
 }
 
-impl<A: Actor + Dispatch> Agent<A> {
-    fn new(system_name: String, actor: A, max_size: Option<u32>, nats: Connection) -> Self {
-        let (tx, rx) = async_channel::unbounded();
-        Agent {
-            system_name: system_name,
-            actor: actor,
-            max_size: max_size,
-            sender: tx,
-            receiver: rx,
-            nats: nats,
-        }
-    }
-
-    fn sender(&self) -> ActorSender {
-        self.sender.clone()
-    }
-
-    async fn recv(&mut self) -> Result<ActorMsg> {
-        match self.receiver.recv().await {
-            Ok(msg) => Ok(msg),
-            Err(err) => Err(err.into()),
-        }
-    }
-
-    /// Publish the response to an actor request
-    async fn reply(&mut self, reply_id: String, msg: HollywoodMsg) {
-        match serde_json::to_vec(&msg) {
-            Ok(msg) => match self.nats.publish(&reply_id[..], msg).await {
-                Err(err) => {
-                    error!("sending response to nats: {:?}", &err);
-                }
-                _ => {}
-            },
-            Err(err) => {
-                error!("serializing request response: {:?}", &err);
-            }
-        }
-    }
+/// Handle send msgs. List the dispatch types an actor
+/// implements (i.e. `#[dispatch(MyMsg(send, request))]`)
+/// and the agent replies with an `UnsupportedDispatchType`
+/// error to the others.
+#[async_trait]
+pub trait HandleSend<M>
+where
+    Self: Actor,
+    M: Msg,
+{
+    async fn send(&mut self, ctx: &Ctx, msg: M) -> Result<()>;
+}
 
-    async fn handle_mailbox_msg(&mut self, mailbox_msg: ActorMsg) {
-        match mailbox_msg {
-            ActorMsg::Health => {
-                // TODO: figure out what a health check means
-                todo!("implement health check");
-            }
-            ActorMsg::Shutdown => {
-                todo!("implement shutdown");
-            }
-            ActorMsg::Request(req) => {
-                self.handle_msg(
-                    req.id,
-                    req.msg_version,
-                    &DispatchType::Request,
-                    &req.msg,
-                    Some(req.reply_id),
-                )
-                .await;
-            }
-            ActorMsg::Send(send) => {
-                self.handle_msg(
-                    send.id,
-                    send.msg_version,
-                    &DispatchType::Send,
-                    &send.msg,
-                    None,
-                )
-                .await;
-            }
-            ActorMsg::Subscribe(sub) => {
-                self.handle_msg(
-                    sub.id,
-                    sub.msg_version,
-                    &DispatchType::Subscribe,
-                    &sub.msg,
-                    None,
-                )
-                .await;
-            }
-        }
-    }
+/// Handle request msgs and respond with the response type
+/// paired with the msg. Use `#[dispatch(MyMsg(request))]`.
+#[async_trait]
+pub trait HandleRequest<M>
+where
+    Self: Actor,
+    M: RequestMsg,
+{
+    async fn request(&mut self, ctx: &Ctx, msg: M) -> Result<M::Response>;
+}
 
-    /// Wrapper for handling send/request/subscribe type messages
-    async fn handle_msg(
+/// Handle requests by streaming a sequence of responses.
+/// Use `#[dispatch(MyMsg(stream))]` and call `Mailbox::request_stream`.
+/// The agent ends the stream once the handler returns.
+#[async_trait]
+pub trait HandleStream<M>
+where
+    Self: Actor,
+    M: RequestMsg,
+{
+    async fn stream(
         &mut self,
-        id: String,
-        version: String,
-        dispatch_type: &DispatchType,
-        msg: &Vec<u8>,
-        reply_id: Option<String>,
-    ) {
-        let result = Dispatch::dispatch(&mut self.actor, version, &dispatch_type, msg).await;
-        let hollywood_msg = match result {
-            Ok((msg_version, msg)) => match dispatch_type {
-                DispatchType::Request => {
-                    let resp = HollywoodResponse {
-                        id: id,
-                        msg_version: msg_version.unwrap_or("unknown_version").to_string(),
-                        msg: msg,
-                        error: None,
-                    };
-                    HollywoodMsg::Response(resp)
-                }
-                _ => return,
-            },
-            Err(err) => match dispatch_type {
-                DispatchType::Request => {
-                    let resp = HollywoodResponse {
-                        id: id,
-                        msg_version: "".to_string(),
-                        msg: None,
-                        error: Some(err.to_string()),
-                    };
-                    HollywoodMsg::Response(resp)
-                }
-                _ => return,
-            },
-        };
-
-        if reply_id.is_some() {
-            let reply_id = reply_id.unwrap();
-            self.reply(reply_id, hollywood_msg).await;
-        }
-    }
-
-    /// Run implements a basic runtime for running an
-    /// agent (broker and actor mailbox consumer) for given
-    /// system and actor instance.
-    async fn run(&mut self) -> Result<()> {
-        // run broker here...
-        let system_name = &self.system_name;
-        let actor_type_name_version = &self.actor.type_name_version();
-
-        // prepare the broker for each mailbox
-        let mailbox_sender = self.sender();
-        let mailbox_max_size = self.max_size.clone();
-        let nats = self.nats.clone();
-        let subscribe_type = self.actor.instance_subscribe_type().clone();
-        let mailbox_names = self
-            .actor
-            .instance_dispatch_types()
-            .into_iter()
-            .map(|msg_version| {
-                let actor_name = format!("{}::{}", &actor_type_name_version, &msg_version);
-                mailbox_name(&system_name, &actor_name)
-            })
-            .collect::<Vec<_>>();
-
-        // run broker...
-        let broker = Broker::new(
-            actor_type_name_version.to_owned(),
-            mailbox_names,
-            mailbox_sender,
-            mailbox_max_size,
-            nats,
-            subscribe_type,
-        );
+        ctx: &Ctx,
+        msg: M,
+        responses: StreamSender<M::Response>,
+    ) -> Result<()>;
+}
 
-        let _ = broker.run().await?;
-        // run the mailbox receiver...
-        loop {
-            if let Ok(mailbox_msg) = self.recv().await {
-                self.handle_mailbox_msg(mailbox_msg).await
-            }
-        }
-    }
+/// Handle pubsub msgs. Use `#[dispatch(MyMsg(subscribe))]`.
+#[async_trait]
+pub trait HandleSubscribe<M>
+where
+    Self: Actor,
+    M: Msg,
+{
+    async fn subscribe(&mut self, ctx: &Ctx, msg: M) -> Result<()>;
 }
 
+/// Returns a new actor instance. Used to create
+/// additional actor instances for agent workers.
+pub(crate) type ActorFactory<A> = Arc<dyn Fn() -> A + Send + Sync>;
+
 /// RunOpts defines an actor instance and
 /// a set of common configuration options.
 pub struct RunOpts<A: Actor + Dispatch> {
     /// The system name we want to connect too.
-    system_name: String,
+    pub(crate) system_name: String,
     /// The actor instance we want to run.
-    actor: A,
+    pub(crate) actor: A,
+    /// Creates an actor instance for each additional
+    /// worker if concurrency is greater than one.
+    pub(crate) actor_factory: Option<ActorFactory<A>>,
+    /// The number of workers (actor instances) that
+    /// handle mailbox messages in parallel. Default is 1.
+    pub(crate) concurrency: usize,
     /// The maximum size of unprocessed messages
     /// from the actor mailbox. Default is None which means
     /// the internal mailbox channel is unbounded.
-    actor_mailbox_max_size: Option<u32>,
+    pub(crate) actor_mailbox_max_size: Option<u32>,
+    /// What to do with msgs once the mailbox is full.
+    /// Default is `OverflowPolicy::Block`.
+    pub(crate) mailbox_overflow: OverflowPolicy,
+    /// The maximum amount of time to spend draining the
+    /// actor mailbox on shutdown. Default is 30 seconds.
+    pub(crate) shutdown_timeout: Duration,
+    /// How to handle actor handler panics. Default is
+    /// `SupervisionStrategy::Escalate`.
+    pub(crate) supervision: SupervisionStrategy,
+    /// How to redeliver msgs after a send/subscribe
+    /// handler error. Default is no retries.
+    pub(crate) retry_policy: RetryPolicy,
+    /// Local address to serve Prometheus metrics
+    /// on `/metrics`. Default is None (no endpoint).
+    pub(crate) metrics_endpoint: Option<SocketAddr>,
+    /// The JetStream consumer of `SubscribeType::Durable`
+    /// actors. Default is `DurableOpts::new()`.
+    pub(crate) durable_opts: DurableOpts,
+    /// How long to remember handled msg ids to skip
+    /// duplicates. Default is None (no deduplication).
+    pub(crate) dedup_opts: Option<DedupOpts>,
     /// The nats connection string as a uri.
-    nats_uri: String,
+    pub(crate) nats_uri: String,
 }
 
 impl<A: Actor + Dispatch> RunOpts<A> {
@@ -438,7 +648,16 @@ impl<A: Actor + Dispatch> RunOpts<A> {
         Self {
             system_name: system_name,
             actor: actor,
+            actor_factory: None,
+            concurrency: 1,
             actor_mailbox_max_size: None,
+            mailbox_overflow: OverflowPolicy::Block,
+            shutdown_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            supervision: SupervisionStrategy::Escalate,
+            retry_policy: RetryPolicy::default(),
+            metrics_endpoint: None,
+            durable_opts: DurableOpts::default(),
+            dedup_opts: None,
             nats_uri,
         }
     }
@@ -454,7 +673,16 @@ impl<A: Actor + Dispatch> RunOpts<A> {
         Ok(Self {
             system_name: system_name,
             actor: actor,
+            actor_factory: None,
+            concurrency: 1,
             actor_mailbox_max_size: None,
+            mailbox_overflow: OverflowPolicy::Block,
+            shutdown_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            supervision: SupervisionStrategy::Escalate,
+            retry_policy: RetryPolicy::default(),
+            metrics_endpoint: None,
+            durable_opts: DurableOpts::default(),
+            dedup_opts: None,
             nats_uri,
         })
     }
@@ -463,17 +691,84 @@ impl<A: Actor + Dispatch> RunOpts<A> {
         self.actor_mailbox_max_size = size;
         self
     }
+
+    /// Define what to do with msgs read from nats
+    /// once the mailbox reached its max size.
+    pub fn with_mailbox_overflow(mut self, overflow: OverflowPolicy) -> Self {
+        self.mailbox_overflow = overflow;
+        self
+    }
+
+    pub fn with_shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// Run `concurrency` workers, each with its own actor
+    /// instance, that read from the same actor mailbox.
+    /// Requires `with_actor_factory` if greater than one.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = std::cmp::max(concurrency, 1);
+        self
+    }
+
+    /// Define how the agent handles actor handler panics.
+    /// Restart strategies require `with_actor_factory`.
+    pub fn with_supervision(mut self, supervision: SupervisionStrategy) -> Self {
+        self.supervision = supervision;
+        self
+    }
+
+    /// Define how to redeliver msgs after `Handle::send`
+    /// or `Handle::subscribe` returns an error.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Serve the agent metrics in the Prometheus text format
+    /// on `http://{addr}/metrics`. Installs a `PrometheusRecorder`
+    /// unless `metrics::set_recorder` was called first.
+    pub fn with_metrics_endpoint(mut self, addr: SocketAddr) -> Self {
+        self.metrics_endpoint = Some(addr);
+        self
+    }
+
+    /// Define how the agent reads the durable mailbox of
+    /// a `SubscribeType::Durable` actor (max deliveries, ack
+    /// wait and where a new actor version starts reading).
+    pub fn with_durable_opts(mut self, durable_opts: DurableOpts) -> Self {
+        self.durable_opts = durable_opts;
+        self
+    }
+
+    /// Skip send/subscribe msgs with an id the agent handled
+    /// within the dedup window and answer duplicate requests
+    /// with the cached response instead of re-running the handler.
+    pub fn with_dedup(mut self, dedup_opts: DedupOpts) -> Self {
+        self.dedup_opts = Some(dedup_opts);
+        self
+    }
+
+    /// Define how to create additional actor instances
+    /// and how to rebuild an actor after a panic.
+    pub fn with_actor_factory<F>(mut self, factory: F) -> Self
+    where
+        F: Fn() -> A + Send + Sync + 'static,
+    {
+        self.actor_factory = Some(Arc::new(factory));
+        self
+    }
 }
 
 /// This is the public interface for running an actor.
-pub async fn run<A: Actor + Dispatch>(opts: RunOpts<A>) -> Result<()> {
-    let system_name = opts.system_name;
-    let actor = opts.actor;
-    let actor_mailbox_max_size = opts.actor_mailbox_max_size;
+/// Returns once the agent receives SIGINT/SIGTERM and
+/// finishes shutting down.
+pub async fn run<A: Actor + Dispatch + 'static>(opts: RunOpts<A>) -> Result<()> {
     let nats_uri = &opts.nats_uri;
 
     // TODO: set a max retries here...
-    let mut result = nats::asynk::connect(&nats_uri).await;
+    let mut result = nats::asynk::connect(nats_uri).await;
     loop {
         match result {
             Err(err) => {
@@ -483,7 +778,7 @@ pub async fn run<A: Actor + Dispatch>(opts: RunOpts<A>) -> Result<()> {
                     &err
                 );
                 sleep(Duration::from_millis(1000)).await;
-                result = nats::asynk::connect(&nats_uri).await;
+                result = nats::asynk::connect(nats_uri).await;
             }
             Ok(_) => {
                 info!("{} agent connected to nats...", A::type_name());
@@ -494,6 +789,281 @@ pub async fn run<A: Actor + Dispatch>(opts: RunOpts<A>) -> Result<()> {
     // unwrap the nats client
     let nats_client = result.unwrap();
     info!("{} agent running", A::type_name());
-    let mut agent = Agent::new(system_name, actor, actor_mailbox_max_size, nats_client);
+    let mut agent = Agent::new(opts, nats_client)?;
     agent.run().await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{is_unsupported, UnsupportedDispatchType, UnsupportedVersion};
+    use crate::testing;
+    use hollywood_macro::Hollywood;
+    // used by the dispatch fn the `Hollywood` derive generates
+    use log::debug;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum PingMsg {
+        Ping,
+    }
+
+    impl Msg for PingMsg {
+        type Type = Self;
+        const VERSION: &'static str = "v1.0";
+    }
+
+    #[derive(Hollywood)]
+    #[dispatch(PingMsg)]
+    struct PingActor {}
+
+    impl Actor for PingActor {
+        const VERSION: &'static str = "v1.0";
+    }
+
+    #[async_trait]
+    impl Handle<PingMsg> for PingActor {
+        type Msg = PingMsg;
+
+        async fn send(&mut self, _: Self::Msg) -> Result<()> {
+            Ok(())
+        }
+
+        async fn request(&mut self, msg: Self::Msg) -> Result<Option<Self::Msg>> {
+            Ok(Some(msg))
+        }
+
+        async fn subscribe(&mut self, _: Self::Msg) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    // answers unknown msg versions with the raw bytes
+    #[derive(Hollywood)]
+    #[dispatch(PingMsg)]
+    struct EchoActor {}
+
+    #[async_trait]
+    impl Actor for EchoActor {
+        const VERSION: &'static str = "v1.0";
+
+        async fn fallback(
+            &mut self,
+            version: &str,
+            _dispatch_type: &DispatchType,
+            bytes: &[u8],
+        ) -> Option<Result<DispatchResponse>> {
+            match version {
+                "v0.9" => Some(Ok((None, Some(bytes.to_vec())))),
+                _ => None,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Handle<PingMsg> for EchoActor {
+        type Msg = PingMsg;
+
+        async fn send(&mut self, _: Self::Msg) -> Result<()> {
+            Ok(())
+        }
+
+        async fn request(&mut self, msg: Self::Msg) -> Result<Option<Self::Msg>> {
+            Ok(Some(msg))
+        }
+
+        async fn subscribe(&mut self, _: Self::Msg) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_version() {
+        let ctx = testing::ctx(DispatchType::Request, "v2.0").await;
+        let bytes = PingMsg::Ping.into_bytes().unwrap();
+        let mut actor = PingActor {};
+
+        let (version, resp) = actor
+            .dispatch(&ctx, "v1.0".into(), &DispatchType::Request, &bytes)
+            .await
+            .unwrap();
+        assert_eq!(version, Some("v1.0"));
+        assert_eq!(resp, Some(bytes.clone()));
+
+        let err = actor
+            .dispatch(&ctx, "v2.0".into(), &DispatchType::Request, &bytes)
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<UnsupportedVersion>().unwrap();
+        assert_eq!(err.actor, "PingActor/v1.0");
+        assert_eq!(err.version, "v2.0");
+        assert_eq!(err.dispatch_types, vec!["PingMsg/v1.0".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_fallback() {
+        let ctx = testing::ctx(DispatchType::Send, "v0.9").await;
+        let bytes = b"{\"legacy\":true}".to_vec();
+        let mut actor = EchoActor {};
+
+        let (_, resp) = actor
+            .dispatch(&ctx, "v0.9".into(), &DispatchType::Send, &bytes)
+            .await
+            .unwrap();
+        assert_eq!(resp, Some(bytes.clone()));
+
+        // versions the fallback skips are still unsupported
+        let err = actor
+            .dispatch(&ctx, "v0.8".into(), &DispatchType::Send, &bytes)
+            .await
+            .unwrap_err();
+        assert!(is_unsupported(&err));
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct CountMsg {
+        to: u32,
+    }
+
+    impl Msg for CountMsg {
+        type Type = Self;
+        const VERSION: &'static str = "v2.0";
+    }
+
+    impl RequestMsg for CountMsg {
+        type Response = PingMsg;
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct IdMsg {
+        id: String,
+    }
+
+    impl Msg for IdMsg {
+        type Type = Self;
+        const VERSION: &'static str = "v3.0";
+    }
+
+    #[derive(Hollywood)]
+    #[dispatch(PingMsg(send), CountMsg(request, stream), IdMsg(ctx))]
+    struct SplitActor {}
+
+    impl Actor for SplitActor {
+        const VERSION: &'static str = "v1.0";
+    }
+
+    #[async_trait]
+    impl HandleSend<PingMsg> for SplitActor {
+        async fn send(&mut self, _: &Ctx, _: PingMsg) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl HandleRequest<CountMsg> for SplitActor {
+        async fn request(&mut self, _: &Ctx, _: CountMsg) -> Result<PingMsg> {
+            Ok(PingMsg::Ping)
+        }
+    }
+
+    #[async_trait]
+    impl HandleStream<CountMsg> for SplitActor {
+        async fn stream(
+            &mut self,
+            _: &Ctx,
+            msg: CountMsg,
+            responses: StreamSender<PingMsg>,
+        ) -> Result<()> {
+            for _ in 0..msg.to {
+                responses.send(PingMsg::Ping).await?;
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl HandleWithCtx<IdMsg> for SplitActor {
+        async fn send(&mut self, _: &Ctx, _: IdMsg) -> Result<()> {
+            Ok(())
+        }
+
+        async fn request(&mut self, ctx: &Ctx, _: IdMsg) -> Result<Option<IdMsg>> {
+            Ok(Some(IdMsg {
+                id: ctx.id().to_string(),
+            }))
+        }
+
+        async fn subscribe(&mut self, _: &Ctx, _: IdMsg) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_dispatch_type() {
+        let ctx = testing::ctx(DispatchType::Request, "v1.0").await;
+        let ping = PingMsg::Ping.into_bytes().unwrap();
+        let count = CountMsg { to: 2 }.into_bytes().unwrap();
+        let mut actor = SplitActor {};
+
+        let resp = actor
+            .dispatch(&ctx, "v1.0".into(), &DispatchType::Send, &ping)
+            .await
+            .unwrap();
+        assert_eq!(resp, (None, None));
+
+        // HandleRequest responds with the paired response type
+        let (version, resp) = actor
+            .dispatch(&ctx, "v2.0".into(), &DispatchType::Request, &count)
+            .await
+            .unwrap();
+        assert_eq!(version, Some("v1.0"));
+        assert_eq!(resp, Some(ping.clone()));
+
+        for (version, dispatch_type, bytes) in [
+            ("v1.0", DispatchType::Request, &ping),
+            ("v1.0", DispatchType::Subscribe, &ping),
+            ("v1.0", DispatchType::Stream, &ping),
+            ("v2.0", DispatchType::Send, &count),
+            ("v2.0", DispatchType::Subscribe, &count),
+        ] {
+            let err = actor
+                .dispatch(&ctx, version.into(), &dispatch_type, bytes)
+                .await
+                .unwrap_err();
+            let err = err.downcast_ref::<UnsupportedDispatchType>().unwrap();
+            assert_eq!(err.actor, "SplitActor/v1.0");
+            assert_eq!(err.version, version);
+            assert_eq!(err.dispatch_type.as_str(), dispatch_type.as_str());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ctx_and_stream_dispatch() {
+        let ctx = testing::ctx(DispatchType::Request, "v3.0").await;
+        let mut actor = SplitActor {};
+
+        // HandleWithCtx handlers get the msg ctx
+        let id = IdMsg { id: String::new() }.into_bytes().unwrap();
+        let (version, resp) = actor
+            .dispatch(&ctx, "v3.0".into(), &DispatchType::Request, &id)
+            .await
+            .unwrap();
+        assert_eq!(version, Some("v3.0"));
+        let resp = IdMsg::from_bytes(&resp.unwrap()).unwrap();
+        assert_eq!(resp.id, ctx.id());
+
+        // Handle and HandleWithCtx msgs can't be streamed
+        let err = actor
+            .dispatch(&ctx, "v3.0".into(), &DispatchType::Stream, &id)
+            .await
+            .unwrap_err();
+        assert!(err.is::<UnsupportedDispatchType>());
+
+        // a stream dispatch without a stream request errors
+        let count = CountMsg { to: 2 }.into_bytes().unwrap();
+        let err = actor
+            .dispatch(&ctx, "v2.0".into(), &DispatchType::Stream, &count)
+            .await
+            .unwrap_err();
+        assert!(!is_unsupported(&err));
+    }
+}
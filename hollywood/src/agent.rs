@@ -0,0 +1,1279 @@
+use crate::actor::{
+    actor_mailbox_name, Actor, ActorFactory, ActorMsg, ActorReceiver, ActorSend, ActorSender,
+    ActorSubscribe, Dispatch, DispatchType, HollywoodMsg, HollywoodPublish, HollywoodResponse,
+    HollywoodSend, Msg, RunOpts,
+};
+use crate::broker::Broker;
+use crate::client::Client;
+use crate::common;
+use crate::ctx::Ctx;
+use crate::dead_letter::DeadLetterPublisher;
+use crate::deadline;
+use crate::dedup::{CachedResponse, DedupCache, DedupOpts, Seen};
+use crate::error;
+use crate::health::{AgentHealth, ACTOR_HEALTH_INTERVAL};
+use crate::jetstream::{self, DurableOpts, Settle};
+use crate::metadata::{self, Handling, Metadata};
+use crate::metrics;
+use crate::priority::{self, Priority};
+use crate::retry::RetryPolicy;
+use crate::scheduler::Scheduler;
+use crate::stream::{StreamOpts, StreamSink};
+use crate::supervisor::{panic_message, SupervisionStrategy};
+use crate::trace;
+use anyhow::{anyhow, Result};
+use futures::FutureExt;
+use log::{debug, error, info, warn};
+use nats::asynk::Connection;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tokio::task::{self, JoinError, JoinSet};
+use tokio::time::{interval, sleep, timeout_at, Duration, Instant, MissedTickBehavior};
+use tracing::Instrument;
+
+/// How long JetStream waits before redelivering a durable
+/// msg that arrived while its first delivery is handled.
+const DUPLICATE_NAK_DELAY: Duration = Duration::from_secs(1);
+
+/// State shared between an agent and its workers.
+struct AgentState {
+    system_name: String,
+    // answered by the broker on the health subjects
+    health: Arc<AgentHealth>,
+}
+
+/// Agent is responsible for running a Broker
+/// and a pool of workers that dispatch the messages
+/// read from the Broker to actor instances.
+pub(crate) struct Agent<A: Actor + Dispatch> {
+    system_name: String,
+    actors: Vec<A>,
+    actor_factory: Option<ActorFactory<A>>,
+    supervision: SupervisionStrategy,
+    retry_policy: RetryPolicy,
+    durable_opts: DurableOpts,
+    dedup_opts: Option<DedupOpts>,
+    shutdown_timeout: Duration,
+    metrics_endpoint: Option<SocketAddr>,
+    sender: ActorSender,
+    receiver: ActorReceiver,
+    nats: Connection,
+}
+
+impl<A: Actor + Dispatch + 'static> Agent<A> {
+    pub(crate) fn new(opts: RunOpts<A>, nats: Connection) -> Result<Self> {
+        // create an actor instance for each worker
+        let mut actors = vec![opts.actor];
+        if opts.concurrency > 1 {
+            let factory = opts.actor_factory.as_ref().ok_or_else(|| {
+                anyhow!(
+                    "{} concurrency of {} requires an actor factory",
+                    A::type_name(),
+                    opts.concurrency
+                )
+            })?;
+            for _ in 1..opts.concurrency {
+                actors.push(factory());
+            }
+        }
+
+        let (tx, rx) = priority::mailbox(opts.actor_mailbox_max_size, opts.mailbox_overflow);
+        Ok(Agent {
+            system_name: opts.system_name,
+            actors,
+            actor_factory: opts.actor_factory,
+            supervision: opts.supervision,
+            retry_policy: opts.retry_policy,
+            durable_opts: opts.durable_opts,
+            dedup_opts: opts.dedup_opts,
+            shutdown_timeout: opts.shutdown_timeout,
+            metrics_endpoint: opts.metrics_endpoint,
+            sender: tx,
+            receiver: rx,
+            nats,
+        })
+    }
+
+    fn sender(&self) -> ActorSender {
+        self.sender.clone()
+    }
+
+    /// Run implements a basic runtime for running an
+    /// agent (broker and actor mailbox consumers) for given
+    /// system and actor instances.
+    pub(crate) async fn run(&mut self) -> Result<()> {
+        self.run_until(common::shutdown_signal()).await
+    }
+
+    /// Runs the agent until `shutdown` completes (or every
+    /// worker stops) and then drains the mailbox.
+    async fn run_until(&mut self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        // timers stop once the agent starts shutting down
+        let (timers_stop_tx, timers_stop_rx) = watch::channel(false);
+        let scheduler = Scheduler::new(
+            format!("{}/{}", A::type_name(), A::version()),
+            self.system_name.clone(),
+            A::dispatch_types(),
+            self.sender(),
+            timers_stop_rx,
+        );
+
+        // reload the msg ids handled before a restart
+        let dedup = match self.dedup_opts.clone() {
+            Some(opts) => Some(Arc::new(
+                task::spawn_blocking(move || DedupCache::open(&opts)).await??,
+            )),
+            None => None,
+        };
+
+        let metrics_server = match self.metrics_endpoint {
+            Some(addr) => Some(metrics::serve(addr).await?),
+            None => None,
+        };
+
+        // start the actors before reading any messages
+        for actor in self.actors.iter_mut() {
+            actor.set_scheduler(scheduler.clone());
+            if let Err(err) = actor.on_start().await {
+                error!("{} actor on_start: {:?}", A::type_name(), &err);
+                return Err(err);
+            }
+        }
+
+        // run broker here...
+        let system_name = &self.system_name;
+        let actor = &self.actors[0];
+        let actor_type_name_version = &actor.type_name_version();
+
+        // prepare the broker for each mailbox
+        let mailbox_sender = self.sender();
+        let nats = self.nats.clone();
+        let subscriptions = actor.instance_subscriptions();
+        let mailbox_names = actor
+            .instance_dispatch_types()
+            .into_iter()
+            .map(|msg_version| {
+                actor_mailbox_name(system_name, actor_type_name_version, &msg_version)
+            })
+            .collect::<Vec<_>>();
+        let dead_letters =
+            DeadLetterPublisher::new(system_name, actor_type_name_version, self.nats.clone());
+
+        // run broker...
+        let health = Arc::new(AgentHealth::new(actor_type_name_version.clone()));
+        let mut broker = Broker::new(
+            system_name,
+            actor_type_name_version.to_owned(),
+            mailbox_names,
+            actor.instance_dispatch_types(),
+            mailbox_sender,
+            nats,
+            subscriptions,
+            self.durable_opts.clone(),
+            health.clone(),
+        );
+
+        broker.run().await?;
+
+        // run a mailbox worker for each actor instance
+        let state = Arc::new(AgentState {
+            system_name: system_name.clone(),
+            health,
+        });
+        let (stop_tx, stop_rx) = watch::channel::<Option<Instant>>(None);
+        let retries = Arc::new(Mutex::new(JoinSet::new()));
+        let mut workers = JoinSet::new();
+        for (id, actor) in self.actors.drain(..).enumerate() {
+            let worker = Worker {
+                id,
+                actor_name: actor_type_name_version.clone(),
+                dispatch_types: A::dispatch_types(),
+                actor,
+                receiver: self.receiver.clone(),
+                nats: self.nats.clone(),
+                state: state.clone(),
+                actor_factory: self.actor_factory.clone(),
+                supervision: self.supervision.clone(),
+                scheduler: scheduler.clone(),
+                dead_letters: dead_letters.clone(),
+                retry_policy: self.retry_policy.clone(),
+                max_deliver: self.durable_opts.max_deliver(),
+                progress_interval: self.durable_opts.progress_interval(),
+                dedup: dedup.clone(),
+                sender: self.sender.clone(),
+                retries: retries.clone(),
+                stop_rx: stop_rx.clone(),
+                consecutive_panics: 0,
+            };
+            workers.spawn(worker.run(self.shutdown_timeout));
+        }
+        info!("{} agent running {} workers", A::type_name(), workers.len());
+
+        // run until we receive an os signal, every
+        // worker has stopped or a worker escalates an error...
+        let mut escalation = None;
+        tokio::select! {
+            _ = shutdown => {
+                info!("{} agent received shutdown signal", A::type_name());
+            }
+            _ = async {
+                while let Some(result) = workers.join_next().await {
+                    escalation = worker_error::<A>(result);
+                    if escalation.is_some() {
+                        break;
+                    }
+                }
+            } => {
+                info!("{} agent workers stopped", A::type_name());
+                // nothing drains the mailbox anymore so refuse the
+                // msgs the broker still forwards instead of blocking
+                self.sender.close_capacity();
+            }
+        }
+
+        // Stop reading from nats and then let the
+        // workers drain the mailbox until the deadline
+        info!("{} agent shutting down", A::type_name());
+        broker.shutdown().await;
+        let _ = timers_stop_tx.send(true);
+        let _ = stop_tx.send(Some(Instant::now() + self.shutdown_timeout));
+        while let Some(result) = workers.join_next().await {
+            if let Some(err) = worker_error::<A>(result) {
+                escalation.get_or_insert(err);
+            }
+        }
+
+        // pending retries dead-letter their msgs once
+        // the agent stops, wait for them to be published
+        let mut retries = std::mem::take(&mut *retries.lock().unwrap());
+        while let Some(result) = retries.join_next().await {
+            if let Err(err) = result {
+                error!("{} agent retry task failed: {:?}", A::type_name(), &err);
+            }
+        }
+
+        // wait for the dedup writer to persist the handled ids
+        if let Some(dedup) = dedup {
+            let _ = task::spawn_blocking(move || drop(dedup)).await;
+        }
+
+        // make sure request replies make it to nats
+        if let Err(err) = self.nats.flush().await {
+            error!("{} agent flushing nats: {:?}", A::type_name(), &err);
+        }
+        if let Some(metrics_server) = metrics_server {
+            metrics_server.abort();
+        }
+        info!("{} agent stopped", A::type_name());
+        match escalation {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Returns the error from a stopped worker task
+fn worker_error<A: Actor>(result: Result<Result<()>, JoinError>) -> Option<anyhow::Error> {
+    match result {
+        Ok(Ok(_)) => None,
+        Ok(Err(err)) => {
+            error!("{} agent worker escalated: {:?}", A::type_name(), &err);
+            Some(err)
+        }
+        Err(err) => {
+            error!("{} agent worker failed: {:?}", A::type_name(), &err);
+            Some(err.into())
+        }
+    }
+}
+
+/// Worker reads messages from the agent mailbox
+/// and dispatches them to its own actor instance.
+struct Worker<A: Actor + Dispatch> {
+    id: usize,
+    // actor type name and version used as the metrics label
+    actor_name: String,
+    // msg types and versions the actor handles
+    // (used to label metrics with the msg type)
+    dispatch_types: Vec<String>,
+    actor: A,
+    receiver: ActorReceiver,
+    nats: Connection,
+    state: Arc<AgentState>,
+    actor_factory: Option<ActorFactory<A>>,
+    supervision: SupervisionStrategy,
+    scheduler: Scheduler,
+    dead_letters: DeadLetterPublisher,
+    retry_policy: RetryPolicy,
+    // deliveries of a durable msg before it's dead-lettered
+    max_deliver: u32,
+    // how often to send in-progress acks for durable msgs
+    progress_interval: Duration,
+    // ids of handled msgs shared by the agent workers
+    dedup: Option<Arc<DedupCache>>,
+    // used to re-enqueue msgs for retries
+    sender: ActorSender,
+    // pending retries, awaited before the agent stops
+    retries: Arc<Mutex<JoinSet<()>>>,
+    // set to the drain deadline once the agent starts shutting down
+    stop_rx: watch::Receiver<Option<Instant>>,
+    consecutive_panics: u32,
+}
+
+impl<A: Actor + Dispatch> Worker<A> {
+    /// Returns an error if an actor panic is escalated.
+    async fn run(mut self, shutdown_timeout: Duration) -> Result<()> {
+        let mut stop_rx = self.stop_rx.clone();
+        let mut health_check = interval(ACTOR_HEALTH_INTERVAL);
+        health_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = stop_rx.changed() => break,
+                _ = health_check.tick() => self.check_health().await,
+                result = self.receiver.recv() => match result {
+                    Ok(mailbox_msg) => self.handle_mailbox_msg(mailbox_msg).await?,
+                    Err(err) => {
+                        error!("{} worker {} mailbox closed: {:?}", A::type_name(), self.id, &err);
+                        break;
+                    }
+                },
+            }
+        }
+
+        let deadline = stop_rx
+            .borrow()
+            .unwrap_or_else(|| Instant::now() + shutdown_timeout);
+        self.shutdown(deadline).await
+    }
+
+    /// Shutdown drains the remaining mailbox messages
+    /// (bounded by the deadline) and then calls `Actor::on_stop`.
+    async fn shutdown(&mut self, deadline: Instant) -> Result<()> {
+        let drained = timeout_at(deadline, async {
+            while let Some(mailbox_msg) = self.receiver.try_recv() {
+                self.handle_mailbox_msg(mailbox_msg).await?;
+            }
+            Ok::<(), anyhow::Error>(())
+        })
+        .await;
+        if let Ok(Err(err)) = drained {
+            return Err(err);
+        } else if drained.is_err() {
+            warn!(
+                "{} worker {} shutdown timeout, dropping {} unprocessed msgs",
+                A::type_name(),
+                self.id,
+                self.receiver.len()
+            );
+        }
+
+        if let Err(err) = self.actor.on_stop().await {
+            error!("{} actor on_stop: {:?}", A::type_name(), &err);
+        }
+        Ok(())
+    }
+
+    /// Rebuild the actor after a handler panic according
+    /// to the supervision strategy. Returns an error if
+    /// the panic should be escalated.
+    async fn restart(&mut self, reason: &str) -> Result<()> {
+        self.consecutive_panics += 1;
+        let delay = match self.supervision.restart_delay(self.consecutive_panics) {
+            Some(delay) => delay,
+            None => return Err(anyhow!("{} actor panicked: {}", A::type_name(), reason)),
+        };
+        let factory = match &self.actor_factory {
+            Some(factory) => factory.clone(),
+            None => {
+                return Err(anyhow!(
+                    "{} actor panicked and can't restart without an actor factory: {}",
+                    A::type_name(),
+                    reason
+                ))
+            }
+        };
+
+        if !delay.is_zero() {
+            sleep(delay).await;
+        }
+        let mut actor = factory();
+        actor.set_scheduler(self.scheduler.clone());
+        actor.on_start().await?;
+        self.actor = actor;
+        let restarts = self.state.health.restarted();
+        warn!(
+            "{} worker {} restarted actor after panic (restarts: {}): {}",
+            A::type_name(),
+            self.id,
+            restarts,
+            reason
+        );
+        Ok(())
+    }
+
+    /// Publish the response to an actor request
+    async fn reply(&mut self, reply_id: String, msg: HollywoodMsg) {
+        match serde_json::to_vec(&msg) {
+            Ok(msg) => {
+                if let Err(err) = self.nats.publish(&reply_id, msg).await {
+                    error!("sending response to nats: {:?}", &err);
+                    metrics::count_publish_error(&self.actor_name);
+                }
+            }
+            Err(err) => {
+                error!("serializing request response: {:?}", &err);
+            }
+        }
+    }
+
+    /// Returns an error if an actor panic is escalated.
+    async fn handle_mailbox_msg(&mut self, mailbox_msg: ActorMsg) -> Result<()> {
+        metrics::set_mailbox_depth(&self.actor_name, self.receiver.len());
+        match mailbox_msg {
+            ActorMsg::Request(req) => {
+                let dispatch_type = match req.stream {
+                    Some(_) => DispatchType::Stream,
+                    None => DispatchType::Request,
+                };
+                let delivery = Delivery {
+                    id: req.id,
+                    msg_version: req.msg_version,
+                    msg: req.msg,
+                    subject: req.subject,
+                    attempt: 1,
+                    reply_id: Some(req.reply_id),
+                    deadline: req.deadline,
+                    priority: req.priority,
+                    traceparent: req.traceparent,
+                    meta: req.meta,
+                    stream: req.stream,
+                    ack: None,
+                };
+                self.handle_msg(&dispatch_type, delivery).await?;
+            }
+            ActorMsg::Send(send) => {
+                let delivery = Delivery {
+                    id: send.id,
+                    msg_version: send.msg_version,
+                    msg: send.msg,
+                    subject: send.subject,
+                    attempt: send.attempt,
+                    reply_id: None,
+                    deadline: None,
+                    priority: send.priority,
+                    traceparent: send.traceparent,
+                    meta: send.meta,
+                    stream: None,
+                    ack: send.ack,
+                };
+                self.handle_msg(&DispatchType::Send, delivery).await?;
+            }
+            ActorMsg::Subscribe(sub) => {
+                let delivery = Delivery {
+                    id: sub.id,
+                    msg_version: sub.msg_version,
+                    msg: sub.msg,
+                    subject: sub.subject,
+                    attempt: sub.attempt,
+                    reply_id: None,
+                    deadline: None,
+                    priority: sub.priority,
+                    traceparent: sub.traceparent,
+                    meta: sub.meta,
+                    stream: None,
+                    ack: None,
+                };
+                self.handle_msg(&DispatchType::Subscribe, delivery).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the result of `Actor::health` for health checks.
+    async fn check_health(&mut self) {
+        let actor_error = match self.actor.health().await {
+            Ok(_) => None,
+            Err(err) => Some(err.to_string()),
+        };
+        self.state.health.set_actor_error(self.id, actor_error);
+    }
+
+    /// Wrapper for handling send/request/subscribe type messages
+    async fn handle_msg(&mut self, dispatch_type: &DispatchType, delivery: Delivery) -> Result<()> {
+        // don't handle requests the caller stopped waiting on
+        if deadline::is_expired(delivery.deadline) {
+            warn!(
+                "{} agent dropping expired request msg id {}",
+                A::type_name(),
+                &delivery.id
+            );
+            metrics::count_dropped(
+                &self.actor_name,
+                Some(dispatch_type),
+                metrics::msg_labels(&self.dispatch_types, &delivery.msg_version),
+                "expired",
+            );
+            return Ok(());
+        }
+        // stream responses can't be cached
+        // so stream requests aren't deduplicated
+        let dedup = match dispatch_type {
+            DispatchType::Stream => None,
+            _ => self.dedup.clone(),
+        };
+        if let Some(dedup) = &dedup {
+            match dedup.begin(&delivery.id) {
+                Seen::New => {}
+                seen => {
+                    self.handle_duplicate(dispatch_type, delivery, seen).await;
+                    return Ok(());
+                }
+            }
+        }
+        let (span, trace) = trace::handler_span(
+            &self.actor_name,
+            dispatch_type.as_str(),
+            &delivery.id,
+            &delivery.msg_version,
+            delivery.traceparent.as_deref(),
+        );
+        let handling = Handling {
+            msg_id: delivery.id.clone(),
+            meta: delivery.meta.clone(),
+            actor: self.actor_name.clone(),
+            instance: self.state.health.instance_id().to_string(),
+        };
+        let stream = match (&delivery.stream, &delivery.reply_id) {
+            (Some(opts), Some(reply_id)) => {
+                let sink = StreamSink::open(
+                    self.nats.clone(),
+                    delivery.id.clone(),
+                    reply_id.clone(),
+                    opts,
+                )
+                .await;
+                match sink {
+                    Ok(sink) => Some(Arc::new(sink)),
+                    Err(err) => {
+                        error!(
+                            "{} agent opening stream for msg id {}: {:?}",
+                            A::type_name(),
+                            &delivery.id,
+                            &err
+                        );
+                        let resp = HollywoodResponse {
+                            id: delivery.id,
+                            msg_version: "".to_string(),
+                            msg: None,
+                            error: Some(format!("opening response stream: {}", &err)),
+                            meta: metadata::response(&handling),
+                        };
+                        self.reply(reply_id.clone(), HollywoodMsg::Response(resp))
+                            .await;
+                        return Ok(());
+                    }
+                }
+            }
+            _ => None,
+        };
+        let mut ctx = Ctx::new(
+            delivery.id.clone(),
+            delivery.subject.clone(),
+            delivery.reply_id.clone(),
+            dispatch_type.clone(),
+            delivery.msg_version.clone(),
+            delivery.attempt,
+            delivery.meta.clone(),
+            delivery.deadline,
+            self.state.system_name.clone(),
+            self.scheduler.clone(),
+            Client::from_connection(self.nats.clone()),
+        );
+        if let Some(stream) = &stream {
+            ctx = ctx.with_stream(stream.clone());
+        }
+        let started = Instant::now();
+        let dispatch = Dispatch::dispatch(
+            &mut self.actor,
+            &ctx,
+            delivery.msg_version.clone(),
+            dispatch_type,
+            &delivery.msg,
+        );
+        let dispatch = deadline::scope(
+            delivery.deadline,
+            trace::scope(trace, metadata::scope(handling.clone(), dispatch)),
+        )
+        .instrument(span);
+        let result = jetstream::in_progress(
+            &self.nats,
+            delivery.ack.as_deref(),
+            self.progress_interval,
+            AssertUnwindSafe(dispatch).catch_unwind(),
+        )
+        .await;
+        let msg_labels = metrics::msg_labels(&self.dispatch_types, &delivery.msg_version);
+        metrics::record_handler_duration(
+            &self.actor_name,
+            dispatch_type,
+            msg_labels,
+            started.elapsed(),
+        );
+        let outcome = match &result {
+            Ok(Ok(_)) => metrics::MSGS_HANDLED,
+            _ => metrics::MSGS_FAILED,
+        };
+        metrics::count_msg(outcome, &self.actor_name, dispatch_type, msg_labels);
+        let result = match result {
+            Ok(result) => {
+                self.consecutive_panics = 0;
+                result
+            }
+            Err(panic) => {
+                if let Some(dedup) = &dedup {
+                    dedup.abort(&delivery.id);
+                }
+                let reason = panic_message(&*panic);
+                error!(
+                    "{} agent {:?} msg id {} panicked: {}",
+                    A::type_name(),
+                    &dispatch_type,
+                    &delivery.id,
+                    &reason
+                );
+                let error = format!("actor panicked: {}", &reason);
+                match (&stream, delivery.reply_id.clone()) {
+                    (Some(stream), _) => stream.end(Some(error)).await,
+                    (None, Some(reply_id)) => {
+                        let resp = HollywoodResponse {
+                            id: delivery.id,
+                            msg_version: "".to_string(),
+                            msg: None,
+                            error: Some(error),
+                            meta: metadata::response(&handling),
+                        };
+                        self.reply(reply_id, HollywoodMsg::Response(resp)).await;
+                    }
+                    (None, None) => {
+                        delivery
+                            .dead_letter(dispatch_type, &self.dead_letters, error)
+                            .await;
+                        self.settle(&delivery, Settle::Term).await;
+                    }
+                }
+                return self.restart(&reason).await;
+            }
+        };
+        self.state.health.handled();
+        // remember handled msgs and forget failed ones
+        // so they're handled again when retried
+        if let Some(dedup) = &dedup {
+            match &result {
+                Ok((msg_version, msg)) => {
+                    let response = match dispatch_type {
+                        DispatchType::Request => Some(CachedResponse {
+                            msg_version: msg_version.unwrap_or("unknown_version").to_string(),
+                            msg: msg.clone(),
+                            meta: metadata::response(&handling),
+                        }),
+                        _ => None,
+                    };
+                    dedup.finish(&delivery.id, response);
+                }
+                Err(_) => dedup.abort(&delivery.id),
+            }
+        }
+        // stream requests end with an end (or error)
+        // frame instead of a response
+        if let Some(stream) = stream {
+            stream.end(result.err().map(|err| err.to_string())).await;
+            return Ok(());
+        }
+        let hollywood_msg = match result {
+            Ok((msg_version, msg)) => match dispatch_type {
+                DispatchType::Request => {
+                    let resp = HollywoodResponse {
+                        id: delivery.id,
+                        msg_version: msg_version.unwrap_or("unknown_version").to_string(),
+                        msg,
+                        error: None,
+                        meta: metadata::response(&handling),
+                    };
+                    HollywoodMsg::Response(resp)
+                }
+                _ => {
+                    self.settle(&delivery, Settle::Ack).await;
+                    return Ok(());
+                }
+            },
+            Err(err) => match dispatch_type {
+                DispatchType::Request => {
+                    let resp = HollywoodResponse {
+                        id: delivery.id,
+                        msg_version: "".to_string(),
+                        msg: None,
+                        error: Some(err.to_string()),
+                        meta: metadata::response(&handling),
+                    };
+                    HollywoodMsg::Response(resp)
+                }
+                _ => {
+                    error!(
+                        "{} agent {:?} msg id {} attempt {} err: {:?}",
+                        A::type_name(),
+                        &dispatch_type,
+                        &delivery.id,
+                        delivery.attempt,
+                        &err
+                    );
+                    self.actor.on_error(&delivery.id, dispatch_type, &err).await;
+                    // retrying msgs the actor doesn't handle would fail the same way
+                    let delay = match (error::is_unsupported(&err), &delivery.ack) {
+                        (true, _) => None,
+                        // JetStream redelivers durable msgs
+                        (false, Some(_)) if delivery.attempt >= self.max_deliver => None,
+                        (false, Some(_)) => {
+                            self.retry_policy.redelivery_delay(delivery.attempt, &err)
+                        }
+                        (false, None) => self.retry_policy.retry_delay(delivery.attempt, &err),
+                    };
+                    match (delay, &delivery.ack) {
+                        (Some(delay), Some(_)) => {
+                            self.settle(&delivery, Settle::Nak(delay)).await;
+                        }
+                        (Some(delay), None) => self.retry(dispatch_type, delivery, delay),
+                        (None, _) => {
+                            delivery
+                                .dead_letter(dispatch_type, &self.dead_letters, err.to_string())
+                                .await;
+                            self.settle(&delivery, Settle::Term).await;
+                        }
+                    }
+                    return Ok(());
+                }
+            },
+        };
+
+        if let Some(reply_id) = delivery.reply_id {
+            self.reply(reply_id, hollywood_msg).await;
+        }
+        Ok(())
+    }
+
+    /// Answer a msg with an id the agent already handled (or
+    /// is still handling) without dispatching it again.
+    async fn handle_duplicate(
+        &mut self,
+        dispatch_type: &DispatchType,
+        delivery: Delivery,
+        seen: Seen,
+    ) {
+        debug!(
+            "{} agent skipping duplicate msg id {}",
+            A::type_name(),
+            &delivery.id
+        );
+        metrics::count_dropped(
+            &self.actor_name,
+            Some(dispatch_type),
+            metrics::msg_labels(&self.dispatch_types, &delivery.msg_version),
+            "duplicate",
+        );
+        let reply_id = match delivery.reply_id.clone() {
+            Some(reply_id) => reply_id,
+            None => {
+                // JetStream redelivers the msg until the
+                // first delivery is acked or naked
+                let settle = match seen {
+                    Seen::InFlight => Settle::Nak(DUPLICATE_NAK_DELAY),
+                    _ => Settle::Ack,
+                };
+                self.settle(&delivery, settle).await;
+                return;
+            }
+        };
+        let resp = match seen {
+            Seen::Handled(Some(cached)) => HollywoodResponse {
+                id: delivery.id,
+                msg_version: cached.msg_version,
+                msg: cached.msg,
+                error: None,
+                meta: cached.meta,
+            },
+            seen => {
+                let error = match seen {
+                    Seen::InFlight => format!("msg id {} is already being handled", &delivery.id),
+                    _ => format!("msg id {} was already handled", &delivery.id),
+                };
+                HollywoodResponse {
+                    id: delivery.id,
+                    msg_version: "".to_string(),
+                    msg: None,
+                    error: Some(error),
+                    meta: Metadata::default(),
+                }
+            }
+        };
+        self.reply(reply_id, HollywoodMsg::Response(resp)).await;
+    }
+
+    /// Ack, nak or term a msg from a durable mailbox
+    /// (no-op for other msgs).
+    async fn settle(&mut self, delivery: &Delivery, settle: Settle) {
+        if let Some(ack) = &delivery.ack {
+            if let Err(err) = jetstream::settle(&self.nats, ack, settle).await {
+                error!(
+                    "{} agent settling msg id {}: {:?}",
+                    A::type_name(),
+                    &delivery.id,
+                    &err
+                );
+                metrics::count_publish_error(&self.actor_name);
+            }
+        }
+    }
+
+    /// Re-enqueue a failed send/subscribe msg into the agent
+    /// mailbox after `delay`. The msg is dead-lettered instead
+    /// if the agent starts shutting down first.
+    fn retry(&self, dispatch_type: &DispatchType, delivery: Delivery, delay: Duration) {
+        let dispatch_type = dispatch_type.clone();
+        let sender = self.sender.clone();
+        let dead_letters = self.dead_letters.clone();
+        let mut stop_rx = self.stop_rx.clone();
+        debug!(
+            "{} agent retrying msg id {} in {:?}",
+            A::type_name(),
+            &delivery.id,
+            &delay
+        );
+        let mut retries = self.retries.lock().unwrap();
+        // forget the retries that already finished
+        while retries.try_join_next().is_some() {}
+        retries.spawn(async move {
+            let reason = "agent stopped before retrying msg";
+            if stop_rx.borrow().is_some() {
+                delivery
+                    .dead_letter(&dispatch_type, &dead_letters, reason.to_string())
+                    .await;
+                return;
+            }
+            tokio::select! {
+                _ = sleep(delay) => {
+                    let mailbox_msg = delivery.retry_msg(&dispatch_type);
+                    if let Err(err) = sender.send(mailbox_msg).await {
+                        warn!("failed to re-enqueue msg for retry: {:?}", &err);
+                    }
+                }
+                _ = stop_rx.changed() => {
+                    delivery
+                        .dead_letter(&dispatch_type, &dead_letters, reason.to_string())
+                        .await;
+                }
+            }
+        });
+    }
+}
+
+/// A request, send or subscribe msg read from the agent mailbox.
+struct Delivery {
+    id: String,
+    msg_version: String,
+    msg: Vec<u8>,
+    // subject the msg was received on
+    subject: String,
+    // starts at 1 and increments on every retry
+    attempt: u32,
+    reply_id: Option<String>,
+    // request deadline (epoch millis)
+    deadline: Option<i64>,
+    priority: Priority,
+    traceparent: Option<String>,
+    meta: Metadata,
+    // set for requests with streamed responses
+    stream: Option<StreamOpts>,
+    // JetStream ack subject of msgs from a durable mailbox
+    ack: Option<String>,
+}
+
+impl Delivery {
+    /// Returns the mailbox msg for the next attempt.
+    fn retry_msg(self, dispatch_type: &DispatchType) -> ActorMsg {
+        match dispatch_type {
+            DispatchType::Subscribe => ActorMsg::Subscribe(ActorSubscribe {
+                id: self.id,
+                subject: self.subject,
+                msg_version: self.msg_version,
+                msg: self.msg,
+                attempt: self.attempt + 1,
+                priority: self.priority,
+                traceparent: self.traceparent,
+                meta: self.meta,
+            }),
+            _ => ActorMsg::Send(ActorSend {
+                id: self.id,
+                subject: self.subject,
+                msg_version: self.msg_version,
+                msg: self.msg,
+                attempt: self.attempt + 1,
+                priority: self.priority,
+                traceparent: self.traceparent,
+                meta: self.meta,
+                ack: self.ack,
+            }),
+        }
+    }
+
+    /// Publish a failed send/subscribe msg to the dead-letter subject
+    /// with the envelope it was originally received in.
+    async fn dead_letter(
+        &self,
+        dispatch_type: &DispatchType,
+        dead_letters: &DeadLetterPublisher,
+        reason: String,
+    ) {
+        let hollywood_msg = match dispatch_type {
+            DispatchType::Subscribe => HollywoodMsg::Publish(HollywoodPublish {
+                id: self.id.clone(),
+                msg_version: self.msg_version.clone(),
+                msg: self.msg.clone(),
+                priority: self.priority,
+                traceparent: self.traceparent.clone(),
+                meta: self.meta.clone(),
+            }),
+            _ => HollywoodMsg::Send(HollywoodSend {
+                id: self.id.clone(),
+                msg_version: self.msg_version.clone(),
+                msg: self.msg.clone(),
+                priority: self.priority,
+                traceparent: self.traceparent.clone(),
+                meta: self.meta.clone(),
+            }),
+        };
+        match hollywood_msg.into_bytes() {
+            Ok(envelope) => {
+                dead_letters
+                    .publish(
+                        &self.subject,
+                        Some(self.id.clone()),
+                        envelope,
+                        reason,
+                        self.attempt,
+                    )
+                    .await;
+            }
+            Err(err) => {
+                error!(
+                    "failed to serialize dead letter for msg id {}: {:?}",
+                    &self.id, &err
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actor::DispatchResponse;
+    use crate::scheduler::TimerHandle;
+    use crate::testing::{nats_stub, NatsStub};
+    use async_trait::async_trait;
+    use serde::{Deserialize, Serialize};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    #[derive(Serialize, Deserialize)]
+    struct TestMsg {}
+
+    impl Msg for TestMsg {
+        type Type = Self;
+        const VERSION: &'static str = "v1.0";
+    }
+
+    #[derive(Default, Clone)]
+    struct TestActor {
+        handled: Arc<AtomicUsize>,
+        stopped: Arc<AtomicBool>,
+        scheduler: Option<Scheduler>,
+        // starts a timer with this period in `on_start`
+        timer_period: Option<Duration>,
+        timers: Arc<Mutex<Vec<TimerHandle>>>,
+        // how long each msg takes to handle
+        handle_delay: Duration,
+        in_flight: Arc<AtomicUsize>,
+        max_in_flight: Arc<AtomicUsize>,
+        // returned from `on_start` if set
+        start_error: Option<String>,
+        // lifecycle hooks and handled msgs in call order
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl TestActor {
+        fn record(&self, event: String) {
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn events(&self) -> Vec<String> {
+            self.events.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl Actor for TestActor {
+        const VERSION: &'static str = "v1.0";
+
+        fn set_scheduler(&mut self, scheduler: Scheduler) {
+            self.scheduler = Some(scheduler);
+        }
+
+        async fn on_start(&mut self) -> Result<()> {
+            self.record("on_start".to_string());
+            if let Some(err) = &self.start_error {
+                return Err(anyhow!(err.clone()));
+            }
+            if let (Some(scheduler), Some(period)) = (&self.scheduler, self.timer_period) {
+                let timer = scheduler.send_interval(TestMsg {}, period)?;
+                self.timers.lock().unwrap().push(timer);
+            }
+            Ok(())
+        }
+
+        async fn on_stop(&mut self) -> Result<()> {
+            self.record("on_stop".to_string());
+            self.stopped.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn on_error(&mut self, id: &str, dispatch_type: &DispatchType, err: &anyhow::Error) {
+            self.record(format!(
+                "on_error {} {} {}",
+                dispatch_type.as_str(),
+                id,
+                err
+            ));
+        }
+    }
+
+    #[async_trait]
+    impl Dispatch for TestActor {
+        fn instance_dispatch_types(&self) -> Vec<String> {
+            Self::dispatch_types()
+        }
+
+        fn dispatch_types() -> Vec<String> {
+            vec!["TestMsg/v1.0".to_string()]
+        }
+
+        async fn dispatch(
+            &mut self,
+            ctx: &Ctx,
+            _version: String,
+            _dispatch_type: &DispatchType,
+            bytes: &Vec<u8>,
+        ) -> Result<DispatchResponse> {
+            if bytes == b"panic" {
+                panic!("test actor panic");
+            }
+            if bytes == b"fail" {
+                return Err(anyhow!("test actor error"));
+            }
+            self.record(format!("handle {}", ctx.id()));
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            sleep(self.handle_delay).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            self.handled.fetch_add(1, Ordering::SeqCst);
+            Ok((None, None))
+        }
+    }
+
+    fn send_msg(id: usize, msg: &[u8]) -> ActorMsg {
+        ActorMsg::Send(ActorSend {
+            id: id.to_string(),
+            subject: "test".to_string(),
+            msg_version: "v1.0".to_string(),
+            msg: msg.to_vec(),
+            attempt: 1,
+            priority: Priority::default(),
+            traceparent: None,
+            meta: Metadata::default(),
+            ack: None,
+        })
+    }
+
+    fn subscribe_msg(id: usize, msg: &[u8]) -> ActorMsg {
+        ActorMsg::Subscribe(ActorSubscribe {
+            id: id.to_string(),
+            subject: "test.events".to_string(),
+            msg_version: "v1.0".to_string(),
+            msg: msg.to_vec(),
+            attempt: 1,
+            priority: Priority::default(),
+            traceparent: None,
+            meta: Metadata::default(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_run_drains_mailbox_on_shutdown() {
+        let nats_uri = nats_stub();
+        let nats = nats::asynk::connect(&nats_uri).await.unwrap();
+        let actor = TestActor::default();
+        let (handled, stopped) = (actor.handled.clone(), actor.stopped.clone());
+        let mut agent = Agent::new(RunOpts::new("test".into(), actor, nats_uri), nats).unwrap();
+        for id in 0..10 {
+            agent.sender().send(send_msg(id, b"hello")).await.unwrap();
+        }
+
+        // shut down right away, the queued msgs are still handled
+        agent.run_until(async {}).await.unwrap();
+        assert_eq!(handled.load(Ordering::SeqCst), 10);
+        assert!(stopped.load(Ordering::SeqCst));
+        assert_eq!(agent.receiver.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency() {
+        let nats_uri = nats_stub();
+        let nats = nats::asynk::connect(&nats_uri).await.unwrap();
+        let actor = TestActor {
+            handle_delay: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let (handled, max_in_flight) = (actor.handled.clone(), actor.max_in_flight.clone());
+        let factory = {
+            let actor = actor.clone();
+            move || actor.clone()
+        };
+        let opts = RunOpts::new("test".into(), actor, nats_uri)
+            .with_concurrency(4)
+            .with_actor_factory(factory);
+        let mut agent = Agent::new(opts, nats).unwrap();
+        for id in 0..8 {
+            agent.sender().send(send_msg(id, b"hello")).await.unwrap();
+        }
+
+        // each worker handles a msg at the same time
+        let all_handled = async {
+            while handled.load(Ordering::SeqCst) < 8 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        agent.run_until(all_handled).await.unwrap();
+        assert_eq!(handled.load(Ordering::SeqCst), 8);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_requires_factory() {
+        let nats_uri = nats_stub();
+        let nats = nats::asynk::connect(&nats_uri).await.unwrap();
+        let opts = RunOpts::new("test".into(), TestActor::default(), nats_uri).with_concurrency(2);
+        let err = Agent::new(opts, nats).err().unwrap();
+        assert!(err.to_string().contains("requires an actor factory"));
+    }
+
+    #[tokio::test]
+    async fn test_panic_restarts_actor() {
+        let nats_uri = nats_stub();
+        let nats = nats::asynk::connect(&nats_uri).await.unwrap();
+        let actor = TestActor::default();
+        let handled = actor.handled.clone();
+        let restarts = Arc::new(AtomicUsize::new(0));
+        let factory = {
+            let (actor, restarts) = (actor.clone(), restarts.clone());
+            move || {
+                restarts.fetch_add(1, Ordering::SeqCst);
+                actor.clone()
+            }
+        };
+        let opts = RunOpts::new("test".into(), actor, nats_uri)
+            .with_supervision(SupervisionStrategy::Restart)
+            .with_actor_factory(factory);
+        let mut agent = Agent::new(opts, nats).unwrap();
+        agent.sender().send(send_msg(0, b"panic")).await.unwrap();
+        agent.sender().send(send_msg(1, b"hello")).await.unwrap();
+
+        // the panic isn't escalated and the rebuilt
+        // actor handles the next msg
+        agent.run_until(async {}).await.unwrap();
+        assert_eq!(restarts.load(Ordering::SeqCst), 1);
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_timers() {
+        let nats_uri = nats_stub();
+        let nats = nats::asynk::connect(&nats_uri).await.unwrap();
+        let actor = TestActor {
+            timer_period: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let timers = actor.timers.clone();
+        let mut agent = Agent::new(RunOpts::new("test".into(), actor, nats_uri), nats).unwrap();
+
+        agent.run_until(async {}).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+        let timers = timers.lock().unwrap();
+        assert_eq!(timers.len(), 1);
+        assert!(timers[0].is_finished());
+    }
+
+    #[tokio::test]
+    async fn test_on_start_error() {
+        let stub = NatsStub::start();
+        let nats = nats::asynk::connect(&stub.uri).await.unwrap();
+        let actor = TestActor {
+            start_error: Some("database is down".to_string()),
+            ..Default::default()
+        };
+        let events = actor.events.clone();
+        let mut agent = Agent::new(
+            RunOpts::new("test".into(), actor, stub.uri.clone()),
+            nats.clone(),
+        )
+        .unwrap();
+
+        // the agent stops before subscribing to its mailboxes
+        let err = agent.run_until(async {}).await.unwrap_err();
+        assert_eq!(err.to_string(), "database is down");
+        sleep(Duration::from_millis(50)).await;
+        nats.flush().await.unwrap();
+        assert!(stub.subjects().is_empty());
+        assert_eq!(*events.lock().unwrap(), vec!["on_start"]);
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_hooks() {
+        let nats_uri = nats_stub();
+        let nats = nats::asynk::connect(&nats_uri).await.unwrap();
+        let actor = TestActor::default();
+        let recorder = actor.clone();
+        let mut agent = Agent::new(RunOpts::new("test".into(), actor, nats_uri), nats).unwrap();
+        for msg in [
+            send_msg(0, b"hello"),
+            send_msg(1, b"fail"),
+            subscribe_msg(2, b"fail"),
+            subscribe_msg(3, b"hello"),
+        ] {
+            agent.sender().send(msg).await.unwrap();
+        }
+
+        // on_stop runs once the mailbox is drained
+        agent.run_until(async {}).await.unwrap();
+        assert_eq!(
+            recorder.events(),
+            vec![
+                "on_start",
+                "handle 0",
+                "on_error send 1 test actor error",
+                "on_error subscribe 2 test actor error",
+                "handle 3",
+                "on_stop",
+            ]
+        );
+    }
+}
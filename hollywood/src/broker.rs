@@ -1,173 +1,747 @@
 use crate::actor::{
-    ActorMsg, ActorRequest, ActorSend, ActorSender, ActorSubscribe, HollywoodMsg, SubscribeType,
+    health_subject, instance_health_subject, topic_group_name, ActorMsg, ActorRequest, ActorSend,
+    ActorSender, ActorSubscribe, DispatchType, HealthStatus, HollywoodMsg, HollywoodPublish,
+    HollywoodResponse, HollywoodSend, Msg, SubscribeType,
 };
-use anyhow::Result;
-use log::{error, info, warn};
-use nats::asynk::Connection;
-use tokio::time::{sleep, Duration};
+use crate::dead_letter::DeadLetterPublisher;
+use crate::deadline;
+use crate::health::AgentHealth;
+use crate::jetstream::{self, DurableMailbox, DurableOpts, Settle};
+use crate::metadata::Metadata;
+use crate::metrics;
+use crate::priority::Overflow;
+use anyhow::{anyhow, Result};
+use log::{debug, error, info, warn};
+use nats::asynk::{Connection, Message, Subscription};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+/// How long JetStream waits before redelivering a durable
+/// msg the agent couldn't add to its mailbox.
+const MAILBOX_FULL_NAK_DELAY: Duration = Duration::from_secs(1);
 
 pub(crate) struct Broker {
     actor_name: String,
     mailbox_names: Vec<String>,
+    subscriptions: Vec<SubscribeType>,
+    health_subject: String,
+    // health subject of this agent instance
+    instance_health_subject: String,
+    // JetStream stream and consumer of Durable actors
+    durable_mailbox: DurableMailbox,
+    durable_opts: DurableOpts,
+    task: BrokerTask,
+    // signals spawned subscriptions to unsubscribe
+    shutdown_tx: watch::Sender<bool>,
+    handles: Vec<JoinHandle<Result<()>>>,
+}
+
+/// Values shared by each spawned subscription
+#[derive(Clone)]
+struct BrokerTask {
+    actor_name: String,
+    // msg types and versions the actor handles
+    // (used to label metrics with the msg type)
+    dispatch_types: Arc<Vec<String>>,
     mailbox_sender: ActorSender,
-    mailbox_max_size: Option<u32>,
     nats: Connection,
-    subscribe_type: SubscribeType,
+    // queue group for PublishGroup subjects
+    topic_group: String,
+    dead_letters: DeadLetterPublisher,
+    // answers health checks (broker liveness is set here)
+    health: Arc<AgentHealth>,
+    shutdown_rx: watch::Receiver<bool>,
+}
+
+/// Kind of subject a spawned subscription reads from. Msgs
+/// without a reply subject are dispatched as `Send` msgs
+/// from a queue mailbox and as `Subscribe` msgs from a topic.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Source {
+    Queue,
+    Topic,
+    // topic read by one agent of the actor type
+    GroupTopic,
+    // JetStream consumer of a durable mailbox
+    Durable,
 }
 
 impl Broker {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
+        system_name: &String,
         actor_name: String,
         mailbox_names: Vec<String>,
+        dispatch_types: Vec<String>,
         mailbox_sender: ActorSender,
-        mailbox_max_size: Option<u32>,
         nats: Connection,
-        subscribe_type: SubscribeType,
+        subscriptions: Vec<SubscribeType>,
+        durable_opts: DurableOpts,
+        health: Arc<AgentHealth>,
     ) -> Self {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let dead_letters = DeadLetterPublisher::new(system_name, &actor_name, nats.clone());
         Self {
-            actor_name: actor_name,
+            health_subject: health_subject(system_name, &actor_name),
+            instance_health_subject: instance_health_subject(
+                system_name,
+                &actor_name,
+                health.instance_id(),
+            ),
             mailbox_names: mailbox_names,
-            mailbox_sender: mailbox_sender,
-            mailbox_max_size: mailbox_max_size,
-            nats: nats,
-            subscribe_type: subscribe_type,
+            subscriptions,
+            durable_mailbox: DurableMailbox::new(system_name, &actor_name),
+            durable_opts,
+            task: BrokerTask {
+                actor_name: actor_name.clone(),
+                dispatch_types: Arc::new(dispatch_types),
+                mailbox_sender,
+                nats,
+                topic_group: topic_group_name(system_name, &actor_name),
+                dead_letters,
+                health,
+                shutdown_rx,
+            },
+            actor_name: actor_name,
+            shutdown_tx,
+            handles: vec![],
         }
     }
 
-    async fn spawn(
-        actor_name: String,
-        mailbox_max_size: Option<u32>,
-        mailbox_name: String,
-        mailbox_sender: ActorSender,
-        nats: Connection,
-        subscribe_type: SubscribeType,
-    ) -> Result<()> {
-        let source = match subscribe_type {
-            SubscribeType::Queue => {
+    async fn spawn(task: BrokerTask, subject: String, kind: Source) -> Result<()> {
+        let source = match kind {
+            Source::Queue => {
                 info!(
                     "{} agent subscribing to queue subject {:?}",
-                    &actor_name, &mailbox_name
+                    &task.actor_name, &subject
                 );
-                nats
+                task.nats
                     // use the mailbox name as the group
-                    .queue_subscribe(&mailbox_name, &mailbox_name)
+                    .queue_subscribe(&subject, &subject)
                     .await?
             }
-            SubscribeType::Publish { subject } => {
+            Source::Topic => {
                 info!(
                     "{} agent subscribing to pubsub subject {:?}",
-                    &actor_name, &subject
+                    &task.actor_name, &subject
+                );
+                task.nats.subscribe(&subject).await?
+            }
+            Source::GroupTopic => {
+                info!(
+                    "{} agent subscribing to pubsub subject {:?} with queue group {:?}",
+                    &task.actor_name, &subject, &task.topic_group
                 );
-                nats.subscribe(&subject).await?
+                task.nats
+                    .queue_subscribe(&subject, &task.topic_group)
+                    .await?
             }
+            Source::Durable => unreachable!("durable mailboxes use spawn_durable"),
         };
+        task.consume(&source, &subject, |nats_msg| task.forward(nats_msg, kind))
+            .await
+    }
 
-        let max_size = if mailbox_max_size.is_some() {
-            mailbox_max_size.unwrap()
-        } else {
-            0
+    /// Answers health check requests. Agents of an actor share
+    /// the health subject through a queue group (so one running
+    /// instance answers) and each also reads its instance subject.
+    async fn spawn_health(
+        task: BrokerTask,
+        health_subject: String,
+        group: Option<String>,
+    ) -> Result<()> {
+        info!(
+            "{} agent subscribing to health subject {:?} with queue group {:?}",
+            &task.actor_name, &health_subject, &group
+        );
+        let source = match &group {
+            Some(group) => task.nats.queue_subscribe(&health_subject, group).await?,
+            None => task.nats.subscribe(&health_subject).await?,
         };
-        let mut backoff = 0;
-        loop {
-            // slow down nats reading if mailbox is full
-            loop {
-                if max_size > 0 && mailbox_sender.len() > max_size as usize {
-                    sleep(Duration::from_millis(100)).await;
-                } else {
-                    break;
+        task.consume(&source, &health_subject, |nats_msg| {
+            task.answer_health(nats_msg)
+        })
+        .await
+    }
+
+    /// Read the durable mailbox from the deliver subject of the
+    /// JetStream consumer. Agents of the same actor version
+    /// share the consumer through its deliver group.
+    async fn spawn_durable(task: BrokerTask, deliver_subject: String, group: String) -> Result<()> {
+        info!(
+            "{} agent subscribing to durable mailbox {:?} with queue group {:?}",
+            &task.actor_name, &deliver_subject, &group
+        );
+        let source = task.nats.queue_subscribe(&deliver_subject, &group).await?;
+        task.consume(&source, &deliver_subject, |nats_msg| {
+            task.forward(nats_msg, Source::Durable)
+        })
+        .await
+    }
+
+    pub(crate) async fn run(&mut self) -> Result<()> {
+        self.task.health.set_broker_alive(true);
+
+        // create the durable mailbox before reading any
+        // msgs so agents fail to start without JetStream
+        let durable = self
+            .subscriptions
+            .iter()
+            .any(|subscribe_type| matches!(subscribe_type, SubscribeType::Durable));
+        if durable {
+            let (deliver_subject, group) = self
+                .durable_mailbox
+                .open(&self.task.nats, &self.durable_opts)
+                .await?;
+            let task = self.task.clone();
+            let handle = supervise(
+                self.task.health.clone(),
+                Broker::spawn_durable(task, deliver_subject, group),
+            );
+            self.handles.push(handle);
+        }
+
+        // spawn broker for each mailbox and pubsub subject
+        let mut subjects: Vec<(String, Source)> = vec![];
+        for subscribe_type in &self.subscriptions {
+            let (names, kind) = match subscribe_type {
+                SubscribeType::Queue | SubscribeType::Durable => {
+                    (self.mailbox_names.clone(), Source::Queue)
+                }
+                subscribe_type => {
+                    let kind = match subscribe_type {
+                        SubscribeType::PublishGroup { .. } => Source::GroupTopic,
+                        _ => Source::Topic,
+                    };
+                    let subjects = subscribe_type
+                        .subjects()
+                        .into_iter()
+                        .map(|subject| subject.to_owned())
+                        .collect();
+                    (subjects, kind)
+                }
+            };
+            for name in names {
+                if !subjects.iter().any(|(subject, _)| subject == &name) {
+                    subjects.push((name, kind));
                 }
             }
+        }
+        for (subject, kind) in subjects {
+            let task = self.task.clone();
+            let handle = supervise(self.task.health.clone(), Broker::spawn(task, subject, kind));
+            self.handles.push(handle);
+        }
 
-            if let Some(nats_msg) = source.try_next() {
-                // deserialize nats_msg.data here
-                let hollywood_msg: HollywoodMsg = match serde_json::from_slice(&nats_msg.data) {
-                    Ok(msg) => msg,
-                    Err(err) => {
-                        error!("deserializing nats msg to HollywoodMsg: {:?}", &err);
-                        continue;
-                    }
-                };
+        // spawn health check subscriptions
+        let health_subjects = [
+            (
+                self.health_subject.clone(),
+                Some(self.health_subject.clone()),
+            ),
+            (self.instance_health_subject.clone(), None),
+        ];
+        for (health_subject, group) in health_subjects {
+            let task = self.task.clone();
+            let handle = supervise(
+                self.task.health.clone(),
+                Broker::spawn_health(task, health_subject, group),
+            );
+            self.handles.push(handle);
+        }
+        Ok(())
+    }
 
-                // We should only have request/send here
-                // HollywoodMsg::Response type is only
-                let (msg_id, msg_version, msg) = match hollywood_msg {
-                    HollywoodMsg::Request(resp) => (resp.id, resp.msg_version, resp.msg),
-                    HollywoodMsg::Send(send) => (send.id, send.msg_version, send.msg),
-                    HollywoodMsg::Publish(publish) => {
-                        (publish.id, publish.msg_version, publish.msg)
-                    }
-                    _ => {
-                        todo!("HollywoodMsg: not yet implemented");
-                    }
-                };
+    /// Stop all mailbox subscriptions and wait for
+    /// each spawned broker to finish forwarding messages
+    /// to the actor mailbox.
+    pub(crate) async fn shutdown(&mut self) {
+        let _ = self.shutdown_tx.send(true);
+        for handle in self.handles.drain(..) {
+            match handle.await {
+                Ok(Err(err)) => {
+                    error!("{} broker shutdown: {:?}", &self.actor_name, &err);
+                }
+                Err(err) => {
+                    error!("{} broker task failed: {:?}", &self.actor_name, &err);
+                }
+                _ => {}
+            }
+        }
+    }
+}
 
-                // create ActorMsg.. if nats msg
-                // has a reply handle then send a nats request
-                // so we can route the response back to the caller
-                let msg = if nats_msg.reply.is_some() {
-                    ActorMsg::Request(ActorRequest {
-                        id: msg_id,
-                        msg: msg,
-                        msg_version: msg_version,
-                        reply_id: nats_msg.reply.unwrap(),
-                    })
-                } else {
-                    // send-type: queue or pubsub?
-                    match subscribe_type {
-                        SubscribeType::Queue => ActorMsg::Send(ActorSend {
-                            id: msg_id,
-                            msg: msg,
-                            msg_version: msg_version,
-                        }),
-                        _ => ActorMsg::Subscribe(ActorSubscribe {
-                            id: msg_id,
-                            msg: msg,
-                            msg_version: msg_version,
-                        }),
-                    }
-                };
+/// Spawns a subscription task and marks the broker as
+/// dead once it stops running, including when it panics.
+fn supervise<F>(health: Arc<AgentHealth>, subscription: F) -> JoinHandle<Result<()>>
+where
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let result = match tokio::spawn(subscription).await {
+            Ok(result) => result,
+            Err(err) => Err(anyhow!("broker subscription task failed: {}", err)),
+        };
+        health.set_broker_alive(false);
+        result
+    })
+}
+
+impl BrokerTask {
+    /// Await msgs from a subscription and pass them to `handle`
+    /// until the agent starts shutting down. The subscription is
+    /// drained (not unsubscribed) so msgs nats already delivered
+    /// to this agent are still handled.
+    async fn consume<F, Fut>(&self, source: &Subscription, subject: &str, handle: F) -> Result<()>
+    where
+        F: Fn(Message) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        // Subscription::next blocks a thread until the next msg
+        // so it isn't cancelled. Draining the subscription wakes
+        // it up once the remaining msgs are handled.
+        let read = async {
+            while let Some(nats_msg) = source.next().await {
+                handle(nats_msg).await;
+            }
+        };
+        tokio::pin!(read);
+        tokio::select! {
+            _ = &mut read => {
+                warn!("{} agent subscription to {:?} closed", &self.actor_name, subject);
+            }
+            _ = async { shutdown_rx.wait_for(|stop| *stop).await.is_ok() } => {
+                info!("{} agent draining {:?}", &self.actor_name, subject);
+                source.drain().await?;
+                read.await;
+            }
+        }
+        Ok(())
+    }
 
-                // send to mailbox
-                match mailbox_sender.send(msg).await {
-                    Ok(_) => {}
-                    Err(err) => {
-                        warn!("failed to forward msg to actor mailbox: {:?}", &err);
-                    }
+    /// Deserialize a nats msg and forward it to the actor mailbox.
+    async fn forward(&self, nats_msg: Message, kind: Source) {
+        // durable msgs are replied to with an ack
+        // instead of a response
+        let ack = match kind {
+            Source::Durable => nats_msg.reply.clone(),
+            _ => None,
+        };
+
+        // deserialize nats_msg.data here
+        let hollywood_msg: HollywoodMsg = match serde_json::from_slice(&nats_msg.data) {
+            Ok(msg) => msg,
+            Err(err) => {
+                error!("deserializing nats msg to HollywoodMsg: {:?}", &err);
+                self.settle(ack.as_deref(), Settle::Term).await;
+                metrics::count_dropped(&self.actor_name, None, metrics::UNKNOWN_MSG, "invalid");
+                self.dead_letters
+                    .publish(
+                        &nats_msg.subject,
+                        None,
+                        nats_msg.data,
+                        format!("deserializing nats msg to HollywoodMsg: {}", &err),
+                        0,
+                    )
+                    .await;
+                return;
+            }
+        };
+
+        // We should only have request/send here
+        // HollywoodMsg::Response type is only
+        let (msg_id, msg_version, msg, deadline, priority, traceparent, meta, stream) =
+            match hollywood_msg {
+                HollywoodMsg::Request(req) => (
+                    req.id,
+                    req.msg_version,
+                    req.msg,
+                    req.deadline,
+                    req.priority,
+                    req.traceparent,
+                    req.meta,
+                    req.stream,
+                ),
+                HollywoodMsg::Send(send) => (
+                    send.id,
+                    send.msg_version,
+                    send.msg,
+                    None,
+                    send.priority,
+                    send.traceparent,
+                    send.meta,
+                    None,
+                ),
+                HollywoodMsg::Publish(publish) => (
+                    publish.id,
+                    publish.msg_version,
+                    publish.msg,
+                    None,
+                    publish.priority,
+                    publish.traceparent,
+                    publish.meta,
+                    None,
+                ),
+                // responses and stream frames are only read by clients
+                HollywoodMsg::Response(_) | HollywoodMsg::Stream(_) => {
+                    warn!(
+                        "{} agent received an unexpected envelope type on {:?}",
+                        &self.actor_name, &nats_msg.subject
+                    );
+                    self.settle(ack.as_deref(), Settle::Term).await;
+                    metrics::count_dropped(
+                        &self.actor_name,
+                        None,
+                        metrics::UNKNOWN_MSG,
+                        "unexpected",
+                    );
+                    self.dead_letters
+                        .publish(
+                            &nats_msg.subject,
+                            None,
+                            nats_msg.data,
+                            "unexpected envelope type".to_string(),
+                            0,
+                        )
+                        .await;
+                    return;
                 }
-                backoff = 0;
-            } else {
-                // backoff max is 1sec
-                backoff = std::cmp::min(backoff + 10, 1000);
+            };
+
+        // if nats msg has a reply handle then send a nats
+        // request so we can route the response back to the caller
+        let dispatch_type = match (&nats_msg.reply, kind) {
+            (_, Source::Durable) => DispatchType::Send,
+            (Some(_), _) if stream.is_some() => DispatchType::Stream,
+            (Some(_), _) => DispatchType::Request,
+            (None, Source::Queue) => DispatchType::Send,
+            (None, _) => DispatchType::Subscribe,
+        };
+        metrics::count_msg(
+            metrics::MSGS_RECEIVED,
+            &self.actor_name,
+            &dispatch_type,
+            metrics::msg_labels(&self.dispatch_types, &msg_version),
+        );
+
+        // the caller already gave up on this request
+        if deadline::is_expired(deadline) {
+            warn!(
+                "{} agent dropping expired request msg id {}",
+                &self.actor_name, &msg_id
+            );
+            metrics::count_dropped(
+                &self.actor_name,
+                Some(&dispatch_type),
+                metrics::msg_labels(&self.dispatch_types, &msg_version),
+                "expired",
+            );
+            return;
+        }
+
+        // create ActorMsg..
+        let id = msg_id.clone();
+        let version = msg_version.clone();
+        let msg = match (&dispatch_type, nats_msg.reply) {
+            (DispatchType::Request | DispatchType::Stream, Some(reply_id)) => {
+                ActorMsg::Request(ActorRequest {
+                    id: msg_id,
+                    subject: nats_msg.subject.clone(),
+                    msg: msg,
+                    msg_version: msg_version,
+                    reply_id,
+                    deadline,
+                    priority,
+                    traceparent,
+                    meta,
+                    stream,
+                })
             }
+            (DispatchType::Send, _) => ActorMsg::Send(ActorSend {
+                id: msg_id,
+                subject: nats_msg.subject.clone(),
+                msg: msg,
+                msg_version: msg_version,
+                // JetStream counts the deliveries of durable msgs
+                attempt: ack.as_deref().and_then(jetstream::delivered).unwrap_or(1),
+                priority,
+                traceparent,
+                meta,
+                ack: ack.clone(),
+            }),
+            _ => ActorMsg::Subscribe(ActorSubscribe {
+                id: msg_id,
+                subject: nats_msg.subject.clone(),
+                msg: msg,
+                msg_version: msg_version,
+                attempt: 1,
+                priority,
+                traceparent,
+                meta,
+            }),
+        };
 
-            if backoff > 0 {
-                sleep(Duration::from_millis(backoff)).await;
+        // send to mailbox (waits for room if the
+        // mailbox is full and the policy is Block)
+        match self.mailbox_sender.push(msg).await {
+            Ok(None) => {}
+            Ok(Some(Overflow::Rejected(msg))) => {
+                debug!(
+                    "{} agent mailbox full, rejecting msg id {}",
+                    &self.actor_name, &id
+                );
+                self.refuse(msg).await;
+            }
+            // the dropped msg may be an older msg
+            // than the one that was just read
+            Ok(Some(Overflow::Dropped(msg))) => {
+                debug!(
+                    "{} agent mailbox full, dropped msg id {}",
+                    &self.actor_name,
+                    msg.id()
+                );
+                self.refuse(msg).await;
+            }
+            Err(err) => {
+                warn!("failed to forward msg to actor mailbox: {:?}", &err);
+                metrics::count_dropped(
+                    &self.actor_name,
+                    Some(&dispatch_type),
+                    metrics::msg_labels(&self.dispatch_types, &version),
+                    "mailbox_closed",
+                );
+                if ack.is_some() {
+                    self.settle(ack.as_deref(), Settle::Nak(Duration::ZERO))
+                        .await;
+                    return;
+                }
+                self.dead_letters
+                    .publish(
+                        &nats_msg.subject,
+                        Some(id),
+                        nats_msg.data,
+                        format!("failed to forward msg to actor mailbox: {}", &err),
+                        0,
+                    )
+                    .await;
             }
         }
-        // Ok(())
     }
 
-    pub(crate) async fn run(&self) -> Result<()> {
-        // spawn broker for each mailbox
-        for mailbox_name in &self.mailbox_names {
-            let actor_name = self.actor_name.clone();
-            let nats = self.nats.clone();
-            let mailbox_name = mailbox_name.to_owned();
-            let mailbox_sender = self.mailbox_sender.clone();
-            let mailbox_max_size = self.mailbox_max_size.clone();
-            let subscribe_type = self.subscribe_type.clone();
-            tokio::spawn(async move {
-                Broker::spawn(
-                    actor_name,
-                    mailbox_max_size,
-                    mailbox_name,
-                    mailbox_sender,
-                    nats,
-                    subscribe_type,
-                )
-                .await
-            });
+    /// Give up on a msg that didn't fit in the mailbox. Requests
+    /// get a busy reply, durable msgs are redelivered later and
+    /// other msgs are dead-lettered.
+    async fn refuse(&self, msg: ActorMsg) {
+        let (dispatch_type, msg_version) = msg.dispatch_type();
+        metrics::count_dropped(
+            &self.actor_name,
+            Some(&dispatch_type),
+            metrics::msg_labels(&self.dispatch_types, msg_version),
+            "mailbox_full",
+        );
+        let (subject, id, envelope) = match msg {
+            ActorMsg::Request(req) => {
+                let resp = HollywoodResponse {
+                    id: req.id,
+                    msg_version: "".to_string(),
+                    msg: None,
+                    error: Some(format!("busy: {} mailbox is full", &self.actor_name)),
+                    meta: Metadata::default(),
+                };
+                self.reply(&req.reply_id, HollywoodMsg::Response(resp))
+                    .await;
+                return;
+            }
+            // let JetStream redeliver it once there's room
+            ActorMsg::Send(ActorSend { ack: Some(ack), .. }) => {
+                self.settle(Some(&ack), Settle::Nak(MAILBOX_FULL_NAK_DELAY))
+                    .await;
+                return;
+            }
+            ActorMsg::Send(send) => (
+                send.subject,
+                send.id.clone(),
+                HollywoodMsg::Send(HollywoodSend {
+                    id: send.id,
+                    msg_version: send.msg_version,
+                    msg: send.msg,
+                    priority: send.priority,
+                    traceparent: send.traceparent,
+                    meta: send.meta,
+                }),
+            ),
+            ActorMsg::Subscribe(sub) => (
+                sub.subject,
+                sub.id.clone(),
+                HollywoodMsg::Publish(HollywoodPublish {
+                    id: sub.id,
+                    msg_version: sub.msg_version,
+                    msg: sub.msg,
+                    priority: sub.priority,
+                    traceparent: sub.traceparent,
+                    meta: sub.meta,
+                }),
+            ),
+        };
+        match envelope.into_bytes() {
+            Ok(envelope) => {
+                self.dead_letters
+                    .publish(&subject, Some(id), envelope, "mailbox full".to_string(), 0)
+                    .await;
+            }
+            Err(err) => {
+                error!(
+                    "failed to serialize dead letter for msg id {}: {:?}",
+                    &id, &err
+                );
+            }
         }
-        Ok(())
+    }
+
+    /// Reply to a health check with the current agent status.
+    async fn answer_health(&self, nats_msg: Message) {
+        let reply_id = match nats_msg.reply {
+            Some(reply_id) => reply_id,
+            None => {
+                warn!("health check msg is missing a reply subject");
+                return;
+            }
+        };
+        let id = match serde_json::from_slice(&nats_msg.data) {
+            Ok(HollywoodMsg::Request(req)) => req.id,
+            Ok(_) => {
+                warn!("health check expects a HollywoodMsg::Request");
+                return;
+            }
+            Err(err) => {
+                error!("deserializing nats msg to HollywoodMsg: {:?}", &err);
+                return;
+            }
+        };
+        let status = self.health.status(&self.mailbox_sender);
+        let resp = match status.into_bytes() {
+            Ok(msg) => HollywoodResponse {
+                id,
+                msg_version: HealthStatus::version().to_string(),
+                msg: Some(msg),
+                error: None,
+                meta: Metadata::default(),
+            },
+            Err(err) => HollywoodResponse {
+                id,
+                msg_version: "".to_string(),
+                msg: None,
+                error: Some(err.to_string()),
+                meta: Metadata::default(),
+            },
+        };
+        self.reply(&reply_id, HollywoodMsg::Response(resp)).await;
+    }
+
+    /// Ack, nak or term a durable msg (no-op for other msgs).
+    async fn settle(&self, ack: Option<&str>, settle: Settle) {
+        if let Some(ack) = ack {
+            if let Err(err) = jetstream::settle(&self.nats, ack, settle).await {
+                error!("{} agent settling {}: {:?}", &self.actor_name, ack, &err);
+                metrics::count_publish_error(&self.actor_name);
+            }
+        }
+    }
+
+    /// Publish a reply to a msg the actor didn't handle.
+    async fn reply(&self, reply_id: &str, msg: HollywoodMsg) {
+        let result = match msg.into_bytes() {
+            Ok(msg) => self.nats.publish(reply_id, msg).await.map_err(Into::into),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            error!(
+                "{} agent reply to {}: {:?}",
+                &self.actor_name, reply_id, &err
+            );
+            metrics::count_publish_error(&self.actor_name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actor::{actor_mailbox_name, HollywoodResponse};
+    use crate::client::Client;
+    use crate::dead_letter::dead_letter_subject;
+    use crate::overflow::OverflowPolicy;
+    use crate::priority;
+    use crate::testing::NatsStub;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_forward_unexpected_envelope() {
+        let stub = NatsStub::start();
+        let nats = nats::asynk::connect(&stub.uri).await.unwrap();
+        let system_name = "test".to_string();
+        let actor_name = "TestActor/v1.0".to_string();
+        let mailbox = actor_mailbox_name(&system_name, &actor_name, &"TestMsg/v1.0".to_string());
+        let (sender, receiver) = priority::mailbox(None, OverflowPolicy::Block);
+        let health = Arc::new(AgentHealth::new(actor_name.clone()));
+        let mut broker = Broker::new(
+            &system_name,
+            actor_name,
+            vec![mailbox.clone()],
+            vec!["TestMsg/v1.0".to_string()],
+            sender.clone(),
+            nats.clone(),
+            vec![SubscribeType::Queue],
+            DurableOpts::default(),
+            health.clone(),
+        );
+        broker.run().await.unwrap();
+        stub.wait_for_subscription(&nats, &mailbox).await;
+        let dead_letters = Client::from_connection(nats.clone())
+            .dead_letters(&system_name)
+            .await
+            .unwrap();
+
+        // a response sent to an actor mailbox is dead-lettered
+        let envelope = serde_json::to_vec(&HollywoodMsg::Response(HollywoodResponse {
+            error: None,
+            id: "1".to_string(),
+            msg: None,
+            msg_version: "v1.0".to_string(),
+            meta: Metadata::default(),
+        }))
+        .unwrap();
+        nats.publish(&mailbox, envelope.clone()).await.unwrap();
+        let dead_letter = timeout(Duration::from_secs(5), dead_letters.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(dead_letter.reason, "unexpected envelope type");
+        assert_eq!(dead_letter.subject, mailbox);
+        assert_eq!(dead_letter.envelope, envelope);
+        assert_eq!(dead_letter.attempts, 0);
+        assert_eq!(stub.published(&dead_letter_subject(&system_name)).len(), 1);
+
+        // and the subscription keeps running
+        assert_eq!(receiver.len(), 0);
+        assert!(health.status(&sender).broker_alive);
+        broker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_supervise_panic() {
+        let (sender, _receiver) = priority::mailbox(None, OverflowPolicy::Block);
+        let health = Arc::new(AgentHealth::new("TestActor/v1.0".to_string()));
+        health.set_broker_alive(true);
+
+        let result = supervise(health.clone(), async { panic!("subscription panic") }).await;
+        assert!(result.unwrap().is_err());
+        assert!(!health.status(&sender).broker_alive);
+        assert!(!health.status(&sender).healthy);
     }
 }
@@ -1,10 +1,38 @@
-use crate::actor::{HollywoodMsg, HollywoodPublish, HollywoodRequest, HollywoodSend, Msg};
+use crate::actor::{
+    HealthStatus, HollywoodMsg, HollywoodPublish, HollywoodRequest, HollywoodSend, Msg, RequestMsg,
+};
 use crate::common::new_id_as_string;
+use crate::dead_letter::{dead_letter_subject, DeadLetter, DeadLetters};
+use crate::deadline;
+use crate::jetstream;
+use crate::metadata::{self, Metadata};
+use crate::metrics;
+use crate::priority::Priority;
+use crate::stream::{
+    ResponseStream, StreamOpts, DEFAULT_STREAM_IDLE_TIMEOUT, DEFAULT_STREAM_WINDOW,
+};
+use crate::trace;
 use anyhow::{anyhow, Result};
 use log::{debug, info, warn};
 use nats;
 use nats::asynk::{Connection, Message};
-use tokio::time::{sleep, Duration};
+use std::io::ErrorKind;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{Instrument, Span};
+
+/// Record the latency of a request and whether it timed out
+fn record_request(subject: &str, started: Instant, result: &std::io::Result<Message>) {
+    let recorder = metrics::recorder();
+    let labels = [("subject", subject)];
+    recorder.record_histogram(
+        metrics::CLIENT_REQUEST_DURATION,
+        &labels,
+        started.elapsed().as_secs_f64(),
+    );
+    if matches!(result, Err(err) if err.kind() == ErrorKind::TimedOut) {
+        recorder.increment_counter(metrics::CLIENT_REQUEST_TIMEOUTS, &labels, 1);
+    }
+}
 
 /// Initialize nats connection
 async fn init_client(nats_uri: String) -> Result<Connection> {
@@ -26,8 +54,13 @@ async fn init_client(nats_uri: String) -> Result<Connection> {
 }
 
 /// Hollywood Client for a given system
+#[derive(Clone)]
 pub struct Client {
     nats: Connection,
+    // mailbox priority of publish/send/request msgs
+    priority: Priority,
+    // how long a response stream waits for each response
+    stream_idle_timeout: Duration,
 }
 
 impl Client {
@@ -37,16 +70,59 @@ impl Client {
             Err(err) => return Err(err.into()),
         };
 
-        Ok(Client { nats: nats_client })
+        Ok(Client {
+            nats: nats_client,
+            priority: Priority::Normal,
+            stream_idle_timeout: DEFAULT_STREAM_IDLE_TIMEOUT,
+        })
+    }
+
+    /// Returns a client that shares an existing nats connection.
+    pub(crate) fn from_connection(nats: Connection) -> Self {
+        Client {
+            nats,
+            priority: Priority::Normal,
+            stream_idle_timeout: DEFAULT_STREAM_IDLE_TIMEOUT,
+        }
+    }
+
+    /// Set the mailbox priority of the msgs this client
+    /// publishes, sends and requests.
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Set how long a `ResponseStream` waits for the next
+    /// response before it returns an error. Default is 60s.
+    pub fn with_stream_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.stream_idle_timeout = idle_timeout;
+        self
     }
 
     pub async fn publish<M: Msg>(&self, subject: &str, msg: M) -> Result<()> {
+        self.publish_with_meta(subject, msg, Metadata::default())
+            .await
+    }
+
+    /// Publish a msg with metadata (i.e. headers).
+    pub async fn publish_with_meta<M: Msg>(
+        &self,
+        subject: &str,
+        msg: M,
+        mut meta: Metadata,
+    ) -> Result<()> {
         let msg = msg.into_bytes()?;
         let msg_version = M::version();
+        let (span, trace) = trace::client_span("publish", subject, msg_version);
+        let id = metadata::msg_id(&mut meta);
         let publish = HollywoodPublish {
-            id: new_id_as_string(),
+            meta: metadata::outgoing(meta, &id),
+            id,
             msg: msg,
             msg_version: msg_version.to_owned(),
+            priority: self.priority,
+            traceparent: Some(trace.traceparent()),
         };
         let hollywood_msg = HollywoodMsg::Publish(publish);
         let msg = hollywood_msg.into_bytes()?;
@@ -54,19 +130,55 @@ impl Client {
             "hollywood::publish to subject: {} w/ msg: {:?}",
             &subject, &hollywood_msg
         );
-        match self.nats.publish(&subject[..], msg).await {
+        match self.nats.publish(subject, msg).instrument(span).await {
             Ok(_) => Ok(()),
             Err(err) => Err(err.into()),
         }
     }
 
     pub async fn send<M: Msg>(&self, subject: &str, msg: M) -> Result<()> {
+        self.send_with_meta(subject, msg, Metadata::default()).await
+    }
+
+    /// Send a msg with metadata (i.e. headers).
+    pub async fn send_with_meta<M: Msg>(
+        &self,
+        subject: &str,
+        msg: M,
+        meta: Metadata,
+    ) -> Result<()> {
+        self.send_envelope(subject, msg, meta, false).await
+    }
+
+    /// Send a msg to a durable mailbox and wait until
+    /// JetStream stored it.
+    pub(crate) async fn send_durable_with_meta<M: Msg>(
+        &self,
+        subject: &str,
+        msg: M,
+        meta: Metadata,
+    ) -> Result<()> {
+        self.send_envelope(subject, msg, meta, true).await
+    }
+
+    async fn send_envelope<M: Msg>(
+        &self,
+        subject: &str,
+        msg: M,
+        mut meta: Metadata,
+        durable: bool,
+    ) -> Result<()> {
         let msg = msg.into_bytes()?;
         let msg_version = M::version();
+        let (span, trace) = trace::client_span("send", subject, msg_version);
+        let id = metadata::msg_id(&mut meta);
         let send = HollywoodSend {
-            id: new_id_as_string(),
+            meta: metadata::outgoing(meta, &id),
+            id,
             msg: msg,
             msg_version: msg_version.to_owned(),
+            priority: self.priority,
+            traceparent: Some(trace.traceparent()),
         };
         let hollywood_msg = HollywoodMsg::Send(send);
         let msg = hollywood_msg.into_bytes()?;
@@ -74,13 +186,23 @@ impl Client {
             "hollywood::send to actor: {} w/ msg: {:?}",
             &subject, &hollywood_msg
         );
-        match self.nats.publish(&subject[..], msg).await {
+        if durable {
+            jetstream::publish(&self.nats, subject, msg)
+                .instrument(span)
+                .await?;
+            return Ok(());
+        }
+        match self.nats.publish(subject, msg).instrument(span).await {
             Ok(_) => Ok(()),
             Err(err) => Err(err.into()),
         }
     }
 
-    async fn handle_request<M: Msg>(&self, result: std::io::Result<Message>) -> Result<M> {
+    /// Returns the response msg and its metadata.
+    async fn handle_request<M: Msg>(
+        &self,
+        result: std::io::Result<Message>,
+    ) -> Result<(M, Metadata)> {
         match result {
             Ok(msg) => {
                 let hollywood_msg = match HollywoodMsg::from_bytes(&msg.data) {
@@ -95,7 +217,7 @@ impl Client {
                         if resp.msg.is_some() {
                             let msg = resp.msg.unwrap();
                             match M::from_bytes(&msg) {
-                                Ok(msg) => return Ok(msg),
+                                Ok(msg) => return Ok((msg, resp.meta)),
                                 Err(err) => return Err(err.into()),
                             }
                         } else {
@@ -109,7 +231,15 @@ impl Client {
                                 ));
                             }
                         }
-                        Err(anyhow!("not sure what happened"))
+                        // the handler responded without a msg
+                        match M::empty() {
+                            Some(msg) => Ok((msg, resp.meta)),
+                            None => Err(anyhow!(
+                                "msg id {} empty response, expected {}",
+                                &resp.id,
+                                M::dispatch_type()
+                            )),
+                        }
                     }
                     _ => {
                         // we should only have Response type here
@@ -121,56 +251,217 @@ impl Client {
         }
     }
 
-    pub async fn request_timeout<M: Msg>(
+    /// Send a request and wait at most `timeout_secs` for the response.
+    /// The request deadline is sent along with the msg so the actor
+    /// can drop it once the caller stops waiting. Calls made while
+    /// handling another request wait no longer than its deadline.
+    pub async fn request_timeout<M: RequestMsg>(
         &self,
         subject: &str,
         msg: M,
         timeout_secs: u64,
-    ) -> Result<M> {
+    ) -> Result<M::Response> {
+        let deadline = deadline::with_timeout(Duration::from_secs(timeout_secs));
+        let (msg, _) = self
+            .request_envelope(subject, msg, Metadata::default(), Some(deadline))
+            .await?;
+        Ok(msg)
+    }
+
+    /// Build a request envelope with its msg id and the standard
+    /// metadata. Requests whose deadline already passed are counted
+    /// as timed out instead of being sent.
+    fn request_msg<M: RequestMsg>(
+        &self,
+        op: &'static str,
+        subject: &str,
+        msg: M,
+        mut meta: Metadata,
+        deadline: Option<i64>,
+        stream: Option<StreamOpts>,
+    ) -> Result<(Vec<u8>, Span)> {
+        if deadline.is_some_and(|deadline| deadline::remaining(deadline).is_zero()) {
+            metrics::recorder().increment_counter(
+                metrics::CLIENT_REQUEST_TIMEOUTS,
+                &[("subject", subject)],
+                1,
+            );
+            return Err(anyhow!("request deadline exceeded"));
+        }
         let msg = msg.into_bytes()?;
         let msg_version = M::version();
+        let (span, trace) = trace::client_span(op, subject, msg_version);
+        let id = metadata::msg_id(&mut meta);
         let req = HollywoodRequest {
-            id: new_id_as_string(),
+            meta: metadata::outgoing(meta, &id),
+            id,
             msg: msg,
             msg_version: msg_version.to_owned(),
+            deadline,
+            priority: self.priority,
+            traceparent: Some(trace.traceparent()),
+            stream,
         };
         let hollywood_msg = HollywoodMsg::Request(req);
         let msg = hollywood_msg.into_bytes()?;
-        let timeout = std::time::Duration::from_secs(timeout_secs);
         debug!(
-            "hollywood::request_timeout to actor:{} w/ msg: {:?}",
-            &subject, &hollywood_msg
+            "hollywood::{} to actor:{} w/ msg: {:?}",
+            op, &subject, &hollywood_msg
         );
-        let result = self.nats.request_timeout(&subject[..], msg, timeout).await;
-        self.handle_request::<M>(result).await
+        Ok((msg, span))
     }
 
-    pub async fn request<M: Msg>(&self, subject: &str, msg: M) -> Result<M> {
-        let msg = msg.into_bytes()?;
-        let msg_version = M::version();
+    /// Send a request and wait for the response. Waits
+    /// at most until the deadline if one is set.
+    async fn request_envelope<M: RequestMsg>(
+        &self,
+        subject: &str,
+        msg: M,
+        meta: Metadata,
+        deadline: Option<i64>,
+    ) -> Result<(M::Response, Metadata)> {
+        let (msg, span) = self.request_msg("request", subject, msg, meta, deadline, None)?;
+        let started = Instant::now();
+        let result = match deadline.map(deadline::remaining) {
+            Some(timeout) => {
+                self.nats
+                    .request_timeout(subject, msg, timeout)
+                    .instrument(span)
+                    .await
+            }
+            None => self.nats.request(subject, msg).instrument(span).await,
+        };
+        record_request(subject, started, &result);
+        self.handle_request::<M::Response>(result).await
+    }
+
+    /// Send a request and stream its responses. The actor must
+    /// implement `HandleStream` for the msg. Dropping the stream
+    /// before it ends cancels the request handler.
+    pub async fn request_stream<M: RequestMsg>(
+        &self,
+        subject: &str,
+        msg: M,
+    ) -> Result<ResponseStream<M::Response>> {
+        self.request_stream_with_meta(subject, msg, Metadata::default())
+            .await
+    }
+
+    /// Send a request with metadata (i.e. headers) and
+    /// stream its responses.
+    pub async fn request_stream_with_meta<M: RequestMsg>(
+        &self,
+        subject: &str,
+        msg: M,
+        meta: Metadata,
+    ) -> Result<ResponseStream<M::Response>> {
+        let inbox = self.nats.new_inbox();
+        let opts = StreamOpts {
+            control: format!("{}.control", &inbox),
+            window: DEFAULT_STREAM_WINDOW,
+            idle_timeout: self.stream_idle_timeout,
+        };
+        let (msg, span) = self.request_msg(
+            "request_stream",
+            subject,
+            msg,
+            meta,
+            deadline::current(),
+            Some(opts.clone()),
+        )?;
+        // subscribe before the agent starts publishing responses
+        let subscription = self.nats.subscribe(&inbox).await?;
+        self.nats
+            .publish_request(subject, &inbox, msg)
+            .instrument(span)
+            .await?;
+        Ok(ResponseStream::new(subscription, self.nats.clone(), &opts))
+    }
+
+    /// Request the health status from an agent health subject.
+    pub async fn health(&self, subject: &str, timeout_secs: u64) -> Result<HealthStatus> {
         let req = HollywoodRequest {
             id: new_id_as_string(),
-            msg: msg,
-            msg_version: msg_version.to_owned(),
+            msg: vec![],
+            msg_version: HealthStatus::version().to_owned(),
+            deadline: None,
+            priority: self.priority,
+            traceparent: None,
+            meta: Metadata::default(),
+            stream: None,
         };
         let hollywood_msg = HollywoodMsg::Request(req);
         let msg = hollywood_msg.into_bytes()?;
+        let timeout = std::time::Duration::from_secs(timeout_secs);
         debug!(
-            "hollywood::request to actor:{} w/ msg: {:?}",
+            "hollywood::health to subject:{} w/ msg: {:?}",
             &subject, &hollywood_msg
         );
-        let result = self.nats.request(&subject[..], msg).await;
-        self.handle_request::<M>(result).await
+        let result = self.nats.request_timeout(&subject[..], msg, timeout).await;
+        let (status, _) = self.handle_request::<HealthStatus>(result).await?;
+        Ok(status)
+    }
+
+    /// Subscribe to the dead letters for a given system.
+    pub async fn dead_letters(&self, system_name: &str) -> Result<DeadLetters> {
+        let subject = dead_letter_subject(&system_name.to_string());
+        let subscription = self.nats.subscribe(&subject).await?;
+        Ok(DeadLetters::new(subscription))
+    }
+
+    /// Re-drive a dead letter by publishing its original
+    /// envelope back to the subject it was received on.
+    /// Dead-lettered publish msgs are delivered to every
+    /// subscriber of the original subject.
+    pub async fn redrive(&self, dead_letter: &DeadLetter) -> Result<()> {
+        debug!(
+            "hollywood::redrive msg id {:?} to subject: {}",
+            &dead_letter.id, &dead_letter.subject
+        );
+        match self
+            .nats
+            .publish(&dead_letter.subject, &dead_letter.envelope)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Send a request and wait for the response. Calls made while
+    /// handling another request inherit its deadline.
+    pub async fn request<M: RequestMsg>(&self, subject: &str, msg: M) -> Result<M::Response> {
+        let (msg, _) = self
+            .request_with_meta(subject, msg, Metadata::default())
+            .await?;
+        Ok(msg)
+    }
+
+    /// Send a request with metadata (i.e. headers) and wait for
+    /// the response. Returns the response along with the metadata
+    /// of its envelope.
+    pub async fn request_with_meta<M: RequestMsg>(
+        &self,
+        subject: &str,
+        msg: M,
+        meta: Metadata,
+    ) -> Result<(M::Response, Metadata)> {
+        self.request_envelope(subject, msg, meta, deadline::current())
+            .await
     }
 }
 
 pub mod mailbox {
 
-    use super::{debug, info};
-    use crate::{env, Actor, Dispatch, Msg, Result, SubscribeType};
+    use super::{debug, info, Duration};
+    use crate::{
+        env, Actor, Dispatch, HealthStatus, Metadata, Msg, Priority, RequestMsg, ResponseStream,
+        Result, SubscribeType,
+    };
     use std::io::{Error, ErrorKind};
 
     #[allow(dead_code)]
+    #[derive(Clone)]
     pub struct Mailbox {
         system_name: String,
         actor_name: &'static str,
@@ -178,6 +469,14 @@ pub mod mailbox {
         msg_name: &'static str,
         msg_version: &'static str,
         mailbox_name: String,
+        // subject `publish` sends to (the first pubsub
+        // subject or the queue mailbox of queue-only actors)
+        publish_subject: String,
+        // pubsub subjects the actor subscribes to
+        subjects: Vec<&'static str>,
+        // JetStream subject send msgs are stored on
+        // if the actor has a durable mailbox
+        durable_subject: Option<String>,
         hollywood: super::Client,
     }
 
@@ -185,38 +484,51 @@ pub mod mailbox {
         pub async fn new<A: Actor + Dispatch, M: Msg>(
             system_name: String,
             nats_uri: String,
+        ) -> Result<Mailbox> {
+            let hollywood_client = super::Client::new(nats_uri).await?;
+            Self::with_client::<A, M>(system_name, hollywood_client)
+        }
+
+        /// Returns a mailbox that sends msgs with an existing client
+        /// instead of opening a new nats connection.
+        pub fn with_client<A: Actor + Dispatch, M: Msg>(
+            system_name: String,
+            hollywood_client: super::Client,
         ) -> Result<Mailbox> {
             let actor_name = A::type_name();
             let actor_version = A::version();
             let msg_name = M::name();
             let msg_version = M::version();
-            let mailbox_name = match A::subscribe_type() {
-                SubscribeType::Queue => {
-                    // check to see if this msg_name/msg_version
-                    // is supported by this Actor::dispatch_types
-                    let actor_type = format!("{}/{}", &actor_name, &actor_version);
-                    let msg_type = format!("{}/{}", &msg_name, &msg_version);
-                    debug!(
-                        "{} allowable dispatch types: {:?}",
-                        &actor_type,
-                        A::dispatch_types().join(",")
-                    );
-                    A::dispatch_types()
-                        .into_iter()
-                        .find(|item| item == &msg_type)
-                        .ok_or(Error::new(
-                            ErrorKind::Unsupported,
-                            format!("{} doesn't support message type {}", &actor_type, &msg_type),
-                        ))?;
-                    let mailbox_name = crate::actor::mailbox_name(
-                        &system_name,
-                        &format!("{}::{}", &actor_type, &msg_type),
-                    );
-                    mailbox_name
-                }
-                SubscribeType::Publish { subject } => subject.to_owned(),
+            let subscriptions = A::subscriptions();
+            let subjects = subscriptions
+                .iter()
+                .flat_map(|subscribe_type| subscribe_type.subjects())
+                .collect::<Vec<_>>();
+            let durable = subscriptions
+                .iter()
+                .any(|subscribe_type| matches!(subscribe_type, SubscribeType::Durable));
+            let actor_type = format!("{}/{}", &actor_name, &actor_version);
+            let msg_type = format!("{}/{}", &msg_name, &msg_version);
+            let durable_subject = match durable {
+                true => Some(crate::jetstream::durable_subject(
+                    &system_name,
+                    &actor_type,
+                    &msg_type,
+                )),
+                false => None,
             };
-            let hollywood_client = super::Client::new(nats_uri).await?;
+            debug!(
+                "{} allowable dispatch types: {:?}",
+                &actor_type,
+                A::dispatch_types().join(",")
+            );
+            let (mailbox_name, publish_subject) = routes(
+                &system_name,
+                &actor_type,
+                &msg_type,
+                &subscriptions,
+                &A::dispatch_types(),
+            )?;
             Ok(Mailbox {
                 system_name,
                 actor_name,
@@ -224,10 +536,27 @@ pub mod mailbox {
                 msg_name,
                 msg_version,
                 mailbox_name,
+                publish_subject,
+                subjects,
+                durable_subject,
                 hollywood: hollywood_client,
             })
         }
 
+        /// Set the mailbox priority of the msgs sent
+        /// with this mailbox. Default is `Priority::Normal`.
+        pub fn with_priority(mut self, priority: Priority) -> Self {
+            self.hollywood = self.hollywood.with_priority(priority);
+            self
+        }
+
+        /// Set how long a `ResponseStream` waits for the next
+        /// response before it returns an error. Default is 60s.
+        pub fn with_stream_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+            self.hollywood = self.hollywood.with_stream_idle_timeout(idle_timeout);
+            self
+        }
+
         pub async fn from_env<A: Actor + Dispatch, M: Msg>() -> Result<Self> {
             let system_name = env::hollywood_system()?;
             let nats_uri = env::hollywood_system_nats_uri(system_name.clone())?;
@@ -252,13 +581,55 @@ pub mod mailbox {
             Ok(())
         }
 
-        pub async fn request<M: Msg>(&self, msg: M) -> Result<M> {
+        fn check_subject(&self, subject: &str) -> Result<()> {
+            if crate::subject::is_wildcard(subject) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("can't publish to wildcard subject {}", subject),
+                )
+                .into());
+            }
+            let subscribed = subject == self.mailbox_name
+                || self
+                    .subjects
+                    .iter()
+                    .any(|pattern| crate::subject::matches(pattern, subject));
+            if !subscribed {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{}/{} doesn't subscribe to subject {}",
+                        &self.actor_name, &self.actor_version, subject
+                    ),
+                )
+                .into());
+            }
+            Ok(())
+        }
+
+        pub async fn request<M: RequestMsg>(&self, msg: M) -> Result<M::Response> {
             self.check_type(M::name(), M::version())?;
             let subject = &self.mailbox_name[..];
             self.hollywood.request(subject, msg).await
         }
 
-        pub async fn request_timeout<M: Msg>(&self, msg: M, timeout_secs: u64) -> Result<M> {
+        /// Send a request with metadata and return the
+        /// response along with its envelope metadata.
+        pub async fn request_with_meta<M: RequestMsg>(
+            &self,
+            msg: M,
+            meta: Metadata,
+        ) -> Result<(M::Response, Metadata)> {
+            self.check_type(M::name(), M::version())?;
+            let subject = &self.mailbox_name[..];
+            self.hollywood.request_with_meta(subject, msg, meta).await
+        }
+
+        pub async fn request_timeout<M: RequestMsg>(
+            &self,
+            msg: M,
+            timeout_secs: u64,
+        ) -> Result<M::Response> {
             self.check_type(M::name(), M::version())?;
             let subject = &self.mailbox_name[..];
             self.hollywood
@@ -266,16 +637,269 @@ pub mod mailbox {
                 .await
         }
 
-        pub async fn send<M: Msg>(&self, msg: M) -> Result<()> {
+        /// Send a request and stream its responses.
+        pub async fn request_stream<M: RequestMsg>(
+            &self,
+            msg: M,
+        ) -> Result<ResponseStream<M::Response>> {
             self.check_type(M::name(), M::version())?;
             let subject = &self.mailbox_name[..];
-            self.hollywood.send(subject, msg).await
+            self.hollywood.request_stream(subject, msg).await
         }
 
-        pub async fn publish<M: Msg>(&self, msg: M) -> Result<()> {
+        /// Send a request with metadata and stream its responses.
+        pub async fn request_stream_with_meta<M: RequestMsg>(
+            &self,
+            msg: M,
+            meta: Metadata,
+        ) -> Result<ResponseStream<M::Response>> {
             self.check_type(M::name(), M::version())?;
             let subject = &self.mailbox_name[..];
-            self.hollywood.publish(subject, msg).await
+            self.hollywood
+                .request_stream_with_meta(subject, msg, meta)
+                .await
         }
+
+        /// Send a msg to the actor mailbox. Msgs sent to a
+        /// durable mailbox return once JetStream stored them.
+        pub async fn send<M: Msg>(&self, msg: M) -> Result<()> {
+            self.send_with_meta(msg, Metadata::default()).await
+        }
+
+        pub async fn send_with_meta<M: Msg>(&self, msg: M, meta: Metadata) -> Result<()> {
+            self.check_type(M::name(), M::version())?;
+            match &self.durable_subject {
+                Some(subject) => {
+                    self.hollywood
+                        .send_durable_with_meta(subject, msg, meta)
+                        .await
+                }
+                None => {
+                    let subject = &self.mailbox_name[..];
+                    self.hollywood.send_with_meta(subject, msg, meta).await
+                }
+            }
+        }
+
+        /// Publish a msg to the first pubsub subject of the actor
+        /// (to the queue mailbox if it has no pubsub subjects).
+        pub async fn publish<M: Msg>(&self, msg: M) -> Result<()> {
+            self.publish_to_with_meta(&self.publish_subject, msg, Metadata::default())
+                .await
+        }
+
+        pub async fn publish_with_meta<M: Msg>(&self, msg: M, meta: Metadata) -> Result<()> {
+            self.publish_to_with_meta(&self.publish_subject, msg, meta)
+                .await
+        }
+
+        /// Publish a msg to one of the subjects the actor subscribes
+        /// to. Use this for actors with more than one subject or
+        /// wildcard subjects (i.e. `orders.created` for `orders.*`).
+        pub async fn publish_to<M: Msg>(&self, subject: &str, msg: M) -> Result<()> {
+            self.publish_to_with_meta(subject, msg, Metadata::default())
+                .await
+        }
+
+        pub async fn publish_to_with_meta<M: Msg>(
+            &self,
+            subject: &str,
+            msg: M,
+            meta: Metadata,
+        ) -> Result<()> {
+            self.check_type(M::name(), M::version())?;
+            self.check_subject(subject)?;
+            self.hollywood.publish_with_meta(subject, msg, meta).await
+        }
+
+        /// Request the health status of a running actor agent.
+        /// One of the running agents answers.
+        pub async fn health(&self, timeout_secs: u64) -> Result<HealthStatus> {
+            let subject = crate::actor::health_subject(
+                &self.system_name,
+                &format!("{}/{}", &self.actor_name, &self.actor_version),
+            );
+            self.hollywood.health(&subject, timeout_secs).await
+        }
+
+        /// Request the health status of the agent with the
+        /// given instance id (see `HealthStatus::instance_id`).
+        pub async fn instance_health(
+            &self,
+            instance_id: &str,
+            timeout_secs: u64,
+        ) -> Result<HealthStatus> {
+            let subject = crate::actor::instance_health_subject(
+                &self.system_name,
+                &format!("{}/{}", &self.actor_name, &self.actor_version),
+                instance_id,
+            );
+            self.hollywood.health(&subject, timeout_secs).await
+        }
+    }
+
+    /// Returns the subject send/request msgs go to (the queue
+    /// mailbox, or the first pubsub subject of pubsub-only actors)
+    /// and the subject `Mailbox::publish` sends to.
+    fn routes(
+        system_name: &String,
+        actor_type: &String,
+        msg_type: &String,
+        subscriptions: &[SubscribeType],
+        dispatch_types: &[String],
+    ) -> Result<(String, String)> {
+        let has_queue = subscriptions.iter().any(|subscribe_type| {
+            matches!(
+                subscribe_type,
+                SubscribeType::Queue | SubscribeType::Durable
+            )
+        });
+        // publish to the first subject by default
+        let first_subject = subscriptions
+            .iter()
+            .flat_map(|subscribe_type| subscribe_type.subjects())
+            .next()
+            .map(|subject| subject.to_string());
+        if !has_queue {
+            let subject = first_subject.ok_or(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} has no pubsub subjects", actor_type),
+            ))?;
+            return Ok((subject.clone(), subject));
+        }
+        // check to see if this msg_name/msg_version
+        // is supported by this Actor::dispatch_types
+        if !dispatch_types.iter().any(|item| item == msg_type) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("{} doesn't support message type {}", actor_type, msg_type),
+            )
+            .into());
+        }
+        let mailbox_name = crate::actor::actor_mailbox_name(system_name, actor_type, msg_type);
+        let publish_subject = first_subject.unwrap_or_else(|| mailbox_name.clone());
+        Ok((mailbox_name, publish_subject))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_routes() {
+            let system_name = "examples".to_string();
+            let actor_type = "ActorY/v1.0".to_string();
+            let msg_type = "ActorYMsg/v1.0".to_string();
+            let dispatch_types = vec![msg_type.clone()];
+            let mailbox_name =
+                crate::actor::actor_mailbox_name(&system_name, &actor_type, &msg_type);
+
+            // queue only actors publish to the queue mailbox
+            let (mailbox, publish) = routes(
+                &system_name,
+                &actor_type,
+                &msg_type,
+                &[SubscribeType::Queue],
+                &dispatch_types,
+            )
+            .unwrap();
+            assert_eq!(mailbox, mailbox_name);
+            assert_eq!(publish, mailbox_name);
+
+            // mixed actors send to the queue and publish to the topic
+            let subscriptions = [
+                SubscribeType::Queue,
+                SubscribeType::Publish {
+                    subject: "actor-y.events",
+                },
+            ];
+            let (mailbox, publish) = routes(
+                &system_name,
+                &actor_type,
+                &msg_type,
+                &subscriptions,
+                &dispatch_types,
+            )
+            .unwrap();
+            assert_eq!(mailbox, mailbox_name);
+            assert_eq!(publish, "actor-y.events");
+
+            // pubsub only actors use the first subject for both
+            let (mailbox, publish) = routes(
+                &system_name,
+                &actor_type,
+                &msg_type,
+                &subscriptions[1..],
+                &dispatch_types,
+            )
+            .unwrap();
+            assert_eq!(mailbox, "actor-y.events");
+            assert_eq!(publish, "actor-y.events");
+
+            assert!(routes(
+                &system_name,
+                &actor_type,
+                &"OtherMsg/v1.0".to_string(),
+                &subscriptions,
+                &dispatch_types,
+            )
+            .is_err());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::NatsStub;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct CountMsg {
+        to: u32,
+    }
+
+    impl Msg for CountMsg {
+        type Type = Self;
+        const VERSION: &'static str = "v1.0";
+    }
+
+    impl RequestMsg for CountMsg {
+        type Response = CountMsg;
+    }
+
+    #[tokio::test]
+    async fn test_request_stream_envelope() {
+        let stub = NatsStub::start();
+        let nats = nats::asynk::connect(&stub.uri).await.unwrap();
+        let client = Client::from_connection(nats.clone());
+        let mut meta = Metadata {
+            msg_id: Some("count-1".to_string()),
+            ..Default::default()
+        };
+        meta.headers.insert("tenant".to_string(), "a".to_string());
+        let _stream = client
+            .request_stream_with_meta("test.count", CountMsg { to: 3 }, meta)
+            .await
+            .unwrap();
+        nats.flush().await.unwrap();
+
+        // stream requests carry the same envelope as plain requests
+        let published = stub.published("test.count");
+        assert_eq!(published.len(), 1);
+        let req = match HollywoodMsg::from_bytes(&published[0]).unwrap() {
+            HollywoodMsg::Request(req) => req,
+            msg => panic!("expected a request, got {:?}", msg),
+        };
+        assert_eq!(req.id, "count-1");
+        assert_eq!(req.msg_version, "v1.0");
+        assert!(req.stream.is_some());
+        assert_eq!(req.meta.correlation_id.as_deref(), Some("count-1"));
+        assert_eq!(
+            req.meta.headers.get("tenant").map(|h| h.as_str()),
+            Some("a")
+        );
+        assert!(req.meta.msg_id.is_none());
+        assert!(req.meta.sent_at.is_some());
     }
 }
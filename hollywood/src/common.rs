@@ -1,8 +1,10 @@
 use anyhow::Result;
 use local_ip_address::local_ip;
+use log::error;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::signal;
 use uuid::Uuid;
 
 pub(crate) fn new_id() -> Uuid {
@@ -27,6 +29,10 @@ pub(crate) fn epoch_as_secs() -> i64 {
     now_as_duration().as_secs() as i64
 }
 
+pub(crate) fn epoch_as_millis() -> i64 {
+    now_as_duration().as_millis() as i64
+}
+
 #[allow(dead_code)]
 pub(crate) fn local_ip_addr() -> Result<String> {
     match local_ip() {
@@ -48,3 +54,35 @@ pub(crate) fn deserialize<R: DeserializeOwned>(msg: &Vec<u8>) -> Result<R> {
         Err(err) => Err(err.into()),
     }
 }
+
+/// Resolves once the process receives SIGINT (ctrl-c)
+/// or SIGTERM (on unix platforms).
+pub(crate) async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = signal::ctrl_c().await {
+            error!("listening for ctrl-c signal: {:?}", &err);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut stream) => {
+                stream.recv().await;
+            }
+            Err(err) => {
+                error!("listening for SIGTERM signal: {:?}", &err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
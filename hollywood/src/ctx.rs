@@ -0,0 +1,156 @@
+use crate::actor::{Actor, Dispatch, DispatchType, Msg};
+use crate::client::{mailbox::Mailbox, Client};
+use crate::deadline;
+use crate::metadata::Metadata;
+use crate::scheduler::Scheduler;
+use crate::stream::{StreamSender, StreamSink};
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Context of the msg an actor handler is running for. Passed
+/// along with the msg to every handler trait except `Handle`.
+#[derive(Clone)]
+pub struct Ctx {
+    // msg id set by the sender
+    id: String,
+    // subject the msg was received on
+    subject: String,
+    // nats reply subject of a request
+    reply_to: Option<String>,
+    dispatch_type: DispatchType,
+    msg_version: String,
+    // starts at 1 and increments on every retry
+    attempt: u32,
+    meta: Metadata,
+    // request deadline (epoch millis)
+    deadline: Option<i64>,
+    system_name: String,
+    scheduler: Scheduler,
+    // client sharing the agent nats connection
+    client: Client,
+    // set for stream requests
+    stream: Option<Arc<StreamSink>>,
+}
+
+impl Ctx {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        id: String,
+        subject: String,
+        reply_to: Option<String>,
+        dispatch_type: DispatchType,
+        msg_version: String,
+        attempt: u32,
+        meta: Metadata,
+        deadline: Option<i64>,
+        system_name: String,
+        scheduler: Scheduler,
+        client: Client,
+    ) -> Self {
+        Self {
+            id,
+            subject,
+            reply_to,
+            dispatch_type,
+            msg_version,
+            attempt,
+            meta,
+            deadline,
+            system_name,
+            scheduler,
+            client,
+            stream: None,
+        }
+    }
+
+    pub(crate) fn with_stream(mut self, stream: Arc<StreamSink>) -> Self {
+        self.stream = Some(stream);
+        self
+    }
+
+    /// Returns the msg id.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the subject the msg was received on. For
+    /// pubsub msgs this is the subject it was published to.
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// Returns the nats reply subject of a request
+    /// or None for send and subscribe msgs.
+    pub fn reply_to(&self) -> Option<&str> {
+        self.reply_to.as_deref()
+    }
+
+    pub fn dispatch_type(&self) -> &DispatchType {
+        &self.dispatch_type
+    }
+
+    /// Returns the msg version (i.e. `v1.0`).
+    pub fn msg_version(&self) -> &str {
+        &self.msg_version
+    }
+
+    /// Returns the delivery attempt (1 unless the msg is
+    /// redelivered by a `RetryPolicy` or by JetStream).
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Returns the envelope metadata sent with the msg.
+    pub fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+
+    /// Returns the value of a metadata header.
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.meta.header(key)
+    }
+
+    /// Returns the request deadline or None if it doesn't have one.
+    pub fn deadline(&self) -> Option<SystemTime> {
+        self.deadline
+            .map(|deadline| UNIX_EPOCH + Duration::from_millis(deadline.max(0) as u64))
+    }
+
+    /// Returns how much time is left before the request
+    /// deadline or None if it doesn't have one.
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.deadline.map(deadline::remaining)
+    }
+
+    pub fn system_name(&self) -> &str {
+        &self.system_name
+    }
+
+    /// Returns the scheduler for sending this actor
+    /// delayed or periodic messages.
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    /// Returns a client that shares the agent nats connection.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Returns the sender for the responses to a stream
+    /// request. Returns an error for other dispatch types.
+    pub fn stream_sender<M: Msg>(&self) -> Result<StreamSender<M>> {
+        match &self.stream {
+            Some(stream) => Ok(StreamSender::new(stream.clone())),
+            None => Err(anyhow!("msg id {} isn't a stream request", &self.id)),
+        }
+    }
+
+    /// Returns the mailbox for sending `M` msgs to actor `A`
+    /// in this system. The mailbox shares the agent nats
+    /// connection.
+    pub fn mailbox<A: Actor + Dispatch, M: Msg>(&self) -> Result<Mailbox> {
+        Mailbox::with_client::<A, M>(self.system_name.clone(), self.client.clone())
+    }
+}
@@ -0,0 +1,186 @@
+use crate::actor::{mailbox_name, Msg, VERSION_v1_0};
+use crate::common;
+use crate::metrics;
+use anyhow::Result;
+use log::{debug, error};
+use nats::asynk::{Connection, Subscription};
+use serde::{Deserialize, Serialize};
+
+/// Returns the dead-letter subject for a given system.
+pub(crate) fn dead_letter_subject(system_name: &String) -> String {
+    mailbox_name(system_name, &"dead-letter".to_string())
+}
+
+/// DeadLetter wraps a message an actor failed to handle
+/// or an agent couldn't deliver to its actor mailbox.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeadLetter {
+    /// Original msg id (None if the envelope couldn't be deserialized)
+    pub id: Option<String>,
+    /// Subject the original envelope was received on
+    pub subject: String,
+    /// Original HollywoodMsg envelope bytes
+    pub envelope: Vec<u8>,
+    /// Why the msg was dead-lettered
+    pub reason: String,
+    /// Actor type name and version (i.e. `ActorX/v1.0`)
+    pub actor: String,
+    /// Number of times the actor tried to handle the msg
+    /// (zero if it never made it to the actor mailbox)
+    pub attempts: u32,
+    /// Epoch secs when the msg was dead-lettered
+    pub timestamp: i64,
+}
+
+impl Msg for DeadLetter {
+    type Type = Self;
+    const VERSION: &'static str = VERSION_v1_0;
+}
+
+/// Publishes dead letters for an agent.
+#[derive(Clone)]
+pub(crate) struct DeadLetterPublisher {
+    subject: String,
+    actor: String,
+    nats: Connection,
+}
+
+impl DeadLetterPublisher {
+    pub(crate) fn new(system_name: &String, actor: &String, nats: Connection) -> Self {
+        Self {
+            subject: dead_letter_subject(system_name),
+            actor: actor.to_owned(),
+            nats,
+        }
+    }
+
+    /// Publish the original envelope to the dead-letter subject.
+    /// Failures are logged since there's nowhere left to send them.
+    pub(crate) async fn publish(
+        &self,
+        subject: &str,
+        id: Option<String>,
+        envelope: Vec<u8>,
+        reason: String,
+        attempts: u32,
+    ) {
+        let dead_letter = DeadLetter {
+            id,
+            subject: subject.to_owned(),
+            envelope,
+            reason,
+            actor: self.actor.clone(),
+            attempts,
+            timestamp: common::epoch_as_secs(),
+        };
+        debug!(
+            "{} dead-lettering msg id {:?} from {}: {}",
+            &self.actor, &dead_letter.id, &dead_letter.subject, &dead_letter.reason
+        );
+        let result = match dead_letter.into_bytes() {
+            Ok(msg) => self
+                .nats
+                .publish(&self.subject, msg)
+                .await
+                .map_err(Into::into),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            error!(
+                "{} failed to publish dead letter for msg id {:?}: {:?}",
+                &self.actor, &dead_letter.id, &err
+            );
+            metrics::count_publish_error(&self.actor);
+        }
+    }
+}
+
+/// Subscription to a system's dead-letter subject.
+/// Use `Client::dead_letters` to create one.
+pub struct DeadLetters {
+    subscription: Subscription,
+}
+
+impl DeadLetters {
+    pub(crate) fn new(subscription: Subscription) -> Self {
+        Self { subscription }
+    }
+
+    /// Returns the next dead letter or None
+    /// if the subscription was closed.
+    pub async fn next(&self) -> Option<Result<DeadLetter>> {
+        let nats_msg = self.subscription.next().await?;
+        Some(DeadLetter::from_bytes(&nats_msg.data))
+    }
+
+    /// Stop reading dead letters.
+    pub async fn unsubscribe(self) -> Result<()> {
+        Ok(self.subscription.unsubscribe().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::testing::NatsStub;
+    use tokio::time::{timeout, Duration};
+
+    #[test]
+    fn test_serialize() {
+        let dead_letter = DeadLetter {
+            id: None,
+            subject: "examples.mailbox.ActorX/v1.0.ActorXMsg/v1.0".to_string(),
+            envelope: vec![0, 159, 146, 150, b'{'],
+            reason: "deserializing nats msg to HollywoodMsg".to_string(),
+            actor: "ActorX/v1.0".to_string(),
+            attempts: 3,
+            timestamp: 1700000000,
+        };
+        let decoded = DeadLetter::from_bytes(&dead_letter.into_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.id, None);
+        assert_eq!(decoded.subject, dead_letter.subject);
+        assert_eq!(decoded.envelope, dead_letter.envelope);
+        assert_eq!(decoded.reason, dead_letter.reason);
+        assert_eq!(decoded.actor, dead_letter.actor);
+        assert_eq!(decoded.attempts, 3);
+        assert_eq!(decoded.timestamp, dead_letter.timestamp);
+    }
+
+    #[tokio::test]
+    async fn test_redrive() {
+        let stub = NatsStub::start();
+        let nats = nats::asynk::connect(&stub.uri).await.unwrap();
+        let client = Client::from_connection(nats.clone());
+        let dead_letters = client.dead_letters("test").await.unwrap();
+        let publisher = DeadLetterPublisher::new(
+            &"test".to_string(),
+            &"ActorX/v1.0".to_string(),
+            nats.clone(),
+        );
+        let subject = "test.mailbox.ActorX/v1.0.ActorXMsg/v1.0";
+        let envelope = b"{\"Send\":{}}".to_vec();
+        publisher
+            .publish(
+                subject,
+                Some("1".to_string()),
+                envelope.clone(),
+                "handler failed".to_string(),
+                2,
+            )
+            .await;
+
+        let dead_letter = timeout(Duration::from_secs(5), dead_letters.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(dead_letter.id.as_deref(), Some("1"));
+        assert_eq!(dead_letter.attempts, 2);
+
+        // the original envelope goes back to the original subject
+        client.redrive(&dead_letter).await.unwrap();
+        nats.flush().await.unwrap();
+        assert_eq!(stub.published(subject), vec![envelope]);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::common;
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+tokio::task_local! {
+    // deadline (epoch millis) of the request an actor is handling
+    static DEADLINE: Option<i64>;
+}
+
+/// Returns the deadline of the request the current actor
+/// handler is processing or None if it doesn't have one.
+///
+/// Deadlines are only visible from the handler task itself
+/// (not from tasks it spawns).
+pub fn deadline() -> Option<SystemTime> {
+    current().map(|deadline| UNIX_EPOCH + Duration::from_millis(deadline.max(0) as u64))
+}
+
+/// Returns how much time is left before the current request
+/// deadline or None if it doesn't have one. Requests made with
+/// a `Client` from inside the handler inherit this budget.
+pub fn time_remaining() -> Option<Duration> {
+    current().map(remaining)
+}
+
+/// Returns the deadline (epoch millis) in scope.
+pub(crate) fn current() -> Option<i64> {
+    DEADLINE.try_with(|deadline| *deadline).ok().flatten()
+}
+
+/// Returns the deadline (epoch millis) for a request
+/// with a timeout, capped by the deadline in scope.
+pub(crate) fn with_timeout(timeout: Duration) -> i64 {
+    let deadline = common::epoch_as_millis() + timeout.as_millis() as i64;
+    match current() {
+        Some(current) => std::cmp::min(current, deadline),
+        None => deadline,
+    }
+}
+
+/// Returns the time left before a deadline (epoch millis).
+pub(crate) fn remaining(deadline: i64) -> Duration {
+    let remaining = deadline - common::epoch_as_millis();
+    Duration::from_millis(remaining.max(0) as u64)
+}
+
+/// Returns true if a deadline (epoch millis) has passed.
+pub(crate) fn is_expired(deadline: Option<i64>) -> bool {
+    match deadline {
+        Some(deadline) => deadline <= common::epoch_as_millis(),
+        None => false,
+    }
+}
+
+/// Run a handler future with the request deadline in scope.
+pub(crate) async fn scope<F: Future>(deadline: Option<i64>, f: F) -> F::Output {
+    DEADLINE.scope(deadline, f).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_deadline_scope() {
+        assert_eq!(time_remaining(), None);
+
+        let deadline = common::epoch_as_millis() + 60_000;
+        scope(Some(deadline), async move {
+            assert_eq!(current(), Some(deadline));
+            assert!(time_remaining().unwrap() <= Duration::from_secs(60));
+
+            // nested requests inherit the shorter deadline
+            assert_eq!(with_timeout(Duration::from_secs(120)), deadline);
+            assert!(with_timeout(Duration::from_secs(1)) < deadline);
+        })
+        .await;
+
+        assert!(is_expired(Some(common::epoch_as_millis() - 1)));
+        assert!(!is_expired(None));
+    }
+}
@@ -0,0 +1,463 @@
+use crate::common;
+use crate::metadata::Metadata;
+use anyhow::Result;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use tokio::time::Duration;
+
+/// DedupOpts defines how long an agent remembers the ids of
+/// the msgs it handled. Sends and subscribe msgs with an id
+/// seen within the window are skipped and duplicate requests
+/// are answered with the cached response.
+///
+/// The window is kept in memory by each agent, so duplicates
+/// handled by another running agent aren't detected.
+#[derive(Clone, Debug)]
+pub struct DedupOpts {
+    window: Duration,
+    max_entries: usize,
+    path: Option<PathBuf>,
+}
+
+impl DedupOpts {
+    /// Remember msg ids for `window` (at most 100,000 ids).
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            max_entries: 100_000,
+            path: None,
+        }
+    }
+
+    /// Forget the oldest ids once the window holds `max_entries`.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries.max(1);
+        self
+    }
+
+    /// Append handled msg ids to a file and reload them
+    /// when the agent restarts.
+    pub fn with_persistence(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+}
+
+/// Response returned to duplicate requests.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct CachedResponse {
+    pub msg_version: String,
+    pub msg: Option<Vec<u8>>,
+    pub meta: Metadata,
+}
+
+/// Returned by `DedupCache::begin`.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Seen {
+    // first time the id is seen, handle the msg
+    New,
+    // another worker is handling the same id
+    InFlight,
+    // handled before (with the response for requests)
+    Handled(Option<Box<CachedResponse>>),
+}
+
+#[derive(Debug)]
+enum State {
+    InFlight,
+    Handled(Option<Box<CachedResponse>>),
+}
+
+#[derive(Debug)]
+struct Entry {
+    // epoch millis the id was first seen
+    seen_at: i64,
+    // insertion order (ids seen within the same
+    // millisecond share their seen_at)
+    seq: u64,
+    state: State,
+}
+
+/// Line appended to the persistence file for each handled msg.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    id: String,
+    seen_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response: Option<CachedResponse>,
+}
+
+#[derive(Default)]
+struct Window {
+    entries: HashMap<String, Entry>,
+    // ids in the order they were first seen
+    // along with their seen_at and seq
+    order: VecDeque<(String, i64, u64)>,
+    next_seq: u64,
+}
+
+/// Ids of the msgs an agent handled within the dedup window.
+/// Shared by the agent workers.
+pub(crate) struct DedupCache {
+    window_millis: i64,
+    max_entries: usize,
+    window: Mutex<Window>,
+    // handled msgs waiting to be persisted by the writer
+    writer_tx: Option<Sender<Record>>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+impl DedupCache {
+    /// Create the cache and reload the ids persisted
+    /// within the window.
+    pub(crate) fn open(opts: &DedupOpts) -> Result<Self> {
+        let mut cache = DedupCache {
+            window_millis: opts.window.as_millis() as i64,
+            max_entries: opts.max_entries,
+            window: Mutex::new(Window::default()),
+            writer_tx: None,
+            writer: None,
+        };
+        let path = match &opts.path {
+            Some(path) => path,
+            None => return Ok(cache),
+        };
+
+        let mut records = VecDeque::new();
+        if path.exists() {
+            let now = common::epoch_as_millis();
+            let window = cache.window.get_mut().unwrap();
+            for line in BufReader::new(File::open(path)?).lines() {
+                // skip records cut off by a crash
+                let record: Record = match serde_json::from_str(&line?) {
+                    Ok(record) => record,
+                    Err(_) => continue,
+                };
+                if now - record.seen_at < cache.window_millis {
+                    cache_insert(
+                        window,
+                        cache.max_entries,
+                        record.id.clone(),
+                        record.seen_at,
+                        State::Handled(record.response.clone().map(Box::new)),
+                    );
+                    records.push_back(record);
+                }
+            }
+            info!("loaded {} dedup ids from {:?}", window.entries.len(), path);
+        }
+
+        let mut writer = Writer {
+            path: path.clone(),
+            file: None,
+            window_millis: cache.window_millis,
+            max_entries: cache.max_entries,
+            records,
+            appended: 0,
+        };
+        writer.compact()?;
+        let (writer_tx, writer_rx) = mpsc::channel();
+        cache.writer_tx = Some(writer_tx);
+        cache.writer = Some(
+            thread::Builder::new()
+                .name("hollywood-dedup".to_string())
+                .spawn(move || writer.run(writer_rx))?,
+        );
+        Ok(cache)
+    }
+
+    /// Mark an id as in flight unless it was seen before.
+    pub(crate) fn begin(&self, id: &str) -> Seen {
+        self.begin_at(id, common::epoch_as_millis())
+    }
+
+    fn begin_at(&self, id: &str, now: i64) -> Seen {
+        let mut window = self.window.lock().unwrap();
+        self.evict(&mut window, now);
+        match window.entries.get(id).map(|entry| &entry.state) {
+            Some(State::InFlight) => Seen::InFlight,
+            Some(State::Handled(response)) => Seen::Handled(response.clone()),
+            None => {
+                cache_insert(
+                    &mut window,
+                    self.max_entries,
+                    id.to_string(),
+                    now,
+                    State::InFlight,
+                );
+                Seen::New
+            }
+        }
+    }
+
+    /// Remember a handled msg (and the response for requests).
+    /// The id is persisted by the writer thread.
+    pub(crate) fn finish(&self, id: &str, response: Option<CachedResponse>) {
+        let mut window = self.window.lock().unwrap();
+        let seen_at = match window.entries.get_mut(id) {
+            Some(entry) => {
+                entry.state = State::Handled(response.clone().map(Box::new));
+                entry.seen_at
+            }
+            // evicted while the msg was handled
+            None => return,
+        };
+        drop(window);
+        if let Some(writer_tx) = &self.writer_tx {
+            let record = Record {
+                id: id.to_string(),
+                seen_at,
+                response,
+            };
+            if writer_tx.send(record).is_err() {
+                error!("persisting dedup id {}: writer stopped", id);
+            }
+        }
+    }
+
+    /// Forget an id that failed, so retries
+    /// and redeliveries are handled again.
+    pub(crate) fn abort(&self, id: &str) {
+        let mut window = self.window.lock().unwrap();
+        if matches!(
+            window.entries.get(id),
+            Some(Entry {
+                state: State::InFlight,
+                ..
+            })
+        ) {
+            window.entries.remove(id);
+        }
+    }
+
+    /// Drop the ids seen before the window.
+    fn evict(&self, window: &mut Window, now: i64) {
+        while let Some((_, seen_at, _)) = window.order.front() {
+            if now - seen_at < self.window_millis {
+                break;
+            }
+            pop_oldest(window);
+        }
+    }
+}
+
+impl Drop for DedupCache {
+    /// Wait for the writer to persist the remaining ids.
+    fn drop(&mut self) {
+        self.writer_tx.take();
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                error!("dedup writer panicked");
+            }
+        }
+    }
+}
+
+fn cache_insert(window: &mut Window, max_entries: usize, id: String, seen_at: i64, state: State) {
+    let seq = window.next_seq;
+    window.next_seq += 1;
+    window.order.push_back((id.clone(), seen_at, seq));
+    window.entries.insert(
+        id,
+        Entry {
+            seen_at,
+            seq,
+            state,
+        },
+    );
+    while window.entries.len() > max_entries {
+        if !pop_oldest(window) {
+            break;
+        }
+    }
+}
+
+fn pop_oldest(window: &mut Window) -> bool {
+    let (id, _, seq) = match window.order.pop_front() {
+        Some(oldest) => oldest,
+        None => return false,
+    };
+    // skip ids that were aborted and seen again since
+    if window.entries.get(&id).map(|entry| entry.seq) == Some(seq) {
+        window.entries.remove(&id);
+    }
+    true
+}
+
+/// Appends handled ids to the persistence file on its own
+/// thread, so workers never wait on file I/O.
+struct Writer {
+    path: PathBuf,
+    file: Option<File>,
+    window_millis: i64,
+    max_entries: usize,
+    // records in the file, oldest first
+    records: VecDeque<Record>,
+    // records appended since the file was last compacted
+    appended: usize,
+}
+
+impl Writer {
+    /// Runs until the cache is dropped.
+    fn run(mut self, writer_rx: Receiver<Record>) {
+        for record in writer_rx {
+            let id = record.id.clone();
+            if let Err(err) = self.append(record) {
+                error!("persisting dedup id {}: {:?}", id, &err);
+            }
+        }
+    }
+
+    fn append(&mut self, record: Record) -> Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            let mut line = serde_json::to_vec(&record)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+        }
+        self.records.push_back(record);
+        self.appended += 1;
+        if self.appended > self.max_entries {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the persistence file with the handled
+    /// ids still in the window.
+    fn compact(&mut self) -> Result<()> {
+        let now = common::epoch_as_millis();
+        let window_millis = self.window_millis;
+        self.records
+            .retain(|record| now - record.seen_at < window_millis);
+        while self.records.len() > self.max_entries {
+            self.records.pop_front();
+        }
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for record in self.records.iter() {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        self.file = Some(OpenOptions::new().append(true).open(&self.path)?);
+        self.appended = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(msg: &str) -> CachedResponse {
+        CachedResponse {
+            msg_version: "v1.0".to_string(),
+            msg: Some(msg.as_bytes().to_vec()),
+            meta: Metadata::default(),
+        }
+    }
+
+    #[test]
+    fn test_begin_finish_abort() {
+        let cache = DedupCache::open(&DedupOpts::new(Duration::from_secs(60))).unwrap();
+        assert_eq!(cache.begin_at("a", 0), Seen::New);
+        assert_eq!(cache.begin_at("a", 1), Seen::InFlight);
+        cache.finish("a", Some(response("pong")));
+        assert_eq!(
+            cache.begin_at("a", 2),
+            Seen::Handled(Some(Box::new(response("pong"))))
+        );
+
+        // failed msgs are handled again
+        assert_eq!(cache.begin_at("b", 3), Seen::New);
+        cache.abort("b");
+        assert_eq!(cache.begin_at("b", 4), Seen::New);
+    }
+
+    #[test]
+    fn test_window() {
+        let cache = DedupCache::open(&DedupOpts::new(Duration::from_millis(100))).unwrap();
+        assert_eq!(cache.begin_at("a", 0), Seen::New);
+        cache.finish("a", None);
+        assert_eq!(cache.begin_at("a", 99), Seen::Handled(None));
+        assert_eq!(cache.begin_at("a", 100), Seen::New);
+
+        let opts = DedupOpts::new(Duration::from_secs(60)).with_max_entries(2);
+        let cache = DedupCache::open(&opts).unwrap();
+        for (now, id) in ["a", "b", "c"].iter().enumerate() {
+            assert_eq!(cache.begin_at(id, now as i64), Seen::New);
+            cache.finish(id, None);
+        }
+        assert_eq!(cache.begin_at("a", 3), Seen::New);
+        assert_eq!(cache.begin_at("c", 4), Seen::Handled(None));
+    }
+
+    #[test]
+    fn test_max_entries_same_millis() {
+        let opts = DedupOpts::new(Duration::from_secs(60)).with_max_entries(2);
+        let cache = DedupCache::open(&opts).unwrap();
+        assert_eq!(cache.begin_at("a", 0), Seen::New);
+        cache.abort("a");
+        assert_eq!(cache.begin_at("b", 0), Seen::New);
+        assert_eq!(cache.begin_at("a", 0), Seen::New);
+
+        // b is the oldest entry even though a was
+        // first seen (and aborted) in the same ms
+        assert_eq!(cache.begin_at("c", 0), Seen::New);
+        assert_eq!(cache.begin_at("a", 0), Seen::InFlight);
+        assert_eq!(cache.begin_at("c", 0), Seen::InFlight);
+        assert_eq!(cache.begin_at("b", 0), Seen::New);
+        assert_eq!(cache.window.lock().unwrap().entries.len(), 2);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let path = std::env::temp_dir().join(format!("hollywood-dedup-{}", common::new_id()));
+        let opts = DedupOpts::new(Duration::from_secs(60)).with_persistence(&path);
+        let cache = DedupCache::open(&opts).unwrap();
+        assert_eq!(cache.begin("a"), Seen::New);
+        cache.finish("a", Some(response("pong")));
+        assert_eq!(cache.begin("b"), Seen::New);
+        drop(cache);
+
+        // in flight ids aren't persisted
+        let cache = DedupCache::open(&opts).unwrap();
+        assert_eq!(
+            cache.begin("a"),
+            Seen::Handled(Some(Box::new(response("pong"))))
+        );
+        assert_eq!(cache.begin("b"), Seen::New);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_persistence_compaction() {
+        let path = std::env::temp_dir().join(format!("hollywood-dedup-{}", common::new_id()));
+        let opts = DedupOpts::new(Duration::from_secs(60))
+            .with_max_entries(2)
+            .with_persistence(&path);
+        let cache = DedupCache::open(&opts).unwrap();
+        for id in ["a", "b", "c", "d"] {
+            assert_eq!(cache.begin(id), Seen::New);
+            cache.finish(id, None);
+        }
+        drop(cache);
+
+        // the file keeps the newest max_entries ids
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines <= 3, "{} lines", lines);
+        let cache = DedupCache::open(&opts).unwrap();
+        assert_eq!(cache.begin("a"), Seen::New);
+        assert_eq!(cache.begin("d"), Seen::Handled(None));
+        let _ = fs::remove_file(&path);
+    }
+}
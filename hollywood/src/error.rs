@@ -0,0 +1,119 @@
+use crate::actor::DispatchType;
+use std::fmt;
+
+/// Returned by `Dispatch::dispatch` if an actor
+/// doesn't handle the message version it received.
+#[derive(Debug, Clone)]
+pub struct UnsupportedVersion {
+    /// Actor type name and version (i.e. `ActorX/v1.0`)
+    pub actor: String,
+    /// The message version the actor received
+    pub version: String,
+    /// The dispatch types (i.e. `ActorXMsg/v1.0`) the actor supports
+    pub dispatch_types: Vec<String>,
+}
+
+impl UnsupportedVersion {
+    pub fn new(actor: String, version: String, dispatch_types: Vec<String>) -> Self {
+        Self {
+            actor,
+            version,
+            dispatch_types,
+        }
+    }
+}
+
+impl fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} doesn't support msg version {:?}, supported dispatch types: [{}]",
+            &self.actor,
+            &self.version,
+            self.dispatch_types.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
+/// Returned by `Dispatch::dispatch` if an actor handles the
+/// message version but not its dispatch type (i.e. a request
+/// for a msg the actor only implements `HandleSend` for).
+#[derive(Debug, Clone)]
+pub struct UnsupportedDispatchType {
+    /// Actor type name and version (i.e. `ActorX/v1.0`)
+    pub actor: String,
+    /// The message version the actor received
+    pub version: String,
+    pub dispatch_type: DispatchType,
+}
+
+impl UnsupportedDispatchType {
+    pub fn new(actor: String, version: String, dispatch_type: DispatchType) -> Self {
+        Self {
+            actor,
+            version,
+            dispatch_type,
+        }
+    }
+}
+
+impl fmt::Display for UnsupportedDispatchType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} doesn't support {} msgs for msg version {:?}",
+            &self.actor,
+            self.dispatch_type.as_str(),
+            &self.version
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedDispatchType {}
+
+/// Returns true if the actor can't handle the msg
+/// (retrying it would fail the same way).
+pub(crate) fn is_unsupported(err: &anyhow::Error) -> bool {
+    err.is::<UnsupportedVersion>() || err.is::<UnsupportedDispatchType>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_display() {
+        let err = UnsupportedVersion::new(
+            "ActorX/v1.0".to_string(),
+            "v2.0".to_string(),
+            vec!["ActorXMsg/v1.0".to_string(), "CountMsg/v1.0".to_string()],
+        );
+        assert_eq!(
+            err.to_string(),
+            "ActorX/v1.0 doesn't support msg version \"v2.0\", supported dispatch types: [ActorXMsg/v1.0, CountMsg/v1.0]"
+        );
+
+        let err = UnsupportedDispatchType::new(
+            "ActorX/v1.0".to_string(),
+            "v1.0".to_string(),
+            DispatchType::Request,
+        );
+        assert_eq!(
+            err.to_string(),
+            "ActorX/v1.0 doesn't support request msgs for msg version \"v1.0\""
+        );
+    }
+
+    #[test]
+    fn test_is_unsupported() {
+        let version = UnsupportedVersion::new("ActorX/v1.0".into(), "v2.0".into(), vec![]);
+        let dispatch_type =
+            UnsupportedDispatchType::new("ActorX/v1.0".into(), "v1.0".into(), DispatchType::Send);
+        assert!(is_unsupported(&version.into()));
+        assert!(is_unsupported(&dispatch_type.into()));
+        assert!(!is_unsupported(&anyhow!("handler failed")));
+    }
+}
@@ -0,0 +1,127 @@
+//! Agent health answered by the broker.
+//!
+//! Health checks are answered from the broker task so a busy or
+//! stuck worker can't delay them. Workers run `Actor::health`
+//! every `ACTOR_HEALTH_INTERVAL` and record the result here.
+
+use crate::actor::{ActorSender, HealthStatus};
+use crate::common;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// How often each worker runs `Actor::health`.
+pub(crate) const ACTOR_HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Health of a running agent shared by its broker and workers.
+pub(crate) struct AgentHealth {
+    // actor type name and version
+    actor: String,
+    // unique id of this agent instance
+    instance_id: String,
+    started_at: Instant,
+    // epoch secs of the last handled msg (zero if none)
+    last_handled_at: AtomicI64,
+    // false once any broker subscription stops running
+    broker_alive: AtomicBool,
+    // number of actor restarts after a handler panic
+    restarts: AtomicU64,
+    // last `Actor::health` error of each worker
+    actor_errors: Mutex<BTreeMap<usize, String>>,
+}
+
+impl AgentHealth {
+    pub(crate) fn new(actor: String) -> Self {
+        Self {
+            actor,
+            instance_id: common::new_id_as_string(),
+            started_at: Instant::now(),
+            last_handled_at: AtomicI64::new(0),
+            broker_alive: AtomicBool::new(false),
+            restarts: AtomicU64::new(0),
+            actor_errors: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub(crate) fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    pub(crate) fn set_broker_alive(&self, alive: bool) {
+        self.broker_alive.store(alive, Ordering::SeqCst);
+    }
+
+    pub(crate) fn handled(&self) {
+        self.last_handled_at
+            .store(common::epoch_as_secs(), Ordering::SeqCst);
+    }
+
+    /// Returns the number of restarts.
+    pub(crate) fn restarted(&self) -> u64 {
+        self.restarts.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Record the result of a worker's `Actor::health` call.
+    pub(crate) fn set_actor_error(&self, worker: usize, error: Option<String>) {
+        let mut actor_errors = self.actor_errors.lock().unwrap();
+        match error {
+            Some(error) => actor_errors.insert(worker, error),
+            None => actor_errors.remove(&worker),
+        };
+    }
+
+    /// Returns the current status of the agent.
+    pub(crate) fn status(&self, mailbox: &ActorSender) -> HealthStatus {
+        let broker_alive = self.broker_alive.load(Ordering::SeqCst);
+        let actor_error = self.actor_errors.lock().unwrap().values().next().cloned();
+        let last_handled_at = match self.last_handled_at.load(Ordering::SeqCst) {
+            0 => None,
+            epoch => Some(epoch),
+        };
+        HealthStatus {
+            actor: self.actor.clone(),
+            instance_id: self.instance_id.clone(),
+            healthy: broker_alive && actor_error.is_none(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            mailbox_len: mailbox.len(),
+            last_handled_at,
+            broker_alive,
+            restarts: self.restarts.load(Ordering::SeqCst),
+            actor_error,
+            mailbox_overflow: mailbox.overflow_counts(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overflow::OverflowPolicy;
+    use crate::priority;
+
+    #[test]
+    fn test_status() {
+        let (sender, _receiver) = priority::mailbox(None, OverflowPolicy::Block);
+        let health = AgentHealth::new("ActorX/v1.0".to_string());
+        assert!(!health.status(&sender).healthy);
+
+        health.set_broker_alive(true);
+        let status = health.status(&sender);
+        assert!(status.healthy);
+        assert_eq!(status.instance_id, health.instance_id());
+        assert_eq!(status.last_handled_at, None);
+
+        health.set_actor_error(1, Some("redis is down".to_string()));
+        health.set_actor_error(0, None);
+        let status = health.status(&sender);
+        assert!(!status.healthy);
+        assert_eq!(status.actor_error.as_deref(), Some("redis is down"));
+
+        health.set_actor_error(1, None);
+        health.handled();
+        let status = health.status(&sender);
+        assert!(status.healthy);
+        assert!(status.last_handled_at.is_some());
+    }
+}
@@ -0,0 +1,531 @@
+//! Durable mailboxes backed by NATS JetStream.
+//!
+//! Send msgs to `SubscribeType::Durable` actors are stored in a
+//! stream per actor (`HOLLYWOOD_{system}_{actor}`) that captures the
+//! durable subjects of every actor version. Each actor version reads
+//! its own subjects through a durable push consumer filtered on its
+//! version and the agents of a version share it with a queue group
+//! on the deliver subject.
+//!
+//! The nats asynk connection doesn't wrap the JetStream API so the
+//! stream and consumer are managed with requests to `$JS.API.*`.
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use nats::asynk::Connection;
+use serde::Deserialize;
+use serde_json::json;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{interval_at, Instant};
+
+/// How long to wait for a JetStream API response.
+const API_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// DurableOpts defines the JetStream consumer an agent
+/// reads the durable mailbox of its actor version with.
+///
+/// Start options only apply when the agent creates the
+/// consumer. An existing consumer resumes where it stopped.
+#[derive(Clone, Debug)]
+pub struct DurableOpts {
+    max_deliver: u32,
+    ack_wait: Duration,
+    start: Start,
+}
+
+/// Where a new consumer starts reading the stream.
+#[derive(Clone, Debug)]
+enum Start {
+    // msgs stored after the consumer is created
+    New,
+    Sequence(u64),
+    Time(SystemTime),
+}
+
+impl Default for DurableOpts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DurableOpts {
+    /// Deliver each msg at most 5 times and wait 30s
+    /// for an ack before redelivering it.
+    pub fn new() -> Self {
+        Self {
+            max_deliver: 5,
+            ack_wait: Duration::from_secs(30),
+            start: Start::New,
+        }
+    }
+
+    /// Deliver each msg at most `max_deliver` times. This counts
+    /// redeliveries after handler errors and after the ack wait
+    /// expired (i.e. the agent stopped while handling it).
+    pub fn with_max_deliver(mut self, max_deliver: u32) -> Self {
+        self.max_deliver = max_deliver.max(1);
+        self
+    }
+
+    /// How long JetStream waits for an ack before it redelivers
+    /// a msg. Workers tell JetStream a msg is still in progress
+    /// every half of the ack wait while its handler runs.
+    pub fn with_ack_wait(mut self, ack_wait: Duration) -> Self {
+        self.ack_wait = ack_wait;
+        self
+    }
+
+    /// Start a new consumer at stream sequence `seq`
+    /// (i.e. to replay msgs for a new actor version).
+    pub fn with_start_sequence(mut self, seq: u64) -> Self {
+        self.start = Start::Sequence(seq);
+        self
+    }
+
+    /// Start a new consumer with the msgs stored since `time`.
+    pub fn with_start_time(mut self, time: SystemTime) -> Self {
+        self.start = Start::Time(time);
+        self
+    }
+
+    pub(crate) fn max_deliver(&self) -> u32 {
+        self.max_deliver
+    }
+
+    /// How often to send in-progress acks for a msg
+    /// that's being handled (at least every 1ms).
+    pub(crate) fn progress_interval(&self) -> Duration {
+        (self.ack_wait / 2).max(Duration::from_millis(1))
+    }
+}
+
+/// How a worker settles a durable msg once it's handled.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Settle {
+    Ack,
+    // redeliver after a delay
+    Nak(Duration),
+    // don't redeliver
+    Term,
+    // reset the ack wait
+    InProgress,
+}
+
+impl Settle {
+    fn body(&self) -> Vec<u8> {
+        match self {
+            Settle::Ack => b"+ACK".to_vec(),
+            Settle::Nak(delay) => format!("-NAK {{\"delay\":{}}}", delay.as_nanos()).into_bytes(),
+            Settle::Term => b"+TERM".to_vec(),
+            Settle::InProgress => b"+WPI".to_vec(),
+        }
+    }
+}
+
+/// Publish the ack, nak or term for a msg to its ack subject.
+pub(crate) async fn settle(nats: &Connection, ack_subject: &str, settle: Settle) -> Result<()> {
+    nats.publish(ack_subject, settle.body()).await?;
+    Ok(())
+}
+
+/// Wait for `handling` while telling JetStream the msg is still
+/// in progress every `interval`, so msgs aren't redelivered to
+/// another agent while a slow handler is still running.
+pub(crate) async fn in_progress<F: Future>(
+    nats: &Connection,
+    ack_subject: Option<&str>,
+    interval: Duration,
+    handling: F,
+) -> F::Output {
+    let ack_subject = match ack_subject {
+        Some(ack_subject) => ack_subject,
+        None => return handling.await,
+    };
+    tokio::pin!(handling);
+    let mut ticks = interval_at(Instant::now() + interval, interval);
+    loop {
+        tokio::select! {
+            output = &mut handling => return output,
+            _ = ticks.tick() => {
+                if let Err(err) = settle(nats, ack_subject, Settle::InProgress).await {
+                    warn!("sending in progress ack to {}: {:?}", ack_subject, &err);
+                }
+            }
+        }
+    }
+}
+
+/// Returns how many times JetStream delivered a msg from its
+/// ack subject (`$JS.ACK.<stream>.<consumer>.<delivered>...`
+/// or the newer form with a domain and account hash).
+pub(crate) fn delivered(ack_subject: &str) -> Option<u32> {
+    let tokens = ack_subject.split('.').collect::<Vec<_>>();
+    let index = match tokens.len() {
+        9 => 4,
+        len if len >= 11 => 6,
+        _ => return None,
+    };
+    if tokens[0] != "$JS" || tokens[1] != "ACK" {
+        return None;
+    }
+    tokens[index].parse().ok()
+}
+
+/// Replace the characters stream names and subject
+/// tokens can't contain.
+fn token(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '.' | '*' | '>' | '/' | '\\' | ' ' | '\t' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Split `Name/version` into its name and version.
+fn split_version(type_name_version: &str) -> (&str, &str) {
+    type_name_version
+        .split_once('/')
+        .unwrap_or((type_name_version, "unknown"))
+}
+
+/// Subject clients send durable msgs to, i.e.
+/// `hollywood.durable.prod.MyActor.v1_0.MyMsg.v1_0`
+pub(crate) fn durable_subject(system_name: &str, actor_type: &str, msg_type: &str) -> String {
+    let (actor_name, actor_version) = split_version(actor_type);
+    let (msg_name, msg_version) = split_version(msg_type);
+    format!(
+        "hollywood.durable.{}.{}.{}.{}.{}",
+        token(system_name),
+        token(actor_name),
+        token(actor_version),
+        token(msg_name),
+        token(msg_version)
+    )
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiError {
+    code: u16,
+    #[serde(default)]
+    description: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiResponse {
+    #[serde(default)]
+    error: Option<ApiError>,
+    #[serde(default)]
+    config: Option<serde_json::Value>,
+    // set in publish acks
+    #[serde(default)]
+    seq: Option<u64>,
+}
+
+async fn api(nats: &Connection, subject: &str, req: serde_json::Value) -> Result<ApiResponse> {
+    let resp = nats
+        .request_timeout(subject, serde_json::to_vec(&req)?, API_TIMEOUT)
+        .await
+        .map_err(|err| match err.kind() {
+            ErrorKind::NotFound => anyhow!("JetStream isn't enabled on the nats server"),
+            _ => anyhow!("JetStream api {}: {}", subject, &err),
+        })?;
+    Ok(serde_json::from_slice(&resp.data)?)
+}
+
+/// Publish a msg to a durable mailbox and wait
+/// until JetStream stored it.
+pub(crate) async fn publish(nats: &Connection, subject: &str, msg: Vec<u8>) -> Result<u64> {
+    let resp = nats
+        .request_timeout(subject, msg, API_TIMEOUT)
+        .await
+        .map_err(|err| match err.kind() {
+            ErrorKind::NotFound => anyhow!(
+                "durable mailbox {} has no JetStream stream (start an agent of the actor first)",
+                subject
+            ),
+            _ => err.into(),
+        })?;
+    let resp: ApiResponse = serde_json::from_slice(&resp.data)?;
+    match (resp.error, resp.seq) {
+        (Some(err), _) => Err(anyhow!("storing durable msg: {}", &err.description)),
+        (None, Some(seq)) => Ok(seq),
+        (None, None) => Err(anyhow!("storing durable msg: unexpected response")),
+    }
+}
+
+/// JetStream stream and consumer of an actor version.
+pub(crate) struct DurableMailbox {
+    stream: String,
+    // captures the durable subjects of every actor version
+    subjects: String,
+    // durable subjects of this actor version only
+    filter_subject: String,
+    consumer: String,
+    deliver_subject: String,
+}
+
+impl DurableMailbox {
+    pub(crate) fn new(system_name: &str, actor_type: &str) -> Self {
+        let (actor_name, actor_version) = split_version(actor_type);
+        let (system_name, actor_name, actor_version) =
+            (token(system_name), token(actor_name), token(actor_version));
+        Self {
+            stream: format!("HOLLYWOOD_{}_{}", &system_name, &actor_name),
+            subjects: format!("hollywood.durable.{}.{}.>", &system_name, &actor_name),
+            filter_subject: format!(
+                "hollywood.durable.{}.{}.{}.>",
+                &system_name, &actor_name, &actor_version
+            ),
+            consumer: format!("{}_{}", &actor_name, &actor_version),
+            deliver_subject: format!(
+                "hollywood.deliver.{}.{}.{}",
+                &system_name, &actor_name, &actor_version
+            ),
+        }
+    }
+
+    /// Create the stream and consumer if they don't exist yet.
+    /// Returns the deliver subject and queue group to read from.
+    pub(crate) async fn open(
+        &self,
+        nats: &Connection,
+        opts: &DurableOpts,
+    ) -> Result<(String, String)> {
+        let info = api(
+            nats,
+            &format!("$JS.API.STREAM.INFO.{}", &self.stream),
+            json!({}),
+        )
+        .await?;
+        match info.error {
+            Some(err) if err.code == 404 => {
+                info!("creating JetStream stream {}", &self.stream);
+                let config = json!({
+                    "name": &self.stream,
+                    "subjects": [&self.subjects],
+                    "retention": "limits",
+                    "storage": "file",
+                    "discard": "old",
+                    "num_replicas": 1,
+                });
+                let created = api(
+                    nats,
+                    &format!("$JS.API.STREAM.CREATE.{}", &self.stream),
+                    config,
+                )
+                .await?;
+                if let Some(err) = created.error {
+                    return Err(anyhow!(
+                        "creating stream {}: {}",
+                        &self.stream,
+                        &err.description
+                    ));
+                }
+            }
+            Some(err) => {
+                return Err(anyhow!(
+                    "stream {} info: {}",
+                    &self.stream,
+                    &err.description
+                ));
+            }
+            None => {}
+        }
+
+        let subject = format!("$JS.API.CONSUMER.INFO.{}.{}", &self.stream, &self.consumer);
+        let mut consumer = api(nats, &subject, json!({})).await?;
+        if matches!(&consumer.error, Some(err) if err.code == 404) {
+            info!(
+                "creating JetStream consumer {} on stream {}",
+                &self.consumer, &self.stream
+            );
+            let subject = format!(
+                "$JS.API.CONSUMER.DURABLE.CREATE.{}.{}",
+                &self.stream, &self.consumer
+            );
+            consumer = api(nats, &subject, self.consumer_config(opts)).await?;
+        }
+        if let Some(err) = consumer.error {
+            return Err(anyhow!("consumer {}: {}", &self.consumer, &err.description));
+        }
+        let config = consumer.config.unwrap_or_default();
+        // consumers reading other versions' msgs would handle
+        // (or dead-letter) them during a rollout
+        if let Some(filter_subject) = config["filter_subject"].as_str() {
+            if filter_subject != self.filter_subject {
+                return Err(anyhow!(
+                    "consumer {} filters {:?} instead of {:?} (delete it so the agent recreates it)",
+                    &self.consumer,
+                    filter_subject,
+                    &self.filter_subject
+                ));
+            }
+        }
+        let deliver_subject = config["deliver_subject"]
+            .as_str()
+            .unwrap_or(&self.deliver_subject)
+            .to_string();
+        let deliver_group = config["deliver_group"]
+            .as_str()
+            .unwrap_or(&self.consumer)
+            .to_string();
+        Ok((deliver_subject, deliver_group))
+    }
+
+    fn consumer_config(&self, opts: &DurableOpts) -> serde_json::Value {
+        let mut config = json!({
+            "durable_name": &self.consumer,
+            "deliver_subject": &self.deliver_subject,
+            "deliver_group": &self.consumer,
+            "deliver_policy": "new",
+            "ack_policy": "explicit",
+            "ack_wait": opts.ack_wait.as_nanos() as u64,
+            "max_deliver": opts.max_deliver,
+            "filter_subject": &self.filter_subject,
+            "replay_policy": "instant",
+        });
+        match opts.start {
+            Start::New => {}
+            Start::Sequence(seq) => {
+                config["deliver_policy"] = json!("by_start_sequence");
+                config["opt_start_seq"] = json!(seq);
+            }
+            Start::Time(time) => {
+                config["deliver_policy"] = json!("by_start_time");
+                config["opt_start_time"] = json!(rfc3339(time));
+            }
+        }
+        json!({
+            "stream_name": &self.stream,
+            "config": config,
+        })
+    }
+}
+
+/// Format a time as an RFC 3339 UTC timestamp.
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86_400) as i64, secs % 86_400);
+    // civil from days (http://howardhinnant.github.io/date_algorithms.html)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_nanos()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delivered() {
+        assert_eq!(
+            delivered("$JS.ACK.HOLLYWOOD_prod_MyActor.MyActor_v1_0.3.42.40.1700000000000000000.0"),
+            Some(3)
+        );
+        assert_eq!(
+            delivered("$JS.ACK.hub.ACCHASH.HOLLYWOOD_prod_MyActor.MyActor_v1_0.2.42.40.1700000000000000000.0.abc"),
+            Some(2)
+        );
+        assert_eq!(delivered("_INBOX.abc"), None);
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(
+            durable_subject("prod", "MyActor/v1.0", "MyMsg/v1.0"),
+            "hollywood.durable.prod.MyActor.v1_0.MyMsg.v1_0"
+        );
+        let mailbox = DurableMailbox::new("prod", "MyActor/v1.0");
+        assert_eq!(&mailbox.stream, "HOLLYWOOD_prod_MyActor");
+        assert_eq!(&mailbox.subjects, "hollywood.durable.prod.MyActor.>");
+        assert_eq!(&mailbox.consumer, "MyActor_v1_0");
+        assert_eq!(
+            &mailbox.filter_subject,
+            "hollywood.durable.prod.MyActor.v1_0.>"
+        );
+
+        // the stream captures every actor version but each
+        // version's consumer only reads its own msgs
+        let v1 = durable_subject("prod", "MyActor/v1.0", "MyMsg/v1.0");
+        let v2 = durable_subject("prod", "MyActor/v2.0", "MyMsg/v1.0");
+        assert!(crate::subject::matches(&mailbox.subjects, &v1));
+        assert!(crate::subject::matches(&mailbox.subjects, &v2));
+        assert!(crate::subject::matches(&mailbox.filter_subject, &v1));
+        assert!(!crate::subject::matches(&mailbox.filter_subject, &v2));
+    }
+
+    #[test]
+    fn test_settle_body() {
+        assert_eq!(Settle::Ack.body(), b"+ACK".to_vec());
+        assert_eq!(
+            Settle::Nak(Duration::from_millis(250)).body(),
+            b"-NAK {\"delay\":250000000}".to_vec()
+        );
+        assert_eq!(Settle::InProgress.body(), b"+WPI".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_in_progress() {
+        let opts = DurableOpts::new().with_ack_wait(Duration::from_millis(100));
+        assert_eq!(opts.progress_interval(), Duration::from_millis(50));
+        assert_eq!(
+            DurableOpts::new()
+                .with_ack_wait(Duration::ZERO)
+                .progress_interval(),
+            Duration::from_millis(1)
+        );
+
+        let stub = crate::testing::NatsStub::start();
+        let nats = nats::asynk::connect(&stub.uri).await.unwrap();
+        let handler = tokio::time::sleep(Duration::from_millis(130));
+
+        // handlers running longer than the ack wait keep the msg
+        let output = in_progress(&nats, Some("ack"), opts.progress_interval(), async {
+            handler.await;
+            42
+        })
+        .await;
+        assert_eq!(output, 42);
+        nats.flush().await.unwrap();
+        let acks = stub.published("ack");
+        assert_eq!(acks.len(), 2);
+        assert!(acks.iter().all(|ack| ack == b"+WPI"));
+
+        // msgs without an ack subject aren't durable
+        let output = in_progress(&nats, None, Duration::from_millis(1), async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            7
+        })
+        .await;
+        assert_eq!(output, 7);
+        nats.flush().await.unwrap();
+        assert_eq!(stub.published("ack").len(), 2);
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000000000Z");
+        let time = UNIX_EPOCH + Duration::new(1_709_210_096, 5);
+        assert_eq!(rfc3339(time), "2024-02-29T12:34:56.000000005Z");
+    }
+}
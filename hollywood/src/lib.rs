@@ -1,20 +1,89 @@
 mod actor;
+mod agent;
 mod broker;
 mod client;
 mod common;
+mod ctx;
+mod dead_letter;
+mod deadline;
+mod dedup;
+mod error;
+mod health;
+mod jetstream;
+mod metadata;
+mod overflow;
+mod priority;
+mod retry;
+mod scheduler;
+mod stream;
+mod subject;
+mod supervisor;
+mod trace;
+
+#[cfg(test)]
+mod testing;
+
+// lets the `Hollywood` derive be used in unit tests
+#[cfg(test)]
+extern crate self as hollywood;
 
 /// Types for defining and running Actors.
 pub use actor::{
-    run, Actor, ActorMailbox, Dispatch, DispatchResponse, DispatchType, Handle, Msg, RunOpts,
-    SubscribeType,
+    run, Ack, Actor, ActorMailbox, Dispatch, DispatchResponse, DispatchType, Handle, HandleRequest,
+    HandleSend, HandleStream, HandleSubscribe, HandleWithCtx, HealthStatus, Msg, RequestMsg,
+    RunOpts, SubscribeType,
 };
 
+/// Context passed to `HandleWithCtx` handlers.
+pub use ctx::Ctx;
+
+/// Errors returned by actor dispatch.
+pub use error::{UnsupportedDispatchType, UnsupportedVersion};
+
+/// Request deadlines visible to actor handlers.
+pub use deadline::{deadline, time_remaining};
+
+/// Envelope metadata sent along with msgs.
+pub use metadata::{metadata, Metadata};
+
+/// Skip msgs with ids an agent already handled.
+pub use dedup::DedupOpts;
+
+/// Messages that failed or couldn't be delivered.
+pub use dead_letter::{DeadLetter, DeadLetters};
+
+/// Priority of msgs in the agent mailbox.
+pub use priority::Priority;
+
+/// What agents do with msgs once their mailbox is full.
+pub use overflow::{MailboxOverflow, OverflowPolicy};
+
+/// Redelivery of msgs after send/subscribe handler errors.
+pub use retry::RetryPolicy;
+
+/// Export tracing spans to an OpenTelemetry collector.
+#[cfg(feature = "opentelemetry")]
+pub use trace::{init_opentelemetry, OpenTelemetry};
+
+/// Supervision strategies for actor handler panics.
+pub use supervisor::SupervisionStrategy;
+
+/// Streaming responses to a single request.
+pub use stream::{ResponseStream, StreamSender};
+
+/// Durable mailboxes backed by NATS JetStream.
+pub use jetstream::DurableOpts;
+
+/// Timers for sending an actor delayed or periodic messages.
+pub use scheduler::{Scheduler, TimerHandle};
+
 pub mod prelude {
     pub mod actor {
         #[allow(unused_imports)]
         pub use super::super::{
-            async_trait, run, Actor, Dispatch, DispatchResponse, DispatchType, Handle, Msg, Result,
-            SubscribeType,
+            async_trait, run, Ack, Actor, Ctx, Dispatch, DispatchResponse, DispatchType, Handle,
+            HandleRequest, HandleSend, HandleStream, HandleSubscribe, HandleWithCtx, Msg,
+            RequestMsg, Result, RetryPolicy, Scheduler, StreamSender, SubscribeType, TimerHandle,
         };
     }
 }
@@ -29,6 +98,9 @@ pub mod config;
 // Hollywood env vars
 pub mod env;
 
+/// Agent and client metrics.
+pub mod metrics;
+
 /// Defines common types so we can re-use
 /// them in Actor implementations.
 pub use anyhow::Result;
@@ -0,0 +1,189 @@
+use crate::common;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+
+/// Content type of msgs encoded by `Msg::into_bytes`.
+pub(crate) const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// Metadata sent in the envelope along with a msg. Clients
+/// fill in the standard fields that aren't set when they
+/// send the msg.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// Epoch millis when the msg was sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<i64>,
+    /// Type name and version of the actor that sent
+    /// the msg (None if it wasn't sent by an actor)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    /// Id of the agent instance that sent the msg
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_instance: Option<String>,
+    /// Id shared by every msg caused by the same original msg
+    /// (defaults to the msg id of the original msg)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// Msg id of the msg the sender was handling
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub causation_id: Option<String>,
+    /// Encoding of the msg (defaults to `application/json`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Application defined headers
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// Id to send the msg with instead of a new id. Reuse the
+    /// id when retrying a msg so agents with a dedup window skip
+    /// it (or return the cached response) if it was handled.
+    /// Sent as the envelope id instead of in the metadata.
+    #[serde(skip)]
+    pub msg_id: Option<String>,
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_header(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn with_correlation_id(mut self, correlation_id: impl Into<String>) -> Self {
+        self.correlation_id = Some(correlation_id.into());
+        self
+    }
+
+    pub fn with_msg_id(mut self, msg_id: impl Into<String>) -> Self {
+        self.msg_id = Some(msg_id.into());
+        self
+    }
+
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    /// Returns the value of a header.
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key).map(String::as_str)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// The msg an actor handler is running for.
+#[derive(Clone)]
+pub(crate) struct Handling {
+    pub msg_id: String,
+    pub meta: Metadata,
+    // actor type name/version and agent instance id
+    pub actor: String,
+    pub instance: String,
+}
+
+tokio::task_local! {
+    static HANDLING: Handling;
+}
+
+/// Returns the metadata of the msg the current actor
+/// handler is running for or None outside of a handler.
+pub fn metadata() -> Option<Metadata> {
+    HANDLING.try_with(|handling| handling.meta.clone()).ok()
+}
+
+/// Run `f` with `handling` as the current msg.
+pub(crate) fn scope<F: Future>(handling: Handling, f: F) -> impl Future<Output = F::Output> {
+    HANDLING.scope(handling, f)
+}
+
+/// Fill in the standard fields of a msg about to be sent.
+/// Msgs sent from a handler are caused by the msg it's
+/// handling and continue its correlation id.
+pub(crate) fn outgoing(mut meta: Metadata, msg_id: &str) -> Metadata {
+    let _ = HANDLING.try_with(|handling| {
+        meta.sender.get_or_insert_with(|| handling.actor.clone());
+        meta.sender_instance
+            .get_or_insert_with(|| handling.instance.clone());
+        meta.causation_id
+            .get_or_insert_with(|| handling.msg_id.clone());
+        meta.correlation_id.get_or_insert_with(|| {
+            handling
+                .meta
+                .correlation_id
+                .clone()
+                .unwrap_or_else(|| handling.msg_id.clone())
+        });
+    });
+    meta.correlation_id
+        .get_or_insert_with(|| msg_id.to_string());
+    meta.content_type
+        .get_or_insert_with(|| JSON_CONTENT_TYPE.to_string());
+    meta.sent_at = Some(common::epoch_as_millis());
+    meta
+}
+
+/// Returns the id to send a msg with
+/// (`Metadata::msg_id` or a new id).
+pub(crate) fn msg_id(meta: &mut Metadata) -> String {
+    meta.msg_id.take().unwrap_or_else(common::new_id_as_string)
+}
+
+/// Returns the metadata for an agent's response to a request.
+pub(crate) fn response(handling: &Handling) -> Metadata {
+    Metadata {
+        sent_at: Some(common::epoch_as_millis()),
+        sender: Some(handling.actor.clone()),
+        sender_instance: Some(handling.instance.clone()),
+        correlation_id: Some(
+            handling
+                .meta
+                .correlation_id
+                .clone()
+                .unwrap_or_else(|| handling.msg_id.clone()),
+        ),
+        causation_id: Some(handling.msg_id.clone()),
+        content_type: Some(JSON_CONTENT_TYPE.to_string()),
+        headers: BTreeMap::new(),
+        msg_id: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_outgoing_metadata() {
+        let root = outgoing(Metadata::new().with_header("tenant", "a"), "msg-1");
+        assert_eq!(root.correlation_id.as_deref(), Some("msg-1"));
+        assert_eq!(root.causation_id, None);
+        assert_eq!(root.sender, None);
+        assert_eq!(root.content_type.as_deref(), Some(JSON_CONTENT_TYPE));
+        assert_eq!(root.header("tenant"), Some("a"));
+        assert!(root.sent_at.is_some());
+
+        let handling = Handling {
+            msg_id: "msg-1".to_string(),
+            meta: root,
+            actor: "ActorX/v1.0".to_string(),
+            instance: "instance-1".to_string(),
+        };
+        let (child, resp) = scope(handling.clone(), async {
+            assert_eq!(metadata(), Some(handling.meta.clone()));
+            (outgoing(Metadata::new(), "msg-2"), response(&handling))
+        })
+        .await;
+        assert_eq!(child.correlation_id.as_deref(), Some("msg-1"));
+        assert_eq!(child.causation_id.as_deref(), Some("msg-1"));
+        assert_eq!(child.sender.as_deref(), Some("ActorX/v1.0"));
+        assert_eq!(child.sender_instance.as_deref(), Some("instance-1"));
+        assert_eq!(resp.causation_id.as_deref(), Some("msg-1"));
+        assert_eq!(metadata(), None);
+    }
+}
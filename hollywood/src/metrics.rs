@@ -0,0 +1,396 @@
+//! Metrics recorded by agents and clients.
+//!
+//! Install a recorder with `set_recorder` before running an
+//! agent or creating a client. `PrometheusRecorder` keeps metrics
+//! in memory and renders them in the Prometheus text format, which
+//! `RunOpts::with_metrics_endpoint` serves over http on `/metrics`.
+
+use crate::actor::DispatchType;
+use anyhow::{anyhow, Result};
+use log::{debug, error, info};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+/// Msgs read from nats and forwarded to the actor mailbox
+pub const MSGS_RECEIVED: &str = "hollywood_msgs_received_total";
+/// Msgs the actor handled without an error
+pub const MSGS_HANDLED: &str = "hollywood_msgs_handled_total";
+/// Msgs the actor returned an error for or panicked on
+pub const MSGS_FAILED: &str = "hollywood_msgs_failed_total";
+/// Msgs dropped before the actor handled them (see the `reason` label)
+pub const MSGS_DROPPED: &str = "hollywood_msgs_dropped_total";
+/// Time spent in actor handlers
+pub const HANDLER_DURATION: &str = "hollywood_handler_duration_seconds";
+/// Number of unprocessed msgs in the agent mailbox
+pub const MAILBOX_DEPTH: &str = "hollywood_mailbox_depth";
+/// Failed attempts to publish replies and dead letters to nats
+pub const NATS_PUBLISH_ERRORS: &str = "hollywood_nats_publish_errors_total";
+/// Time clients spent waiting on request responses
+pub const CLIENT_REQUEST_DURATION: &str = "hollywood_client_request_duration_seconds";
+/// Client requests that timed out
+pub const CLIENT_REQUEST_TIMEOUTS: &str = "hollywood_client_request_timeouts_total";
+
+/// Metric labels as (name, value) pairs.
+pub type Labels<'a> = &'a [(&'static str, &'a str)];
+
+/// MetricsRecorder receives every metric hollywood records.
+/// Implement this to forward metrics to another metrics library.
+pub trait MetricsRecorder: Send + Sync {
+    fn increment_counter(&self, name: &'static str, labels: Labels, value: u64);
+    fn set_gauge(&self, name: &'static str, labels: Labels, value: f64);
+    fn record_histogram(&self, name: &'static str, labels: Labels, value: f64);
+
+    // Returns the metrics in the Prometheus text format
+    // or None if this recorder can't render them.
+    fn render(&self) -> Option<String> {
+        None
+    }
+}
+
+struct NoopRecorder;
+
+impl MetricsRecorder for NoopRecorder {
+    fn increment_counter(&self, _: &'static str, _: Labels, _: u64) {}
+    fn set_gauge(&self, _: &'static str, _: Labels, _: f64) {}
+    fn record_histogram(&self, _: &'static str, _: Labels, _: f64) {}
+}
+
+static RECORDER: OnceLock<Arc<dyn MetricsRecorder>> = OnceLock::new();
+
+/// Install the global metrics recorder. Returns an
+/// error if a recorder was already installed.
+pub fn set_recorder(recorder: Arc<dyn MetricsRecorder>) -> Result<()> {
+    RECORDER
+        .set(recorder)
+        .map_err(|_| anyhow!("a metrics recorder is already installed"))
+}
+
+/// Returns the global metrics recorder. Metrics are
+/// discarded if a recorder wasn't installed.
+pub fn recorder() -> &'static dyn MetricsRecorder {
+    match RECORDER.get() {
+        Some(recorder) => recorder.as_ref(),
+        None => &NoopRecorder,
+    }
+}
+
+/// Labels of msgs that aren't dispatched to an actor.
+pub(crate) const UNKNOWN_MSG: (&str, &str) = ("unknown", "unknown");
+
+/// Returns the msg type and version labels of the msg type
+/// an actor dispatches `msg_version` to (`dispatch_types` as
+/// returned by `Dispatch::dispatch_types`) or `UNKNOWN_MSG`.
+/// The msg version comes from the sender so only the versions
+/// an actor dispatches are used as labels.
+pub(crate) fn msg_labels<'a>(
+    dispatch_types: &'a [String],
+    msg_version: &str,
+) -> (&'a str, &'a str) {
+    dispatch_types
+        .iter()
+        .find_map(|dispatch_type| {
+            let (msg_type, version) = dispatch_type.rsplit_once('/')?;
+            (version == msg_version).then_some((msg_type, version))
+        })
+        .unwrap_or(UNKNOWN_MSG)
+}
+
+/// Increment a msg counter for an actor.
+pub(crate) fn count_msg(
+    name: &'static str,
+    actor: &str,
+    dispatch_type: &DispatchType,
+    (msg_type, msg_version): (&str, &str),
+) {
+    let labels = [
+        ("actor", actor),
+        ("dispatch_type", dispatch_type.as_str()),
+        ("msg_type", msg_type),
+        ("msg_version", msg_version),
+    ];
+    recorder().increment_counter(name, &labels, 1);
+}
+
+/// Record how long an actor handler ran.
+pub(crate) fn record_handler_duration(
+    actor: &str,
+    dispatch_type: &DispatchType,
+    (msg_type, msg_version): (&str, &str),
+    elapsed: Duration,
+) {
+    let labels = [
+        ("actor", actor),
+        ("dispatch_type", dispatch_type.as_str()),
+        ("msg_type", msg_type),
+        ("msg_version", msg_version),
+    ];
+    recorder().record_histogram(HANDLER_DURATION, &labels, elapsed.as_secs_f64());
+}
+
+/// Set the mailbox depth gauge for an actor.
+pub(crate) fn set_mailbox_depth(actor: &str, depth: usize) {
+    recorder().set_gauge(MAILBOX_DEPTH, &[("actor", actor)], depth as f64);
+}
+
+/// Increment the dropped msg counter for an actor.
+pub(crate) fn count_dropped(
+    actor: &str,
+    dispatch_type: Option<&DispatchType>,
+    (msg_type, msg_version): (&str, &str),
+    reason: &str,
+) {
+    let labels = [
+        ("actor", actor),
+        (
+            "dispatch_type",
+            dispatch_type.map_or("unknown", DispatchType::as_str),
+        ),
+        ("msg_type", msg_type),
+        ("msg_version", msg_version),
+        ("reason", reason),
+    ];
+    recorder().increment_counter(MSGS_DROPPED, &labels, 1);
+}
+
+/// Increment the nats publish error counter for an actor.
+pub(crate) fn count_publish_error(actor: &str) {
+    recorder().increment_counter(NATS_PUBLISH_ERRORS, &[("actor", actor)], 1);
+}
+
+/// Histogram buckets in seconds.
+const BUCKETS: [f64; 11] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0,
+];
+
+type Series = (&'static str, Vec<(&'static str, String)>);
+
+#[derive(Default)]
+struct Histogram {
+    // cumulative count for each bucket
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// In-memory recorder that renders metrics
+/// in the Prometheus text format.
+#[derive(Default)]
+pub struct PrometheusRecorder {
+    counters: Mutex<BTreeMap<Series, u64>>,
+    gauges: Mutex<BTreeMap<Series, f64>>,
+    histograms: Mutex<BTreeMap<Series, Histogram>>,
+}
+
+fn series(name: &'static str, labels: Labels) -> Series {
+    let labels = labels
+        .iter()
+        .map(|(key, value)| (*key, value.to_string()))
+        .collect();
+    (name, labels)
+}
+
+fn write_labels(out: &mut String, labels: &[(&'static str, String)], extra: Option<(&str, &str)>) {
+    let mut pairs = labels
+        .iter()
+        .map(|(key, value)| (*key, value.as_str()))
+        .chain(extra)
+        .peekable();
+    if pairs.peek().is_none() {
+        return;
+    }
+    out.push('{');
+    for (i, (key, value)) in pairs.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let value = value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let _ = write!(out, "{}=\"{}\"", key, value);
+    }
+    out.push('}');
+}
+
+// Write a `# TYPE` line the first time a metric name shows up
+fn write_type(out: &mut String, last: &mut Option<&'static str>, name: &'static str, kind: &str) {
+    if *last != Some(name) {
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        *last = Some(name);
+    }
+}
+
+impl PrometheusRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl MetricsRecorder for PrometheusRecorder {
+    fn increment_counter(&self, name: &'static str, labels: Labels, value: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters.entry(series(name, labels)).or_default() += value;
+    }
+
+    fn set_gauge(&self, name: &'static str, labels: Labels, value: f64) {
+        let mut gauges = self.gauges.lock().unwrap();
+        gauges.insert(series(name, labels), value);
+    }
+
+    fn record_histogram(&self, name: &'static str, labels: Labels, value: f64) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry(series(name, labels)).or_default();
+        for (i, bound) in BUCKETS.iter().enumerate() {
+            if value <= *bound {
+                histogram.buckets[i] += 1;
+            }
+        }
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+
+    fn render(&self) -> Option<String> {
+        let mut out = String::new();
+        let mut last = None;
+        for ((name, labels), value) in self.counters.lock().unwrap().iter() {
+            write_type(&mut out, &mut last, name, "counter");
+            out.push_str(name);
+            write_labels(&mut out, labels, None);
+            let _ = writeln!(out, " {}", value);
+        }
+        for ((name, labels), value) in self.gauges.lock().unwrap().iter() {
+            write_type(&mut out, &mut last, name, "gauge");
+            out.push_str(name);
+            write_labels(&mut out, labels, None);
+            let _ = writeln!(out, " {}", value);
+        }
+        for ((name, labels), histogram) in self.histograms.lock().unwrap().iter() {
+            write_type(&mut out, &mut last, name, "histogram");
+            for (i, bound) in BUCKETS.iter().enumerate() {
+                let _ = write!(out, "{}_bucket", name);
+                write_labels(&mut out, labels, Some(("le", &bound.to_string())));
+                let _ = writeln!(out, " {}", histogram.buckets[i]);
+            }
+            let _ = write!(out, "{}_bucket", name);
+            write_labels(&mut out, labels, Some(("le", "+Inf")));
+            let _ = writeln!(out, " {}", histogram.count);
+            let _ = write!(out, "{}_sum", name);
+            write_labels(&mut out, labels, None);
+            let _ = writeln!(out, " {}", histogram.sum);
+            let _ = write!(out, "{}_count", name);
+            write_labels(&mut out, labels, None);
+            let _ = writeln!(out, " {}", histogram.count);
+        }
+        Some(out)
+    }
+}
+
+/// Serve the global recorder's metrics on `GET /metrics`. Installs
+/// a `PrometheusRecorder` unless a recorder was already installed.
+pub(crate) async fn serve(addr: SocketAddr) -> Result<JoinHandle<()>> {
+    RECORDER.get_or_init(|| Arc::new(PrometheusRecorder::new()));
+    let listener = TcpListener::bind(addr).await?;
+    info!("serving metrics on http://{}/metrics", &addr);
+    Ok(tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    error!("accepting metrics connection: {:?}", &err);
+                    continue;
+                }
+            };
+            tokio::spawn(async move {
+                if let Err(err) = handle_http(stream).await {
+                    debug!("metrics request from {}: {:?}", &peer, &err);
+                }
+            });
+        }
+    }))
+}
+
+async fn handle_http(mut stream: TcpStream) -> Result<()> {
+    // we only need the request line so ignore the
+    // rest of the request once the headers end
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    let read = async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        Ok::<(), std::io::Error>(())
+    };
+    tokio::time::timeout(Duration::from_secs(5), read).await??;
+
+    let request_line = String::from_utf8_lossy(&request);
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next(), recorder().render()) {
+        (Some("GET"), Some("/metrics"), Some(body)) => ("200 OK", body),
+        (Some("GET"), Some("/metrics"), None) => {
+            error!("the installed metrics recorder can't render metrics");
+            ("501 Not Implemented", String::new())
+        }
+        _ => ("404 Not Found", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_render() {
+        let recorder = PrometheusRecorder::new();
+        let labels = [("actor", "ActorX/v1.0"), ("dispatch_type", "send")];
+        recorder.increment_counter(MSGS_HANDLED, &labels, 1);
+        recorder.increment_counter(MSGS_HANDLED, &labels, 2);
+        recorder.set_gauge(MAILBOX_DEPTH, &[("actor", "Actor\"X\"")], 4.0);
+        recorder.record_histogram(HANDLER_DURATION, &labels[..1], 0.02);
+
+        let out = recorder.render().unwrap();
+        assert!(out.contains("# TYPE hollywood_msgs_handled_total counter\n"));
+        assert!(out.contains(
+            "hollywood_msgs_handled_total{actor=\"ActorX/v1.0\",dispatch_type=\"send\"} 3\n"
+        ));
+        assert!(out.contains("hollywood_mailbox_depth{actor=\"Actor\\\"X\\\"\"} 4\n"));
+        assert!(out.contains(
+            "hollywood_handler_duration_seconds_bucket{actor=\"ActorX/v1.0\",le=\"0.01\"} 0\n"
+        ));
+        assert!(out.contains(
+            "hollywood_handler_duration_seconds_bucket{actor=\"ActorX/v1.0\",le=\"0.025\"} 1\n"
+        ));
+        assert!(out.contains(
+            "hollywood_handler_duration_seconds_bucket{actor=\"ActorX/v1.0\",le=\"+Inf\"} 1\n"
+        ));
+        assert!(out.contains("hollywood_handler_duration_seconds_count{actor=\"ActorX/v1.0\"} 1\n"));
+    }
+
+    #[test]
+    fn test_msg_labels() {
+        let dispatch_types = vec!["ActorXMsg/v1.0".to_string(), "TopicMsg/v2.0".to_string()];
+        assert_eq!(msg_labels(&dispatch_types, "v1.0"), ("ActorXMsg", "v1.0"));
+        assert_eq!(msg_labels(&dispatch_types, "v2.0"), ("TopicMsg", "v2.0"));
+        // versions sent by clients never become labels
+        assert_eq!(msg_labels(&dispatch_types, "v3.0"), UNKNOWN_MSG);
+        assert_eq!(msg_labels(&dispatch_types, "v1.0\"} 1\n"), UNKNOWN_MSG);
+    }
+}
@@ -0,0 +1,64 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// OverflowPolicy defines what an agent does with msgs read
+/// from nats once its mailbox reached `actor_mailbox_max_size`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Stop reading from nats until the mailbox has room.
+    #[default]
+    Block,
+
+    /// Reply to requests with a busy error and
+    /// dead-letter send/publish msgs.
+    Reject,
+
+    /// Drop the oldest msg of the lowest priority lane that has
+    /// msgs to make room for the new msg. Msgs in higher priority
+    /// lanes are kept even if they are older. Dropped requests
+    /// get a busy reply and dropped send/publish msgs are
+    /// dead-lettered.
+    DropOldest,
+
+    /// Drop the new msg (replying busy to requests and
+    /// dead-lettering send/publish msgs).
+    DropNewest,
+}
+
+/// Number of times each overflow policy was applied.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct MailboxOverflow {
+    pub blocked: u64,
+    pub rejected: u64,
+    pub dropped_oldest: u64,
+    pub dropped_newest: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct OverflowCounters {
+    blocked: AtomicU64,
+    rejected: AtomicU64,
+    dropped_oldest: AtomicU64,
+    dropped_newest: AtomicU64,
+}
+
+impl OverflowCounters {
+    pub(crate) fn record(&self, policy: OverflowPolicy) {
+        let counter = match policy {
+            OverflowPolicy::Block => &self.blocked,
+            OverflowPolicy::Reject => &self.rejected,
+            OverflowPolicy::DropOldest => &self.dropped_oldest,
+            OverflowPolicy::DropNewest => &self.dropped_newest,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> MailboxOverflow {
+        MailboxOverflow {
+            blocked: self.blocked.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            dropped_oldest: self.dropped_oldest.load(Ordering::Relaxed),
+            dropped_newest: self.dropped_newest.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use crate::actor::ActorMsg;
+use crate::overflow::{MailboxOverflow, OverflowCounters, OverflowPolicy};
+use async_channel::{self, RecvError, SendError};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::{Semaphore, TryAcquireError};
+
+/// Number of consecutive msgs a non-empty lane can be
+/// skipped for higher priority lanes before it's served.
+const STARVATION_LIMIT: u32 = 8;
+
+const LANES: usize = 3;
+
+/// Priority of a msg in the agent mailbox. Agents handle
+/// higher priority msgs first but still serve lower priority
+/// msgs every so often so they aren't starved.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    pub(crate) fn is_normal(&self) -> bool {
+        *self == Priority::Normal
+    }
+
+    fn lane(&self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+}
+
+impl ActorMsg {
+    fn priority(&self) -> Priority {
+        match self {
+            ActorMsg::Request(req) => req.priority,
+            ActorMsg::Send(send) => send.priority,
+            ActorMsg::Subscribe(sub) => sub.priority,
+        }
+    }
+
+    pub(crate) fn id(&self) -> &str {
+        match self {
+            ActorMsg::Request(req) => &req.id,
+            ActorMsg::Send(send) => &send.id,
+            ActorMsg::Subscribe(sub) => &sub.id,
+        }
+    }
+}
+
+/// Returns a multi-lane agent mailbox that holds at most `max_size`
+/// msgs (unbounded if None or zero).
+pub(crate) fn mailbox(
+    max_size: Option<u32>,
+    overflow: OverflowPolicy,
+) -> (MailboxSender, MailboxReceiver) {
+    let (high_tx, high_rx) = async_channel::unbounded();
+    let (normal_tx, normal_rx) = async_channel::unbounded();
+    let (low_tx, low_rx) = async_channel::unbounded();
+    let capacity = match max_size {
+        Some(max_size) if max_size > 0 => Some(Arc::new(Semaphore::new(max_size as usize))),
+        _ => None,
+    };
+    let receiver = MailboxReceiver {
+        lanes: [high_rx, normal_rx, low_rx],
+        capacity: capacity.clone(),
+        skipped: [0; LANES],
+    };
+    let sender = MailboxSender {
+        lanes: [high_tx, normal_tx, low_tx],
+        receivers: receiver.lanes.clone(),
+        capacity,
+        overflow,
+        counters: Arc::new(OverflowCounters::default()),
+    };
+    (sender, receiver)
+}
+
+/// Msg that didn't fit in a full mailbox.
+pub(crate) enum Overflow {
+    /// The new msg was rejected.
+    Rejected(ActorMsg),
+    /// The msg was dropped to apply the overflow policy.
+    Dropped(ActorMsg),
+}
+
+/// Sends msgs to the mailbox lane for their priority.
+#[derive(Clone)]
+pub(crate) struct MailboxSender {
+    lanes: [async_channel::Sender<ActorMsg>; LANES],
+    // used to drop msgs from the lowest priority lane
+    receivers: [async_channel::Receiver<ActorMsg>; LANES],
+    // one permit per free mailbox slot (None if unbounded)
+    capacity: Option<Arc<Semaphore>>,
+    overflow: OverflowPolicy,
+    counters: Arc<OverflowCounters>,
+}
+
+impl MailboxSender {
+    /// Send a msg and wait for room in the mailbox
+    /// regardless of the overflow policy.
+    pub(crate) async fn send(&self, msg: ActorMsg) -> Result<(), SendError<ActorMsg>> {
+        if let Some(capacity) = &self.capacity {
+            // closed once the workers stop so senders
+            // don't wait on a mailbox nobody reads
+            match capacity.acquire().await {
+                Ok(permit) => permit.forget(),
+                Err(_) => return Err(SendError(msg)),
+            }
+        }
+        self.enqueue(msg).await
+    }
+
+    /// Send a msg read from nats and apply the overflow policy
+    /// if the mailbox is full. Returns the msg that was rejected
+    /// or dropped to make room.
+    pub(crate) async fn push(
+        &self,
+        msg: ActorMsg,
+    ) -> Result<Option<Overflow>, SendError<ActorMsg>> {
+        let capacity = match &self.capacity {
+            Some(capacity) => capacity,
+            None => return self.enqueue(msg).await.map(|_| None),
+        };
+        match capacity.try_acquire() {
+            Ok(permit) => permit.forget(),
+            Err(TryAcquireError::Closed) => return Err(SendError(msg)),
+            Err(TryAcquireError::NoPermits) => {
+                self.counters.record(self.overflow);
+                match self.overflow {
+                    OverflowPolicy::Block => return self.send(msg).await.map(|_| None),
+                    OverflowPolicy::Reject => return Ok(Some(Overflow::Rejected(msg))),
+                    OverflowPolicy::DropNewest => return Ok(Some(Overflow::Dropped(msg))),
+                    OverflowPolicy::DropOldest => match self.pop_lowest_priority() {
+                        // the new msg takes the dropped msg's slot
+                        Some(oldest) => {
+                            self.enqueue(msg).await?;
+                            return Ok(Some(Overflow::Dropped(oldest)));
+                        }
+                        // workers emptied the mailbox in the meantime
+                        None => return self.send(msg).await.map(|_| None),
+                    },
+                }
+            }
+        }
+        self.enqueue(msg).await.map(|_| None)
+    }
+
+    /// Refuse any new msgs so senders don't wait on
+    /// workers that stopped reading the mailbox.
+    pub(crate) fn close_capacity(&self) {
+        if let Some(capacity) = &self.capacity {
+            capacity.close();
+        }
+    }
+
+    /// Returns the number of msgs across all lanes.
+    pub(crate) fn len(&self) -> usize {
+        self.lanes.iter().map(|lane| lane.len()).sum()
+    }
+
+    /// Returns how often each overflow policy was applied.
+    pub(crate) fn overflow_counts(&self) -> MailboxOverflow {
+        self.counters.snapshot()
+    }
+
+    async fn enqueue(&self, msg: ActorMsg) -> Result<(), SendError<ActorMsg>> {
+        self.lanes[msg.priority().lane()].send(msg).await
+    }
+
+    // Returns the front msg of the lowest priority lane that
+    // has msgs. It's the oldest msg of that lane, not the oldest
+    // msg in the mailbox: higher priority msgs are never dropped
+    // while a lower priority lane has msgs.
+    fn pop_lowest_priority(&self) -> Option<ActorMsg> {
+        (0..LANES)
+            .rev()
+            .find_map(|lane| self.receivers[lane].try_recv().ok())
+    }
+}
+
+/// Receives msgs from the highest priority lane unless
+/// a lower priority lane reached the starvation limit.
+/// Each clone tracks starvation on its own.
+pub(crate) struct MailboxReceiver {
+    lanes: [async_channel::Receiver<ActorMsg>; LANES],
+    capacity: Option<Arc<Semaphore>>,
+    // consecutive msgs received while each lane was waiting
+    skipped: [u32; LANES],
+}
+
+impl Clone for MailboxReceiver {
+    fn clone(&self) -> Self {
+        Self {
+            lanes: self.lanes.clone(),
+            capacity: self.capacity.clone(),
+            skipped: [0; LANES],
+        }
+    }
+}
+
+impl MailboxReceiver {
+    /// Wait for the next msg. Returns an error once
+    /// the mailbox is closed and empty.
+    pub(crate) async fn recv(&mut self) -> Result<ActorMsg, RecvError> {
+        if let Some(msg) = self.try_recv() {
+            return Ok(msg);
+        }
+        // biased so simultaneous msgs are taken by priority
+        let (lane, result) = tokio::select! {
+            biased;
+            result = self.lanes[0].recv() => (0, result),
+            result = self.lanes[1].recv() => (1, result),
+            result = self.lanes[2].recv() => (2, result),
+        };
+        let msg = result?;
+        self.served(lane);
+        Ok(msg)
+    }
+
+    /// Returns the next msg or None if every lane is empty.
+    pub(crate) fn try_recv(&mut self) -> Option<ActorMsg> {
+        let waiting = self.waiting();
+        let first = next_lane(&self.skipped, &waiting)?;
+        // other workers may empty a lane between
+        // checking its length and receiving from it
+        let lanes = std::iter::once(first).chain((0..LANES).filter(|lane| *lane != first));
+        for lane in lanes {
+            if let Ok(msg) = self.lanes[lane].try_recv() {
+                self.served(lane);
+                return Some(msg);
+            }
+        }
+        None
+    }
+
+    /// Returns the number of msgs across all lanes.
+    pub(crate) fn len(&self) -> usize {
+        self.lanes.iter().map(|lane| lane.len()).sum()
+    }
+
+    fn waiting(&self) -> [bool; LANES] {
+        [
+            !self.lanes[0].is_empty(),
+            !self.lanes[1].is_empty(),
+            !self.lanes[2].is_empty(),
+        ]
+    }
+
+    fn served(&mut self, lane: usize) {
+        // free the msg's mailbox slot
+        if let Some(capacity) = &self.capacity {
+            capacity.add_permits(1);
+        }
+        let waiting = self.waiting();
+        record_served(&mut self.skipped, &waiting, lane);
+    }
+}
+
+/// Returns the lane to receive from next: the most starved lane
+/// past the starvation limit or else the highest priority lane
+/// with msgs waiting.
+fn next_lane(skipped: &[u32; LANES], waiting: &[bool; LANES]) -> Option<usize> {
+    let starved = (0..LANES)
+        .filter(|lane| waiting[*lane] && skipped[*lane] >= STARVATION_LIMIT)
+        // max_by_key returns the last max so reverse to prefer higher priority
+        .rev()
+        .max_by_key(|lane| skipped[*lane]);
+    starved.or_else(|| (0..LANES).find(|lane| waiting[*lane]))
+}
+
+fn record_served(skipped: &mut [u32; LANES], waiting: &[bool; LANES], served: usize) {
+    for lane in 0..LANES {
+        if lane == served || !waiting[lane] {
+            skipped[lane] = 0;
+        } else {
+            skipped[lane] += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actor::ActorSend;
+
+    fn send_msg(id: &str, priority: Priority) -> ActorMsg {
+        ActorMsg::Send(ActorSend {
+            id: id.to_string(),
+            subject: "test".to_string(),
+            msg_version: "v1.0".to_string(),
+            msg: vec![],
+            attempt: 1,
+            priority,
+            traceparent: None,
+            meta: Default::default(),
+            ack: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_mailbox_overflow() {
+        let (sender, mut receiver) = mailbox(Some(2), OverflowPolicy::Reject);
+        assert!(sender
+            .push(send_msg("1", Priority::Normal))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(sender
+            .push(send_msg("2", Priority::Normal))
+            .await
+            .unwrap()
+            .is_none());
+        let rejected = sender.push(send_msg("3", Priority::Normal)).await.unwrap();
+        assert!(matches!(rejected, Some(Overflow::Rejected(msg)) if msg.id() == "3"));
+
+        // receiving frees a slot
+        assert_eq!(receiver.recv().await.unwrap().id(), "1");
+        assert!(sender
+            .push(send_msg("4", Priority::Normal))
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(sender.overflow_counts().rejected, 1);
+
+        // the low priority msg is dropped even though it's newer
+        let (sender, mut receiver) = mailbox(Some(2), OverflowPolicy::DropOldest);
+        sender.push(send_msg("1", Priority::High)).await.unwrap();
+        sender.push(send_msg("2", Priority::Low)).await.unwrap();
+        let dropped = sender.push(send_msg("3", Priority::Normal)).await.unwrap();
+        assert!(matches!(dropped, Some(Overflow::Dropped(msg)) if msg.id() == "2"));
+        assert_eq!(receiver.len(), 2);
+        assert_eq!(receiver.recv().await.unwrap().id(), "1");
+        assert_eq!(receiver.recv().await.unwrap().id(), "3");
+        assert_eq!(sender.overflow_counts().dropped_oldest, 1);
+    }
+
+    #[tokio::test]
+    async fn test_mailbox_closed_capacity() {
+        let (sender, mut receiver) = mailbox(Some(1), OverflowPolicy::Block);
+        sender.push(send_msg("1", Priority::Normal)).await.unwrap();
+        sender.close_capacity();
+
+        // closed mailboxes hand back the msg instead of enqueueing it
+        let err = sender.send(send_msg("2", Priority::Normal)).await;
+        assert!(matches!(err, Err(SendError(msg)) if msg.id() == "2"));
+        let err = sender.push(send_msg("3", Priority::Normal)).await;
+        assert!(matches!(err, Err(SendError(msg)) if msg.id() == "3"));
+        assert_eq!(sender.len(), 1);
+
+        // msgs already in the mailbox can still be drained
+        assert_eq!(receiver.recv().await.unwrap().id(), "1");
+        assert!(sender.send(send_msg("4", Priority::Normal)).await.is_err());
+        assert_eq!(receiver.len(), 0);
+    }
+
+    // simulate receiving from lanes that are never empty
+    fn serve(rounds: usize, waiting: [bool; LANES]) -> [usize; LANES] {
+        let mut skipped = [0; LANES];
+        let mut served = [0; LANES];
+        for _ in 0..rounds {
+            let lane = next_lane(&skipped, &waiting).unwrap();
+            record_served(&mut skipped, &waiting, lane);
+            served[lane] += 1;
+        }
+        served
+    }
+
+    #[test]
+    fn test_next_lane_priority() {
+        assert_eq!(next_lane(&[0; LANES], &[false; LANES]), None);
+        assert_eq!(next_lane(&[0; LANES], &[false, true, true]), Some(1));
+        assert_eq!(next_lane(&[0; LANES], &[true, true, true]), Some(0));
+        assert_eq!(serve(100, [true, false, false]), [100, 0, 0]);
+    }
+
+    #[test]
+    fn test_next_lane_starvation() {
+        assert_eq!(
+            next_lane(&[0, STARVATION_LIMIT, 0], &[true, true, true]),
+            Some(1)
+        );
+        // skipped counts don't matter for empty lanes
+        assert_eq!(
+            next_lane(&[0, STARVATION_LIMIT, 0], &[true, false, true]),
+            Some(0)
+        );
+
+        // every busy lane gets served
+        let served = serve(1000, [true, true, true]);
+        assert!(served[0] > served[1]);
+        assert!(served[1] > 0);
+        assert!(served[2] > 0);
+    }
+}
@@ -0,0 +1,160 @@
+use rand::Rng;
+use std::fmt;
+use std::sync::Arc;
+use tokio::time::Duration;
+
+type Retryable = Arc<dyn Fn(&anyhow::Error) -> bool + Send + Sync>;
+
+/// RetryPolicy defines how an agent redelivers messages
+/// after `Handle::send` or `Handle::subscribe` returns an error.
+/// Retried messages are re-enqueued into the agent mailbox and
+/// dead-lettered once the policy gives up.
+///
+/// The default policy doesn't retry.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    // fraction (0.0..=1.0) of each backoff to randomize
+    jitter: f64,
+    retryable: Retryable,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("min_backoff", &self.min_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("jitter", &self.jitter)
+            .finish()
+    }
+}
+
+impl RetryPolicy {
+    /// Handle each message at most `max_attempts` times
+    /// (including the first attempt) retrying every error.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            jitter: 0.0,
+            retryable: Arc::new(|_| true),
+        }
+    }
+
+    /// Wait `min * 2^(n-1)` (capped at `max`) before
+    /// attempt `n + 1`.
+    pub fn with_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Randomly shorten each backoff by up to `jitter`
+    /// (clamped to 0.0..=1.0) of its value so failed
+    /// messages don't retry in lockstep.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Only retry errors the predicate returns true for.
+    pub fn with_retryable<F>(mut self, retryable: F) -> Self
+    where
+        F: Fn(&anyhow::Error) -> bool + Send + Sync + 'static,
+    {
+        self.retryable = Arc::new(retryable);
+        self
+    }
+
+    /// Returns how long to wait before redelivering a message
+    /// that failed on `attempt` or None if it shouldn't be retried.
+    pub(crate) fn retry_delay(&self, attempt: u32, err: &anyhow::Error) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        self.redelivery_delay(attempt, err)
+    }
+
+    /// Returns the backoff before JetStream redelivers a durable
+    /// msg that failed on `attempt` or None if the error isn't
+    /// retryable. `DurableOpts::with_max_deliver` limits the
+    /// attempts instead of `max_attempts`.
+    pub(crate) fn redelivery_delay(&self, attempt: u32, err: &anyhow::Error) -> Option<Duration> {
+        if !(self.retryable)(err) {
+            return None;
+        }
+        let delay = self.backoff(attempt);
+        if self.jitter > 0.0 {
+            let jitter = rand::thread_rng().gen_range(0.0..=self.jitter);
+            return Some(delay.mul_f64(1.0 - jitter));
+        }
+        Some(delay)
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(31);
+        let delay = self.min_backoff.saturating_mul(1u32 << exp);
+        std::cmp::min(delay, self.max_backoff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_retry_delay() {
+        let err = anyhow!("connection reset");
+        assert_eq!(RetryPolicy::default().retry_delay(1, &err), None);
+
+        let policy = RetryPolicy::new(4)
+            .with_backoff(Duration::from_millis(100), Duration::from_millis(250));
+        assert_eq!(
+            policy.retry_delay(1, &err),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(
+            policy.retry_delay(2, &err),
+            Some(Duration::from_millis(200))
+        );
+        assert_eq!(
+            policy.retry_delay(3, &err),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(policy.retry_delay(4, &err), None);
+
+        let policy = policy.with_retryable(|err| err.to_string() != "bad msg");
+        assert_eq!(policy.retry_delay(1, &anyhow!("bad msg")), None);
+        assert_eq!(policy.redelivery_delay(1, &anyhow!("bad msg")), None);
+
+        // durable redeliveries ignore max_attempts
+        assert_eq!(
+            RetryPolicy::default().redelivery_delay(3, &err),
+            Some(Duration::from_millis(400))
+        );
+    }
+
+    #[test]
+    fn test_retry_delay_jitter() {
+        let err = anyhow!("connection reset");
+        let policy = RetryPolicy::new(3)
+            .with_backoff(Duration::from_millis(100), Duration::from_secs(1))
+            .with_jitter(0.5);
+        for _ in 0..100 {
+            let delay = policy.retry_delay(2, &err).unwrap();
+            assert!(delay >= Duration::from_millis(100));
+            assert!(delay <= Duration::from_millis(200));
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use crate::actor::{actor_mailbox_name, ActorMsg, ActorSend, ActorSender, Msg};
+use crate::common::new_id_as_string;
+use crate::metadata::Metadata;
+use crate::priority::Priority;
+use anyhow::Result;
+use log::warn;
+use std::io::{Error, ErrorKind};
+use tokio::sync::watch;
+use tokio::task::AbortHandle;
+use tokio::time::{interval_at, sleep, Duration, Instant};
+
+/// Scheduler enqueues messages into the agent mailbox
+/// of the actor that owns it. Scheduled messages are
+/// delivered to `Handle::send` like any other send message.
+#[derive(Clone)]
+pub struct Scheduler {
+    actor_name: String,
+    system_name: String,
+    dispatch_types: Vec<String>,
+    sender: ActorSender,
+    // true once the agent starts shutting down
+    stop_rx: watch::Receiver<bool>,
+}
+
+/// Handle for a scheduled message. Dropping the
+/// handle doesn't cancel the timer, call `cancel` instead.
+pub struct TimerHandle {
+    abort_handle: AbortHandle,
+}
+
+impl TimerHandle {
+    /// Cancel the timer. Messages already delivered
+    /// to the mailbox are still handled.
+    pub fn cancel(&self) {
+        self.abort_handle.abort();
+    }
+
+    /// Returns true if the timer was cancelled or has fired
+    /// (send_after) or the agent stopped.
+    pub fn is_finished(&self) -> bool {
+        self.abort_handle.is_finished()
+    }
+}
+
+impl Scheduler {
+    pub(crate) fn new(
+        actor_name: String,
+        system_name: String,
+        dispatch_types: Vec<String>,
+        sender: ActorSender,
+        stop_rx: watch::Receiver<bool>,
+    ) -> Self {
+        Self {
+            actor_name,
+            system_name,
+            dispatch_types,
+            sender,
+            stop_rx,
+        }
+    }
+
+    /// Send `msg` to this actor's mailbox once `delay` elapses.
+    pub fn send_after<M: Msg>(&self, msg: M, delay: Duration) -> Result<TimerHandle> {
+        let msg = self.msg_bytes(msg)?;
+        let msg_version = M::version();
+        let subject = self.subject::<M>();
+        let sender = self.sender.clone();
+        let mut stop_rx = self.stop_rx.clone();
+        let handle = tokio::spawn(async move {
+            tokio::select! {
+                _ = sleep(delay) => {
+                    enqueue(&sender, &subject, msg, msg_version).await;
+                }
+                _ = stop_rx.changed() => {}
+            }
+        });
+        Ok(TimerHandle {
+            abort_handle: handle.abort_handle(),
+        })
+    }
+
+    /// Send `msg` to this actor's mailbox every `period`,
+    /// starting one `period` from now.
+    pub fn send_interval<M: Msg>(&self, msg: M, period: Duration) -> Result<TimerHandle> {
+        let msg = self.msg_bytes(msg)?;
+        let msg_version = M::version();
+        let subject = self.subject::<M>();
+        let sender = self.sender.clone();
+        let mut stop_rx = self.stop_rx.clone();
+        let handle = tokio::spawn(async move {
+            let mut ticker = interval_at(Instant::now() + period, period);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {
+                        enqueue(&sender, &subject, msg.clone(), msg_version).await;
+                    }
+                    _ = stop_rx.changed() => break,
+                }
+            }
+        });
+        Ok(TimerHandle {
+            abort_handle: handle.abort_handle(),
+        })
+    }
+
+    // Check this actor can dispatch the msg type
+    // and serialize it for the mailbox.
+    fn msg_bytes<M: Msg>(&self, msg: M) -> Result<Vec<u8>> {
+        let msg_type = M::dispatch_type();
+        if !self.dispatch_types.contains(&msg_type) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "{} doesn't support message type {}",
+                    &self.actor_name, &msg_type
+                ),
+            )
+            .into());
+        }
+        msg.into_bytes()
+    }
+
+    // Scheduled msgs are dead-lettered as if they
+    // were sent to this actor's queue mailbox.
+    fn subject<M: Msg>(&self) -> String {
+        actor_mailbox_name(&self.system_name, &self.actor_name, &M::dispatch_type())
+    }
+}
+
+async fn enqueue(sender: &ActorSender, subject: &str, msg: Vec<u8>, msg_version: &str) {
+    let mailbox_msg = ActorMsg::Send(ActorSend {
+        id: new_id_as_string(),
+        subject: subject.to_owned(),
+        msg_version: msg_version.to_owned(),
+        msg,
+        attempt: 1,
+        priority: Priority::Normal,
+        traceparent: None,
+        meta: Metadata::default(),
+        ack: None,
+    });
+    if let Err(err) = sender.send(mailbox_msg).await {
+        warn!("failed to enqueue scheduled msg: {:?}", &err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overflow::OverflowPolicy;
+    use crate::priority::{self, MailboxReceiver};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize)]
+    struct TickMsg {}
+
+    impl Msg for TickMsg {
+        type Type = Self;
+        const VERSION: &'static str = "v1.0";
+    }
+
+    fn scheduler() -> (Scheduler, MailboxReceiver, watch::Sender<bool>) {
+        let (sender, receiver) = priority::mailbox(None, OverflowPolicy::Block);
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let scheduler = Scheduler::new(
+            "TestActor/v1.0".to_string(),
+            "test".to_string(),
+            vec![TickMsg::dispatch_type()],
+            sender,
+            stop_rx,
+        );
+        (scheduler, receiver, stop_tx)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_send_after() {
+        let (scheduler, mut receiver, _stop_tx) = scheduler();
+        let timer = scheduler
+            .send_after(TickMsg {}, Duration::from_secs(5))
+            .unwrap();
+
+        sleep(Duration::from_secs(4)).await;
+        assert_eq!(receiver.len(), 0);
+
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(receiver.len(), 1);
+        assert!(timer.is_finished());
+
+        // delivered once
+        sleep(Duration::from_secs(60)).await;
+        assert_eq!(receiver.len(), 1);
+        match receiver.try_recv() {
+            Some(ActorMsg::Send(send)) => {
+                assert_eq!(send.msg_version, "v1.0");
+                assert_eq!(send.subject, scheduler.subject::<TickMsg>());
+            }
+            _ => panic!("expected a scheduled send msg"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_send_interval() {
+        let (scheduler, receiver, _stop_tx) = scheduler();
+        let timer = scheduler
+            .send_interval(TickMsg {}, Duration::from_secs(1))
+            .unwrap();
+
+        sleep(Duration::from_millis(3500)).await;
+        assert_eq!(receiver.len(), 3);
+
+        timer.cancel();
+        sleep(Duration::from_secs(5)).await;
+        assert_eq!(receiver.len(), 3);
+        assert!(timer.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stop() {
+        let (scheduler, receiver, stop_tx) = scheduler();
+        let after = scheduler
+            .send_after(TickMsg {}, Duration::from_secs(5))
+            .unwrap();
+        let interval = scheduler
+            .send_interval(TickMsg {}, Duration::from_secs(1))
+            .unwrap();
+
+        stop_tx.send(true).unwrap();
+        sleep(Duration::from_secs(10)).await;
+        assert_eq!(receiver.len(), 0);
+        assert!(after.is_finished());
+        assert!(interval.is_finished());
+    }
+
+    #[test]
+    fn test_unsupported_msg_type() {
+        #[derive(Serialize, Deserialize)]
+        struct OtherMsg {}
+
+        impl Msg for OtherMsg {
+            type Type = Self;
+            const VERSION: &'static str = "v1.0";
+        }
+
+        let (scheduler, _receiver, _stop_tx) = scheduler();
+        let err = scheduler.msg_bytes(OtherMsg {}).unwrap_err();
+        assert!(err.to_string().contains("OtherMsg/v1.0"));
+    }
+}
@@ -0,0 +1,463 @@
+//! Streaming responses to a single request.
+//!
+//! The client subscribes to an inbox and sends a request with
+//! `StreamOpts`. The agent publishes the handler responses to the
+//! inbox as `HollywoodStreamFrame`s followed by an end (or error)
+//! frame. Flow control is credit based: the agent starts with
+//! `window` credits, spends one per response and the client grants
+//! more on the control subject as it consumes them. Dropping the
+//! `ResponseStream` sends a cancel on the control subject.
+
+use crate::actor::{HollywoodMsg, Msg};
+use anyhow::{anyhow, Result};
+use futures::stream::{BoxStream, Stream, StreamExt};
+use log::{debug, error};
+use nats::asynk::{Connection, Message, Subscription};
+use nats::Headers;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+
+/// Number of responses an agent sends before
+/// it waits for the client to grant more credit.
+pub(crate) const DEFAULT_STREAM_WINDOW: u32 = 64;
+
+/// How long a client waits for each response
+/// before giving up on the stream.
+pub(crate) const DEFAULT_STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a handler waits for credit before
+/// giving up on a consumer that stopped reading.
+const CREDIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sent along with a request to stream its responses.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct StreamOpts {
+    // subject the client sends credits and cancels to
+    pub control: String,
+    // credits the agent starts with
+    pub window: u32,
+    // how long the client waits for each frame (client only)
+    #[serde(skip)]
+    pub idle_timeout: Duration,
+}
+
+/// A response (or the end of the stream) published to
+/// the reply subject of a stream request.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct HollywoodStreamFrame {
+    // id of the stream request
+    pub id: String,
+    pub seq: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // true for the last frame
+    #[serde(default)]
+    pub end: bool,
+}
+
+/// Sent by the client on the control subject.
+#[derive(Serialize, Deserialize, Debug)]
+struct StreamControl {
+    #[serde(default)]
+    credit: u32,
+    #[serde(default)]
+    cancel: bool,
+}
+
+/// Counts consumed responses and returns the credit to
+/// grant once half the window has been consumed.
+struct Credit {
+    window: u32,
+    consumed: u32,
+}
+
+impl Credit {
+    fn new(window: u32) -> Self {
+        Self {
+            window: window.max(1),
+            consumed: 0,
+        }
+    }
+
+    fn consume(&mut self) -> Option<u32> {
+        self.consumed += 1;
+        if self.consumed < (self.window / 2).max(1) {
+            return None;
+        }
+        Some(std::mem::take(&mut self.consumed))
+    }
+}
+
+fn cancelled() -> anyhow::Error {
+    Error::new(
+        ErrorKind::BrokenPipe,
+        "response stream cancelled by the consumer",
+    )
+    .into()
+}
+
+/// Agent side of a stream request.
+pub(crate) struct StreamSink {
+    id: String,
+    reply: String,
+    nats: Connection,
+    credits: Arc<Semaphore>,
+    cancelled: Arc<AtomicBool>,
+    seq: AtomicU64,
+    // reads credits and cancels from the control subject
+    control: JoinHandle<()>,
+}
+
+impl StreamSink {
+    /// Subscribe to the control subject before
+    /// the handler sends its first response.
+    pub(crate) async fn open(
+        nats: Connection,
+        id: String,
+        reply: String,
+        opts: &StreamOpts,
+    ) -> Result<Self> {
+        let subscription = nats.subscribe(&opts.control).await?;
+        let credits = Arc::new(Semaphore::new(opts.window as usize));
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let control = tokio::spawn(Self::read_control(
+            subscription,
+            credits.clone(),
+            cancelled.clone(),
+        ));
+        Ok(Self {
+            id,
+            reply,
+            nats,
+            credits,
+            cancelled,
+            seq: AtomicU64::new(0),
+            control,
+        })
+    }
+
+    async fn read_control(
+        subscription: Subscription,
+        credits: Arc<Semaphore>,
+        cancelled: Arc<AtomicBool>,
+    ) {
+        while let Some(nats_msg) = subscription.next().await {
+            match serde_json::from_slice::<StreamControl>(&nats_msg.data) {
+                Ok(control) if control.cancel => {
+                    cancelled.store(true, Ordering::SeqCst);
+                    credits.close();
+                    return;
+                }
+                Ok(control) => credits.add_permits(control.credit as usize),
+                Err(err) => error!("deserializing stream control msg: {:?}", &err),
+            }
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Publish a response once the client granted credit for it.
+    async fn send(&self, msg: Vec<u8>) -> Result<()> {
+        match timeout(CREDIT_TIMEOUT, self.credits.acquire()).await {
+            Ok(Ok(permit)) => permit.forget(),
+            Ok(Err(_)) => return Err(cancelled()),
+            Err(_) => {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "response stream consumer stopped granting credit",
+                )
+                .into())
+            }
+        }
+        self.publish(Some(msg), None, false).await
+    }
+
+    /// Publish the end of the stream or the handler error.
+    pub(crate) async fn end(&self, error: Option<String>) {
+        if self.is_cancelled() {
+            return;
+        }
+        if let Err(err) = self.publish(None, error, true).await {
+            error!("publishing end of stream msg id {}: {:?}", &self.id, &err);
+        }
+    }
+
+    async fn publish(&self, msg: Option<Vec<u8>>, error: Option<String>, end: bool) -> Result<()> {
+        let frame = HollywoodStreamFrame {
+            id: self.id.clone(),
+            seq: self.seq.fetch_add(1, Ordering::SeqCst),
+            msg,
+            error,
+            end,
+        };
+        let frame = HollywoodMsg::Stream(frame).into_bytes()?;
+        self.nats.publish(&self.reply, frame).await?;
+        Ok(())
+    }
+}
+
+impl Drop for StreamSink {
+    fn drop(&mut self) {
+        self.control.abort();
+    }
+}
+
+/// Sends the responses to a stream request.
+/// Passed to `HandleStream::stream`.
+pub struct StreamSender<M> {
+    sink: Arc<StreamSink>,
+    _msg: PhantomData<fn(M)>,
+}
+
+impl<M> Clone for StreamSender<M> {
+    fn clone(&self) -> Self {
+        Self {
+            sink: self.sink.clone(),
+            _msg: PhantomData,
+        }
+    }
+}
+
+impl<M: Msg> StreamSender<M> {
+    pub(crate) fn new(sink: Arc<StreamSink>) -> Self {
+        Self {
+            sink,
+            _msg: PhantomData,
+        }
+    }
+
+    /// Send a response. Waits while the client has no credit left
+    /// and returns an error once the client cancels the stream.
+    pub async fn send(&self, msg: M) -> Result<()> {
+        if self.sink.is_cancelled() {
+            return Err(cancelled());
+        }
+        self.sink.send(msg.into_bytes()?).await
+    }
+
+    /// Returns true once the client dropped the stream.
+    pub fn is_cancelled(&self) -> bool {
+        self.sink.is_cancelled()
+    }
+}
+
+/// Returns true for the status msg nats sends to the
+/// reply subject when nobody subscribes to the request.
+fn is_no_responders(data: &[u8], headers: Option<&Headers>) -> bool {
+    data.is_empty()
+        && headers
+            .and_then(|headers| headers.get("Status"))
+            .is_some_and(|status| status.contains("503"))
+}
+
+/// Wait up to `idle_timeout` for the next frame.
+async fn next_frame(
+    next: impl Future<Output = Option<Message>>,
+    idle_timeout: Duration,
+) -> Result<Message> {
+    match timeout(idle_timeout, next).await {
+        Ok(Some(nats_msg)) if is_no_responders(&nats_msg.data, nats_msg.headers.as_ref()) => {
+            Err(anyhow!("no responders for the stream request"))
+        }
+        Ok(Some(nats_msg)) => Ok(nats_msg),
+        Ok(None) => Err(anyhow!("response stream subscription closed")),
+        Err(_) => Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("no stream response received for {:?}", idle_timeout),
+        )
+        .into()),
+    }
+}
+
+/// Client side of a stream request.
+struct Consumer {
+    subscription: Subscription,
+    nats: Connection,
+    control: String,
+    credit: Credit,
+    idle_timeout: Duration,
+    done: Arc<AtomicBool>,
+}
+
+impl Consumer {
+    /// Returns the next response or None at the end of the stream.
+    async fn next<M: Msg>(&mut self) -> Option<Result<M>> {
+        if self.done.load(Ordering::SeqCst) {
+            return None;
+        }
+        let result = self.read::<M>().await;
+        if !matches!(result, Some(Ok(_))) {
+            self.done.store(true, Ordering::SeqCst);
+        }
+        result
+    }
+
+    async fn read<M: Msg>(&mut self) -> Option<Result<M>> {
+        let nats_msg = match next_frame(self.subscription.next(), self.idle_timeout).await {
+            Ok(nats_msg) => nats_msg,
+            Err(err) => return Some(Err(err)),
+        };
+        let frame = match HollywoodMsg::from_bytes(&nats_msg.data) {
+            Ok(HollywoodMsg::Stream(frame)) => frame,
+            // the agent rejected the request before streaming (i.e. busy)
+            Ok(HollywoodMsg::Response(resp)) => {
+                let err = resp
+                    .error
+                    .unwrap_or_else(|| "unexpected response".to_string());
+                return Some(Err(anyhow!("msg id {} response err: {:?}", &resp.id, &err)));
+            }
+            Ok(_) => return Some(Err(anyhow!("stream received an unexpected HollywoodMsg"))),
+            Err(err) => return Some(Err(err)),
+        };
+        if let Some(err) = frame.error {
+            return Some(Err(anyhow!("msg id {} stream err: {:?}", &frame.id, &err)));
+        }
+        let msg = match frame.msg {
+            Some(msg) => msg,
+            None if frame.end => return None,
+            None => return Some(Err(anyhow!("msg id {} empty stream frame", &frame.id))),
+        };
+        if let Some(credit) = self.credit.consume() {
+            self.control(StreamControl {
+                credit,
+                cancel: false,
+            })
+            .await;
+        }
+        Some(M::from_bytes(&msg))
+    }
+
+    async fn control(&self, control: StreamControl) {
+        let result = match serde_json::to_vec(&control) {
+            Ok(msg) => self
+                .nats
+                .publish(&self.control, msg)
+                .await
+                .map_err(Into::into),
+            Err(err) => Err(anyhow::Error::from(err)),
+        };
+        if let Err(err) = result {
+            error!("publishing stream control msg: {:?}", &err);
+        }
+    }
+}
+
+/// Stream of responses returned by `Client::request_stream`.
+/// Dropping it before the end of the stream cancels the
+/// request handler.
+pub struct ResponseStream<M> {
+    inner: BoxStream<'static, Result<M>>,
+    nats: Connection,
+    control: String,
+    done: Arc<AtomicBool>,
+}
+
+impl<M: Msg + Send + 'static> ResponseStream<M> {
+    pub(crate) fn new(subscription: Subscription, nats: Connection, opts: &StreamOpts) -> Self {
+        let done = Arc::new(AtomicBool::new(false));
+        let consumer = Consumer {
+            subscription,
+            nats: nats.clone(),
+            control: opts.control.clone(),
+            credit: Credit::new(opts.window),
+            idle_timeout: opts.idle_timeout,
+            done: done.clone(),
+        };
+        let inner = futures::stream::unfold(consumer, |mut consumer| async move {
+            let next = consumer.next::<M>().await?;
+            Some((next, consumer))
+        })
+        .boxed();
+        Self {
+            inner,
+            nats,
+            control: opts.control.clone(),
+            done,
+        }
+    }
+}
+
+impl<M> Stream for ResponseStream<M> {
+    type Item = Result<M>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+impl<M> Drop for ResponseStream<M> {
+    fn drop(&mut self) {
+        if self.done.load(Ordering::SeqCst) {
+            return;
+        }
+        debug!("cancelling response stream {}", &self.control);
+        let nats = self.nats.clone();
+        let control = self.control.clone();
+        let cancel = StreamControl {
+            credit: 0,
+            cancel: true,
+        };
+        if let Ok(msg) = serde_json::to_vec(&cancel) {
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                runtime.spawn(async move {
+                    if let Err(err) = nats.publish(&control, msg).await {
+                        error!("publishing stream cancel: {:?}", &err);
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credit() {
+        let mut credit = Credit::new(4);
+        assert_eq!(credit.consume(), None);
+        assert_eq!(credit.consume(), Some(2));
+        assert_eq!(credit.consume(), None);
+        assert_eq!(credit.consume(), Some(2));
+
+        // grant every response with a window of 1
+        let mut credit = Credit::new(0);
+        assert_eq!(credit.consume(), Some(1));
+        assert_eq!(credit.consume(), Some(1));
+    }
+
+    #[test]
+    fn test_no_responders() {
+        let status = Headers::from_iter([("Status".to_string(), "503".to_string())]);
+        assert!(is_no_responders(&[], Some(&status)));
+        assert!(!is_no_responders(b"frame", Some(&status)));
+        assert!(!is_no_responders(&[], None));
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let idle_timeout = Duration::from_millis(10);
+        let err = next_frame(std::future::pending(), idle_timeout)
+            .await
+            .unwrap_err();
+        let err = err.downcast::<Error>().unwrap();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        let err = next_frame(async { None }, idle_timeout).await.unwrap_err();
+        assert!(err.to_string().contains("closed"));
+    }
+}
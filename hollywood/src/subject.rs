@@ -0,0 +1,51 @@
+/// Returns true if a nats subject has a `*` or `>` wildcard token.
+pub(crate) fn is_wildcard(subject: &str) -> bool {
+    subject.split('.').any(|token| token == "*" || token == ">")
+}
+
+/// Returns true if a subject matches a subscription subject
+/// (i.e. `orders.created` matches `orders.*` and `orders.>`).
+/// `*` matches one token and a trailing `>` matches one or more.
+pub(crate) fn matches(pattern: &str, subject: &str) -> bool {
+    let mut tokens = subject.split('.');
+    for pattern_token in pattern.split('.') {
+        if pattern_token == ">" {
+            return tokens.next().is_some();
+        }
+        match tokens.next() {
+            Some(token) if pattern_token == "*" || pattern_token == token => {}
+            _ => return false,
+        }
+    }
+    tokens.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_wildcard() {
+        assert!(is_wildcard("orders.*"));
+        assert!(is_wildcard("orders.>"));
+        assert!(is_wildcard("*.created"));
+        assert!(!is_wildcard("orders.created"));
+        assert!(!is_wildcard("orders.v*"));
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("orders.created", "orders.created"));
+        assert!(!matches("orders.created", "orders.deleted"));
+
+        assert!(matches("orders.*", "orders.created"));
+        assert!(!matches("orders.*", "orders"));
+        assert!(!matches("orders.*", "orders.created.eu"));
+        assert!(matches("*.created", "orders.created"));
+
+        assert!(matches("orders.>", "orders.created"));
+        assert!(matches("orders.>", "orders.created.eu"));
+        assert!(!matches("orders.>", "orders"));
+        assert!(!matches("orders.>", "users.created"));
+    }
+}
@@ -0,0 +1,70 @@
+use std::any::Any;
+use tokio::time::Duration;
+
+/// SupervisionStrategy defines what an agent worker
+/// does after an actor handler panics.
+#[derive(Clone, Debug)]
+pub enum SupervisionStrategy {
+    /// Rebuild the actor with the actor factory
+    /// and keep reading from the mailbox.
+    Restart,
+
+    /// Rebuild the actor with the actor factory after
+    /// waiting `min * 2^(n-1)` (capped at `max`) where `n` is the
+    /// number of consecutive panics since the last handled message.
+    RestartWithBackoff { min: Duration, max: Duration },
+
+    /// Stop the agent and return an error from `hollywood::run`.
+    Escalate,
+}
+
+impl SupervisionStrategy {
+    /// Returns how long to wait before restarting an actor
+    /// or None if the panic should be escalated.
+    pub(crate) fn restart_delay(&self, consecutive_panics: u32) -> Option<Duration> {
+        match self {
+            SupervisionStrategy::Restart => Some(Duration::ZERO),
+            SupervisionStrategy::RestartWithBackoff { min, max } => {
+                let exp = consecutive_panics.saturating_sub(1).min(31);
+                let delay = min.saturating_mul(1u32 << exp);
+                Some(std::cmp::min(delay, *max))
+            }
+            SupervisionStrategy::Escalate => None,
+        }
+    }
+}
+
+/// Returns the message from a caught panic payload.
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restart_delay() {
+        assert_eq!(
+            SupervisionStrategy::Restart.restart_delay(3),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(SupervisionStrategy::Escalate.restart_delay(1), None);
+
+        let backoff = SupervisionStrategy::RestartWithBackoff {
+            min: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        assert_eq!(backoff.restart_delay(1), Some(Duration::from_millis(100)));
+        assert_eq!(backoff.restart_delay(2), Some(Duration::from_millis(200)));
+        assert_eq!(backoff.restart_delay(4), Some(Duration::from_millis(800)));
+        assert_eq!(backoff.restart_delay(5), Some(Duration::from_secs(1)));
+        assert_eq!(backoff.restart_delay(100), Some(Duration::from_secs(1)));
+    }
+}
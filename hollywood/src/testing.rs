@@ -0,0 +1,272 @@
+//! Helpers for running agents and actors in unit tests.
+
+use crate::actor::DispatchType;
+use crate::client::Client;
+use crate::ctx::Ctx;
+use crate::metadata::Metadata;
+use crate::overflow::OverflowPolicy;
+use crate::priority;
+use crate::scheduler::Scheduler;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::sync::watch;
+
+/// Starts a nats server stub and returns its uri.
+pub(crate) fn nats_stub() -> String {
+    NatsStub::start().uri
+}
+
+/// A nats server stub speaking enough of the core protocol for
+/// unit tests: msgs are delivered to matching subscriptions
+/// (one member per queue group) and published msgs are recorded.
+/// There is no JetStream.
+pub(crate) struct NatsStub {
+    pub uri: String,
+    state: Arc<Mutex<StubState>>,
+}
+
+#[derive(Default)]
+struct StubState {
+    subs: Vec<StubSub>,
+    // subject and payload of every published msg
+    published: Vec<(String, Vec<u8>)>,
+}
+
+struct StubSub {
+    conn: usize,
+    sid: String,
+    subject: String,
+    queue: Option<String>,
+    // unsubscribe after this many msgs
+    max: Option<u64>,
+    delivered: u64,
+    writer: Arc<Mutex<TcpStream>>,
+}
+
+impl NatsStub {
+    pub(crate) fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(StubState::default()));
+        let accept_state = state.clone();
+        thread::spawn(move || {
+            for (conn, stream) in listener.incoming().enumerate() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                let state = accept_state.clone();
+                thread::spawn(move || {
+                    let _ = serve(conn, addr.port(), stream, &state);
+                    // forget the subscriptions of closed connections
+                    state.lock().unwrap().subs.retain(|sub| sub.conn != conn);
+                });
+            }
+        });
+        Self {
+            uri: format!("nats://{}", addr),
+            state,
+        }
+    }
+
+    /// Returns the payloads published to `subject`.
+    pub(crate) fn published(&self, subject: &str) -> Vec<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state
+            .published
+            .iter()
+            .filter(|(published, _)| published == subject)
+            .map(|(_, payload)| payload.clone())
+            .collect()
+    }
+
+    /// Returns the subjects of the open subscriptions.
+    pub(crate) fn subjects(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.subs.iter().map(|sub| sub.subject.clone()).collect()
+    }
+
+    /// Waits until the stub reads a subscription to `subject`.
+    pub(crate) async fn wait_for_subscription(
+        &self,
+        nats: &nats::asynk::Connection,
+        subject: &str,
+    ) {
+        loop {
+            nats.flush().await.unwrap();
+            let subscribed = self
+                .state
+                .lock()
+                .unwrap()
+                .subs
+                .iter()
+                .any(|sub| sub.subject == subject);
+            if subscribed {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+}
+
+fn serve(conn: usize, port: u16, stream: TcpStream, state: &Mutex<StubState>) -> io::Result<()> {
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let info = format!(
+        "INFO {{\"server_id\":\"stub\",\"version\":\"2.10.0\",\"go\":\"go1.21\",\"host\":\"127.0.0.1\",\"port\":{},\"proto\":1,\"headers\":true,\"max_payload\":1048576,\"client_id\":1}}\r\n",
+        port
+    );
+    writer.lock().unwrap().write_all(info.as_bytes())?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.first().map(|op| op.to_ascii_uppercase()).as_deref() {
+            Some("PING") => writer.lock().unwrap().write_all(b"PONG\r\n")?,
+            Some("SUB") => {
+                let (queue, sid) = match args.len() {
+                    4 => (Some(args[2].to_string()), args[3]),
+                    _ => (None, args[2]),
+                };
+                state.lock().unwrap().subs.push(StubSub {
+                    conn,
+                    sid: sid.to_string(),
+                    subject: args[1].to_string(),
+                    queue,
+                    max: None,
+                    delivered: 0,
+                    writer: writer.clone(),
+                });
+            }
+            Some("UNSUB") => {
+                let max = args.get(2).and_then(|max| max.parse::<u64>().ok());
+                let mut state = state.lock().unwrap();
+                match max {
+                    Some(max) => state
+                        .subs
+                        .iter_mut()
+                        .filter(|sub| sub.conn == conn && sub.sid == args[1])
+                        .for_each(|sub| sub.max = Some(max)),
+                    None => state
+                        .subs
+                        .retain(|sub| !(sub.conn == conn && sub.sid == args[1])),
+                }
+            }
+            Some("PUB") | Some("HPUB") => {
+                let headers = args[0].eq_ignore_ascii_case("HPUB");
+                let size: usize = args[args.len() - 1].parse().unwrap();
+                let header_size: usize = match headers {
+                    true => args[args.len() - 2].parse().unwrap(),
+                    false => 0,
+                };
+                let reply = match args.len() - headers as usize {
+                    4 => Some(args[2]),
+                    _ => None,
+                };
+                let mut data = vec![0; size + 2];
+                reader.read_exact(&mut data)?;
+                data.truncate(size);
+                deliver(state, args[1], reply, header_size, headers, data);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Writes a published msg to every matching subscription
+/// (and one member of each queue group).
+fn deliver(
+    state: &Mutex<StubState>,
+    subject: &str,
+    reply: Option<&str>,
+    header_size: usize,
+    headers: bool,
+    data: Vec<u8>,
+) {
+    let mut state = state.lock().unwrap();
+    let mut groups: Vec<(String, String)> = vec![];
+    for sub in state.subs.iter_mut() {
+        if !subject_matches(&sub.subject, subject) {
+            continue;
+        }
+        if let Some(queue) = &sub.queue {
+            let group = (sub.subject.clone(), queue.clone());
+            if groups.contains(&group) {
+                continue;
+            }
+            groups.push(group);
+        }
+        let reply = reply.map(|reply| format!(" {}", reply)).unwrap_or_default();
+        let head = match headers {
+            true => format!(
+                "HMSG {} {}{} {} {}\r\n",
+                subject,
+                &sub.sid,
+                reply,
+                header_size,
+                data.len()
+            ),
+            false => format!("MSG {} {}{} {}\r\n", subject, &sub.sid, reply, data.len()),
+        };
+        let mut frame = head.into_bytes();
+        frame.extend_from_slice(&data);
+        frame.extend_from_slice(b"\r\n");
+        let _ = sub.writer.lock().unwrap().write_all(&frame);
+        sub.delivered += 1;
+    }
+    state
+        .subs
+        .retain(|sub| sub.max.is_none_or(|max| sub.delivered < max));
+    let payload = data[header_size..].to_vec();
+    state.published.push((subject.to_string(), payload));
+}
+
+/// Returns true if `subject` matches a subscription
+/// `pattern` with `*` and `>` wildcards.
+fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('.').collect();
+    let subject: Vec<&str> = subject.split('.').collect();
+    for (i, token) in pattern.iter().enumerate() {
+        if *token == ">" {
+            return subject.len() > i;
+        }
+        match subject.get(i) {
+            Some(part) if token == part || *token == "*" => {}
+            _ => return false,
+        }
+    }
+    pattern.len() == subject.len()
+}
+
+/// Returns the ctx of a msg handled by a `test` system agent
+/// connected to a nats stub.
+pub(crate) async fn ctx(dispatch_type: DispatchType, msg_version: &str) -> Ctx {
+    let nats = nats::asynk::connect(&nats_stub()).await.unwrap();
+    let (sender, _receiver) = priority::mailbox(None, OverflowPolicy::Block);
+    let (_stop_tx, stop_rx) = watch::channel(false);
+    let scheduler = Scheduler::new(
+        "TestActor/v1.0".to_string(),
+        "test".to_string(),
+        vec![],
+        sender,
+        stop_rx,
+    );
+    Ctx::new(
+        "1".to_string(),
+        "test".to_string(),
+        None,
+        dispatch_type,
+        msg_version.to_string(),
+        1,
+        Metadata::default(),
+        None,
+        "test".to_string(),
+        scheduler,
+        Client::from_connection(nats),
+    )
+}
@@ -0,0 +1,312 @@
+//! Trace context propagated through the HollywoodMsg envelope.
+//!
+//! Clients create a `tracing` span for each request, send and
+//! publish and send its W3C `traceparent` along with the msg.
+//! Agents handle the msg inside a child span and calls made
+//! from the handler continue the same trace.
+
+use rand::Rng;
+use std::future::Future;
+use tracing::field::Empty;
+use tracing::{info_span, Span};
+
+/// W3C trace context (`https://www.w3.org/TR/trace-context/`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TraceContext {
+    // 32 lowercase hex chars
+    pub trace_id: String,
+    // 16 lowercase hex chars
+    pub span_id: String,
+    // sampled trace flag (false if the caller
+    // decided not to record the trace)
+    pub sampled: bool,
+}
+
+/// `sampled` bit of the traceparent trace flags.
+const SAMPLED_FLAG: u8 = 0x01;
+
+fn new_id(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    loop {
+        let id: String = (0..bytes)
+            .map(|_| format!("{:02x}", rng.gen::<u8>()))
+            .collect();
+        // all zero ids are invalid
+        if id.bytes().any(|b| b != b'0') {
+            return id;
+        }
+    }
+}
+
+fn is_hex_id(id: &str, len: usize) -> bool {
+    id.len() == len
+        && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        && id.bytes().any(|b| b != b'0')
+}
+
+impl TraceContext {
+    /// Returns a new span in the parent trace or the
+    /// root span of a new (sampled) trace.
+    fn child(parent: Option<&TraceContext>) -> Self {
+        Self {
+            trace_id: parent.map_or_else(|| new_id(16), |p| p.trace_id.clone()),
+            span_id: new_id(8),
+            sampled: parent.is_none_or(|p| p.sampled),
+        }
+    }
+
+    /// Parse a `traceparent` value. Returns None if it's invalid.
+    pub(crate) fn parse(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.split('-');
+        let (version, trace_id, span_id) = (parts.next()?, parts.next()?, parts.next()?);
+        let flags = parts.next()?;
+        if version != "00" || !is_hex_id(trace_id, 32) || !is_hex_id(span_id, 16) {
+            return None;
+        }
+        if flags.len() != 2 || parts.next().is_some() {
+            return None;
+        }
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        Some(Self {
+            trace_id: trace_id.to_string(),
+            span_id: span_id.to_string(),
+            sampled: flags & SAMPLED_FLAG != 0,
+        })
+    }
+
+    pub(crate) fn traceparent(&self) -> String {
+        let flags = match self.sampled {
+            true => SAMPLED_FLAG,
+            false => 0,
+        };
+        format!("00-{}-{}-{:02x}", &self.trace_id, &self.span_id, flags)
+    }
+}
+
+tokio::task_local! {
+    // trace context of the msg being handled
+    static TRACE: TraceContext;
+}
+
+/// Returns the trace context of the msg being
+/// handled or None outside of an actor handler.
+pub(crate) fn current() -> Option<TraceContext> {
+    TRACE.try_with(|trace| trace.clone()).ok()
+}
+
+/// Run `f` with `trace` as the current trace context.
+pub(crate) fn scope<F: Future>(trace: TraceContext, f: F) -> impl Future<Output = F::Output> {
+    TRACE.scope(trace, f)
+}
+
+/// Returns the span for a client call and its trace context.
+pub(crate) fn client_span(
+    op: &'static str,
+    subject: &str,
+    msg_version: &str,
+) -> (Span, TraceContext) {
+    let span = info_span!(
+        "hollywood.client",
+        op,
+        subject,
+        msg_version,
+        trace_id = Empty,
+        span_id = Empty,
+    );
+    let trace = start(&span, None);
+    (span, trace)
+}
+
+/// Returns the span for an actor handler and its trace context.
+/// `traceparent` is the trace context received with the msg.
+pub(crate) fn handler_span(
+    actor: &str,
+    dispatch_type: &'static str,
+    msg_id: &str,
+    msg_version: &str,
+    traceparent: Option<&str>,
+) -> (Span, TraceContext) {
+    let span = info_span!(
+        "hollywood.handle",
+        actor,
+        dispatch_type,
+        msg_id,
+        msg_version,
+        trace_id = Empty,
+        span_id = Empty,
+    );
+    let parent = traceparent.and_then(TraceContext::parse);
+    let trace = start(&span, parent.as_ref());
+    (span, trace)
+}
+
+/// Assign a trace context to a new span. Spans without a
+/// `remote_parent` continue the trace of the msg being handled.
+fn start(span: &Span, remote_parent: Option<&TraceContext>) -> TraceContext {
+    let trace = otel_start(span, remote_parent).unwrap_or_else(|| {
+        let parent = remote_parent.cloned().or_else(current);
+        TraceContext::child(parent.as_ref())
+    });
+    span.record("trace_id", trace.trace_id.as_str());
+    span.record("span_id", trace.span_id.as_str());
+    trace
+}
+
+#[cfg(feature = "opentelemetry")]
+use otel::start as otel_start;
+
+// spans aren't exported without the opentelemetry feature
+#[cfg(not(feature = "opentelemetry"))]
+fn otel_start(_span: &Span, _remote_parent: Option<&TraceContext>) -> Option<TraceContext> {
+    None
+}
+
+#[cfg(feature = "opentelemetry")]
+pub use otel::{init_opentelemetry, OpenTelemetry};
+
+#[cfg(feature = "opentelemetry")]
+mod otel {
+    use super::TraceContext;
+    use anyhow::Result;
+    use log::error;
+    use opentelemetry::trace::{
+        SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState, TracerProvider,
+    };
+    use opentelemetry::Context;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+    use tracing::Span;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    /// Handle to the OpenTelemetry exporter. The remaining
+    /// spans are flushed when it's dropped so keep it alive
+    /// until the process exits.
+    pub struct OpenTelemetry {
+        provider: SdkTracerProvider,
+    }
+
+    impl Drop for OpenTelemetry {
+        fn drop(&mut self) {
+            if let Err(err) = self.provider.shutdown() {
+                error!("shutting down the OpenTelemetry exporter: {:?}", &err);
+            }
+        }
+    }
+
+    /// Export `tracing` spans to an OTLP/http collector. Uses the
+    /// standard `OTEL_EXPORTER_OTLP_*` env variables (default
+    /// `http://localhost:4318`) unless `endpoint` is set.
+    /// Installs the global `tracing` subscriber.
+    pub fn init_opentelemetry(service_name: &str, endpoint: Option<&str>) -> Result<OpenTelemetry> {
+        let mut exporter = SpanExporter::builder().with_http();
+        if let Some(endpoint) = endpoint {
+            exporter = exporter.with_endpoint(endpoint);
+        }
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter.build()?)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(service_name.to_string())
+                    .build(),
+            )
+            .build();
+        let tracer = provider.tracer("hollywood");
+        tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .try_init()?;
+        Ok(OpenTelemetry { provider })
+    }
+
+    /// Returns the OpenTelemetry trace context of the span or
+    /// None if spans aren't exported to OpenTelemetry.
+    pub(super) fn start(span: &Span, remote_parent: Option<&TraceContext>) -> Option<TraceContext> {
+        if let Some(parent) = remote_parent.and_then(span_context) {
+            let _ = span.set_parent(Context::new().with_remote_span_context(parent));
+        }
+        let context = span.context();
+        let span_context = context.span().span_context().clone();
+        if !span_context.is_valid() {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id: span_context.trace_id().to_string(),
+            span_id: span_context.span_id().to_string(),
+            sampled: span_context.is_sampled(),
+        })
+    }
+
+    fn span_context(trace: &TraceContext) -> Option<SpanContext> {
+        Some(SpanContext::new(
+            TraceId::from_hex(&trace.trace_id).ok()?,
+            SpanId::from_hex(&trace.span_id).ok()?,
+            match trace.sampled {
+                true => TraceFlags::SAMPLED,
+                false => TraceFlags::default(),
+            },
+            true,
+            TraceState::default(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traceparent() {
+        let trace = TraceContext::child(None);
+        assert_eq!(trace.trace_id.len(), 32);
+        assert_eq!(trace.span_id.len(), 16);
+        assert_eq!(
+            TraceContext::parse(&trace.traceparent()),
+            Some(trace.clone())
+        );
+
+        let child = TraceContext::child(Some(&trace));
+        assert_eq!(child.trace_id, trace.trace_id);
+        assert_ne!(child.span_id, trace.span_id);
+
+        let zero_trace = format!("00-{}-{}-01", "0".repeat(32), &trace.span_id);
+        assert_eq!(TraceContext::parse(&zero_trace), None);
+        assert_eq!(TraceContext::parse("00-abc-def-01"), None);
+        assert_eq!(TraceContext::parse("not a traceparent"), None);
+        assert_eq!(
+            TraceContext::parse(&format!("00-{}-{}-0g", &trace.trace_id, &trace.span_id)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_traceparent_sampled_flag() {
+        let trace = TraceContext::child(None);
+        assert!(trace.sampled);
+        assert!(trace.traceparent().ends_with("-01"));
+
+        // children keep the caller's decision not to sample
+        let traceparent = format!("00-{}-{}-00", &trace.trace_id, &trace.span_id);
+        let parent = TraceContext::parse(&traceparent).unwrap();
+        assert!(!parent.sampled);
+        let child = TraceContext::child(Some(&parent));
+        assert!(!child.sampled);
+        assert!(child.traceparent().ends_with("-00"));
+
+        let traceparent = format!("00-{}-{}-03", &trace.trace_id, &trace.span_id);
+        assert!(TraceContext::parse(&traceparent).unwrap().sampled);
+    }
+
+    #[tokio::test]
+    async fn test_trace_scope() {
+        assert_eq!(current(), None);
+        let trace = TraceContext::child(None);
+        let (_, child) = scope(trace.clone(), async {
+            client_span("send", "subject", "v1.0")
+        })
+        .await;
+        assert_eq!(child.trace_id, trace.trace_id);
+        assert_ne!(child.span_id, trace.span_id);
+    }
+}